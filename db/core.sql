@@ -1,16 +1,129 @@
+-- Every webhook received, kept to look into notifications customers say they never got.
+-- An event is claimed as processing by the request handling it, and is done once its notification is queued.
+-- Failed events and processing events whose lock expired (the request crashed) can be claimed again.
 CREATE TABLE IF NOT EXISTS events (
-    event_id VARCHAR(100) PRIMARY KEY
+    event_id VARCHAR(100) PRIMARY KEY,
+    webhook_secret_id VARCHAR(50),
+    topic VARCHAR(100),
+    webhook_id VARCHAR(100),
+    shop_domain VARCHAR(255),
+    api_version VARCHAR(20),
+    -- The raw webhook body, NULL if it wasn't valid JSON
+    payload JSONB,
+    status VARCHAR(20) NOT NULL DEFAULT 'processing' CHECK (status IN ('processing', 'done', 'failed')),
+    error TEXT,
+    received_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    processed_at TIMESTAMPTZ,
+    -- Set once the retention task has stripped the payload down to the order number
+    payload_redacted_at TIMESTAMPTZ,
+    locked_until TIMESTAMPTZ
 );
 
+-- Duplicate checks and claims go through the primary key, these keep the retention task from scanning the table
+CREATE INDEX IF NOT EXISTS events_processed_at_idx ON events (status, processed_at);
+CREATE INDEX IF NOT EXISTS events_unredacted_idx ON events (processed_at) WHERE payload_redacted_at IS NULL AND payload IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS events_order_number_idx ON events ((payload->>'order_number'));
+CREATE INDEX IF NOT EXISTS events_customer_email_idx ON events (lower(payload->'customer'->>'email'));
+
+-- Notifications waiting to be sent, written in the same transaction as the event they belong to
+CREATE TABLE IF NOT EXISTS outbox (
+    id BIGSERIAL PRIMARY KEY,
+    event_id VARCHAR(100) REFERENCES events(event_id) ON DELETE SET NULL,
+    shop_domain VARCHAR(255) NOT NULL,
+    email JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    available_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    sent_at TIMESTAMPTZ,
+    last_error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS outbox_pending_idx ON outbox (available_at) WHERE sent_at IS NULL;
+CREATE INDEX IF NOT EXISTS outbox_sent_at_idx ON outbox (sent_at) WHERE sent_at IS NOT NULL;
+
+-- Every attempt at sending a notification, kept when the notification is moved to the dead letters
+CREATE TABLE IF NOT EXISTS delivery_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    outbox_id BIGINT NOT NULL,
+    attempted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- The SMTP relay that accepted the notification
+    relay VARCHAR(255),
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS delivery_attempts_outbox_id_idx ON delivery_attempts (outbox_id);
+CREATE INDEX IF NOT EXISTS delivery_attempts_attempted_at_idx ON delivery_attempts (attempted_at);
+
+-- Notifications that failed permanently or ran out of attempts, they keep their outbox id so they can be requeued
+CREATE TABLE IF NOT EXISTS dead_letters (
+    outbox_id BIGINT PRIMARY KEY,
+    event_id VARCHAR(100) REFERENCES events(event_id) ON DELETE SET NULL,
+    shop_domain VARCHAR(255) NOT NULL,
+    email JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    attempts INTEGER NOT NULL,
+    error TEXT NOT NULL,
+    dead_lettered_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Shops besides the one configured with environment variables, resolved by X-Shopify-Shop-Domain
+CREATE TABLE IF NOT EXISTS shops (
+    id SERIAL PRIMARY KEY,
+    domain VARCHAR(255) NOT NULL UNIQUE,
+    -- A single secret, or a rotation list in the same format as the shopify_webhook_secrets environment variable
+    webhook_secret TEXT NOT NULL,
+    api_version VARCHAR(20) NOT NULL,
+    origin_email VARCHAR(255) NOT NULL,
+    smtp_host VARCHAR(255) NOT NULL,
+    smtp_port INTEGER NOT NULL,
+    smtp_username VARCHAR(255) NOT NULL,
+    smtp_password VARCHAR(255) NOT NULL,
+    -- Locale of the templates used when the customer's locale has none, e.g. en or de-AT
+    default_locale VARCHAR(35),
+    -- Mails are signed with DKIM when a selector and a private key are set, for the domain of origin_email by default
+    dkim_selector VARCHAR(63),
+    dkim_domain VARCHAR(255),
+    -- An RSA key in PKCS#1 PEM, or a base64 Ed25519 key
+    dkim_private_key TEXT,
+    -- Mails go to the transactional email provider's API instead of the SMTP server when an endpoint is set
+    email_api_endpoint VARCHAR(255),
+    email_api_key TEXT
+);
+
+-- Templates and partials without a shop are shared by all shops, and without a locale are used for any locale
+-- Every change to a template is a new version with the same name, active_templates points at the one in use
 CREATE TABLE IF NOT EXISTS templates (
     id SERIAL PRIMARY KEY,
+    shop_id INTEGER REFERENCES shops(id),
     name VARCHAR(50) NOT NULL,
-    content TEXT NOT NULL
+    version INTEGER NOT NULL DEFAULT 1,
+    -- Handlebars, filled with the same payload as the content
+    subject TEXT,
+    -- Shown after the subject in most inboxes, hidden in the email itself
+    preheader TEXT,
+    content TEXT NOT NULL,
+    -- Plain text body, derived from the rendered content when not set
+    text_content TEXT,
+    author VARCHAR(100),
+    note TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE NULLS NOT DISTINCT (shop_id, name, version)
 );
 
+CREATE OR REPLACE FUNCTION reject_template_update() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'template versions are immutable, create a new version instead';
+END;
+$$ LANGUAGE plpgsql;
 
-INSERT INTO templates (name, content) VALUES (
+CREATE OR REPLACE TRIGGER templates_immutable
+    BEFORE UPDATE ON templates
+    FOR EACH ROW EXECUTE FUNCTION reject_template_update();
+
+INSERT INTO templates (name, subject, content) VALUES (
     'order_created_example',
+    '#{{order_number}}: We have received your order',
     '<!DOCTYPE html>
     <html>
     <body style="font-family: Arial, sans-serif; line-height: 1.6; color: #333;">
@@ -29,8 +142,9 @@ INSERT INTO templates (name, content) VALUES (
     </html>'
 );
 
-INSERT INTO templates (name, content) VALUES (
+INSERT INTO templates (name, subject, content) VALUES (
     'order_cancelled_example',
+    '#{{order_number}}: Your order has been cancelled',
     '<!DOCTYPE html>
     <html>
     <body style="font-family: Arial, sans-serif; line-height: 1.6; color: #333;">
@@ -45,8 +159,9 @@ INSERT INTO templates (name, content) VALUES (
     </html>'
 );
 
-INSERT INTO templates (name, content) VALUES (
+INSERT INTO templates (name, subject, content) VALUES (
     'order_fulfilled_example',
+    'Order Fulfilled',
     '<!DOCTYPE html>
     <html>
     <body>
@@ -85,8 +200,13 @@ INSERT INTO template_types (name) VALUES ('invoice');
 
 CREATE TABLE IF NOT EXISTS active_templates (
     id SERIAL PRIMARY KEY,
-    template_type_id INTEGER NOT NULL UNIQUE,
+    shop_id INTEGER,
+    template_type_id INTEGER NOT NULL,
     template_id INTEGER NOT NULL,
+    -- Lowercase, e.g. de-at, customers in de-at fall back to de and then to the shop's default locale
+    locale VARCHAR(35),
+    UNIQUE NULLS NOT DISTINCT (shop_id, template_type_id, locale),
+    FOREIGN KEY (shop_id) REFERENCES shops(id),
     FOREIGN KEY (template_type_id) REFERENCES template_types(id),
     FOREIGN KEY (template_id) REFERENCES templates(id)
 );
@@ -96,10 +216,28 @@ INSERT INTO active_templates (template_type_id, template_id) VALUES (2, 2);
 INSERT INTO active_templates (template_type_id, template_id) VALUES (3, 3);
 INSERT INTO active_templates (template_type_id, template_id) VALUES (4, 4);
 
+-- Copies, Reply-To and extra headers of outgoing emails, of one template type or of all of them when it is NULL.
+-- A shop's settings override the shared ones field by field, and those of a template type the ones for every type.
+CREATE TABLE IF NOT EXISTS email_settings (
+    id SERIAL PRIMARY KEY,
+    shop_id INTEGER REFERENCES shops(id),
+    template_type_id INTEGER REFERENCES template_types(id),
+    -- NULL keeps the addresses of the settings overridden, an empty array sends no copies
+    cc TEXT[],
+    bcc TEXT[],
+    reply_to VARCHAR(255),
+    -- Header names and the Handlebars filling their value, e.g. {"X-Order-Id": "{{order_number}}"}
+    headers JSONB NOT NULL DEFAULT '{}',
+    UNIQUE NULLS NOT DISTINCT (shop_id, template_type_id)
+);
+
 CREATE TABLE IF NOT EXISTS template_partials (
     id SERIAL PRIMARY KEY,
+    shop_id INTEGER REFERENCES shops(id),
     name VARCHAR(50) NOT NULL,
-    content TEXT NOT NULL
+    locale VARCHAR(35),
+    content TEXT NOT NULL,
+    UNIQUE NULLS NOT DISTINCT (shop_id, name, locale)
 );
 
 INSERT INTO template_partials (name, content) VALUES (
@@ -111,3 +249,58 @@ INSERT INTO template_partials (name, content) VALUES (
     'invoice_details',
     '<p>Invoice details</p>'
 );
+
+-- Images embedded in emails, templates show them with <img src="cid:name">
+CREATE TABLE IF NOT EXISTS template_images (
+    id SERIAL PRIMARY KEY,
+    -- NULL for the images shared by all shops, a shop's own image overrides a shared one with the same name
+    shop_id INTEGER REFERENCES shops(id),
+    name VARCHAR(50) NOT NULL,
+    content_type VARCHAR(100) NOT NULL,
+    content BYTEA NOT NULL,
+    UNIQUE NULLS NOT DISTINCT (shop_id, name)
+);
+
+-- Payloads to preview a template type with, without waiting for a webhook
+CREATE TABLE IF NOT EXISTS template_samples (
+    id SERIAL PRIMARY KEY,
+    template_type_id INTEGER NOT NULL REFERENCES template_types(id),
+    name VARCHAR(50) NOT NULL,
+    payload JSONB NOT NULL,
+    UNIQUE (template_type_id, name)
+);
+
+INSERT INTO template_samples (template_type_id, name, payload)
+SELECT id, 'default', '{
+    "order_number": "1001",
+    "customer": { "email": "jane.doe@example.com", "first_name": "Jane", "last_name": "Doe" }
+}'
+FROM template_types;
+
+-- Running services listen on this channel to reload their templates
+CREATE OR REPLACE FUNCTION notify_template_change() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('template_changes', TG_TABLE_NAME);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER templates_changed
+    AFTER INSERT OR UPDATE OR DELETE ON templates
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_template_change();
+
+CREATE OR REPLACE TRIGGER template_partials_changed
+    AFTER INSERT OR UPDATE OR DELETE ON template_partials
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_template_change();
+
+CREATE OR REPLACE TRIGGER template_images_changed
+    AFTER INSERT OR UPDATE OR DELETE ON template_images
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_template_change();
+
+CREATE OR REPLACE TRIGGER email_settings_changed
+    AFTER INSERT OR UPDATE OR DELETE ON email_settings
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_template_change();
+
+CREATE OR REPLACE TRIGGER active_templates_changed
+    AFTER INSERT OR UPDATE OR DELETE ON active_templates
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_template_change();
@@ -1,80 +1,74 @@
 use crate::{
-    middlewares::verify_shopify_origin,
+    config::Config,
+    middlewares::{verify_shopify_origin, VerifyShopifyOriginState},
     routes::{
-        health_check,
+        admin, health_check,
         webhooks::handlers::{order_cancelled, order_created, order_fulfilled},
     },
     services::{
         database::Pool,
-        email::{Mailer, MailerTrait},
-        queries::{partial, template},
-        template::Manager,
+        email::Mailer,
+        outbox::{self, Outbox},
+        retention::{self, RetentionMetrics},
+        shop::Shops,
+        template,
     },
 };
 use axum::{middleware, routing::get, routing::post, Extension, Router};
-use handlebars::Handlebars;
-use std::env;
-use tower::ServiceBuilder;
 
 /// Main application entry point
 /// # Panics
 /// This function may panic if:
-/// - Required environment variables are missing
 /// - Database connection fails
-pub async fn app() -> Router {
+/// - A shop cannot be loaded
+pub async fn app(config: Config) -> Router {
     // Create a database client
     let db_client = Pool::new(
-        env::var("postgres_db").unwrap(),
-        env::var("postgres_url").unwrap(),
-        env::var("postgres_user").unwrap(),
-        env::var("postgres_password").unwrap(),
+        config.database.name.clone(),
+        config.database.url.clone(),
+        config.database.user.clone(),
+        config.database.password.clone(),
     );
 
-    // Create an email client
-    let mailer = Mailer::new(
-        env::var("smtp_username").unwrap(),
-        env::var("smtp_password").unwrap(),
-        env::var("smtp_host").unwrap().as_str(),
-        env::var("origin_email").unwrap(),
-        env::var("smtp_port").unwrap().parse::<u16>().unwrap(),
+    // Load every shop with its own email client and templates, the webhook middleware picks one per request
+    let shops = Shops::<Mailer>::load(&db_client, &config).await.unwrap();
+
+    // Notifications are sent in the background, so webhook responses don't wait on SMTP
+    let _ = outbox::spawn_workers(
+        &db_client,
+        &shops,
+        config.outbox_workers,
+        config.outbox_poll_interval,
+        config.retry_policy,
     );
 
-    // Get templates from database and persist in memory with the template client
-    let mut templates = Handlebars::new();
-    let templates_from_db = template::get_all(&db_client.get_client().await.unwrap()).await.unwrap();
-    for template in templates_from_db {
-        let name: &str = template.get("name");
-        let content: &str = template.get("content");
+    // Processed events and sent notifications are purged once they are past retention
+    let retention_metrics = RetentionMetrics::default();
+    retention::spawn(&db_client, config.retention_policy, retention_metrics.clone());
 
-        if templates.register_template_string(name, content).is_ok() {
-            println!("Template registered and ready: {name}");
-        } else {
-            println!("Error registering template: {name}");
-        }
-    }
+    // Templates are reloaded whenever they change in the database, without a restart
+    let template_managers = shops.template_managers();
+    template::spawn_reloader(&db_client, template_managers.clone());
+
+    let outbox = Outbox::new(db_client.clone());
+    let admin_token = config.admin_token;
+    let admin_db_client = db_client.clone();
+    let verify_state = VerifyShopifyOriginState { db_client, shops };
 
-    // Get partials from database and persist in memory with the template client
-    let partials_templates_from_db = partial::get_all(&db_client.get_client().await.unwrap()).await.unwrap();
-    for partial in partials_templates_from_db {
-        let name: &str = partial.get("name");
-        let content: &str = partial.get("content");
+    // Create the app
+    let app = Router::new()
+        .route("/api/order/create", post(order_created::<Outbox>))
+        .route("/api/order/cancel", post(order_cancelled::<Outbox>))
+        .route("/api/order/fulfilled", post(order_fulfilled::<Outbox>))
+        .layer(Extension(outbox))
+        .route_layer(middleware::from_fn_with_state(verify_state, verify_shopify_origin::<Mailer>))
+        .route("/health", get(health_check));
 
-        if templates.register_partial(name, content).is_ok() {
-            println!("Partial template registered and ready: {name}");
-        } else {
-            println!("Error registering partial template: {name}");
+    match admin_token {
+        Some(admin_token) => app.merge(admin::router(admin_db_client, admin_token, retention_metrics, template_managers)),
+        None => {
+            println!("admin_api_token is not set, the admin API is disabled");
+            app
         }
     }
-
-    // Create a template client
-    let template_manager = Manager::new(templates);
-
-    // Create the app
-    Router::new()
-        .route("/api/order/create", post(order_created::<Mailer>))
-        .route("/api/order/cancel", post(order_cancelled::<Mailer>))
-        .route("/api/order/fulfilled", post(order_fulfilled::<Mailer>))
-        .layer(ServiceBuilder::new().layer(Extension(mailer)).layer(Extension(template_manager)))
-        .route_layer(middleware::from_fn_with_state(db_client, verify_shopify_origin))
-        .route("/health", get(health_check))
 }
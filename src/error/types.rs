@@ -8,6 +8,12 @@ pub enum QueryError {
     #[error("Failed to insert {0}")]
     Insert(&'static str),
 
+    #[error("Failed to update {0}")]
+    Update(&'static str),
+
+    #[error("Failed to delete {0}")]
+    Delete(&'static str),
+
     #[error("Failed to prepare statement")]
     PrepareStatement,
 }
@@ -1,19 +1,26 @@
 use dotenv::dotenv;
-use notification_service::app::app;
+use notification_service::{app::app, config::Config};
+use std::process;
 
 /// Main application entry point
 /// # Panics
 /// This function may panic if:
-/// - Required environment variables are missing
 /// - Database connection fails
 /// - TCP listener fails to bind
 #[tokio::main]
 pub async fn main() {
     dotenv().ok();
 
-    let app = app().await;
+    // Every missing or malformed setting is reported at once, before anything is started
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    });
+    let bind_address = config.bind_address;
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("Server is listening on port 3000");
+    let app = app(config).await;
+
+    let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
+    println!("Server is listening on {bind_address}");
     axum::serve(listener, app).await.unwrap();
 }
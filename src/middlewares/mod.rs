@@ -1,3 +1,5 @@
+pub mod verify_admin_token;
 pub mod verify_shopify_origin;
 
-pub use verify_shopify_origin::verify_shopify_origin;
+pub use verify_admin_token::{verify_admin_token, AdminToken};
+pub use verify_shopify_origin::{verify_shopify_origin, VerifyShopifyOriginState};
@@ -0,0 +1,96 @@
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+/// Bearer token protecting the admin API.
+#[derive(Clone)]
+pub struct AdminToken {
+    token: Arc<str>,
+}
+
+impl AdminToken {
+    #[must_use]
+    pub fn new(token: &str) -> Self {
+        Self { token: Arc::from(token) }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum VerifyAdminTokenError {
+    #[error("Authorization header is missing")]
+    MissingAuthorization,
+
+    #[error("Authorization header is not a bearer token")]
+    InvalidAuthorization,
+
+    #[error("Admin token is incorrect")]
+    IncorrectToken,
+}
+
+/// Verifies the request carries the admin token as `Authorization: Bearer <token>`.
+///
+/// # Errors
+///
+/// Returns `(StatusCode::UNAUTHORIZED, e.to_string())` if the token is missing or incorrect.
+pub async fn verify_admin_token(State(admin_token): State<AdminToken>, req: Request, next: Next) -> Result<Response, (StatusCode, String)> {
+    verify_token(req.headers(), &admin_token).map_err(|e| (StatusCode::UNAUTHORIZED, e.to_string()))?;
+
+    Ok(next.run(req).await)
+}
+
+fn verify_token(headers: &HeaderMap, admin_token: &AdminToken) -> Result<(), VerifyAdminTokenError> {
+    let authorization = headers.get(AUTHORIZATION).ok_or(VerifyAdminTokenError::MissingAuthorization)?;
+    let token = authorization
+        .to_str()
+        .ok()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .ok_or(VerifyAdminTokenError::InvalidAuthorization)?;
+
+    // Compared in constant time, so the token can't be guessed from response times
+    if !bool::from(token.as_bytes().ct_eq(admin_token.token.as_bytes())) {
+        return Err(VerifyAdminTokenError::IncorrectToken);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const ADMIN_TOKEN: &str = "admin_token";
+
+    #[test]
+    fn test_verify_token_correct_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}").parse().unwrap());
+        assert!(verify_token(&headers, &AdminToken::new(ADMIN_TOKEN)).is_ok());
+    }
+
+    #[test]
+    fn test_verify_token_missing_authorization() {
+        let result = verify_token(&HeaderMap::new(), &AdminToken::new(ADMIN_TOKEN));
+        assert_eq!(result.unwrap_err(), VerifyAdminTokenError::MissingAuthorization);
+    }
+
+    #[test]
+    fn test_verify_token_not_bearer() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Basic {ADMIN_TOKEN}").parse().unwrap());
+        let result = verify_token(&headers, &AdminToken::new(ADMIN_TOKEN));
+        assert_eq!(result.unwrap_err(), VerifyAdminTokenError::InvalidAuthorization);
+    }
+
+    #[test]
+    fn test_verify_token_wrong_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer admin_tokem".parse().unwrap());
+        let result = verify_token(&headers, &AdminToken::new(ADMIN_TOKEN));
+        assert_eq!(result.unwrap_err(), VerifyAdminTokenError::IncorrectToken);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::error::types::QueryError;
+use crate::services::template::{ManagerError, TemplateSyntaxError};
+use axum::{
+    extract::Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error("{0} not found")]
+    NotFound(&'static str),
+
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("Invalid template: {0}")]
+    InvalidTemplate(#[from] TemplateSyntaxError),
+
+    #[error("Failed to get client from pool")]
+    FailedToGetClient,
+
+    #[error(transparent)]
+    Query(#[from] QueryError),
+
+    #[error(transparent)]
+    Template(ManagerError),
+}
+
+impl From<ManagerError> for AdminError {
+    fn from(e: ManagerError) -> Self {
+        match e {
+            // Name the template, a reload compiles all of them
+            ManagerError::InvalidTemplate(name, e) => Self::InvalidTemplate(TemplateSyntaxError {
+                message: format!("{name}: {}", e.message),
+                ..e
+            }),
+            ManagerError::Query(e) => Self::Query(e),
+            e => Self::Template(e),
+        }
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::InvalidTemplate(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::FailedToGetClient | Self::Query(_) | Self::Template(_) => {
+                println!("Error handling admin request: {self}");
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "error": "Internal server error" }))).into_response();
+            }
+        };
+
+        // Syntax errors carry the position, so the editor can point at it
+        let body = match &self {
+            Self::InvalidTemplate(e) => json!({ "error": self.to_string(), "line": e.line, "column": e.column }),
+            _ => json!({ "error": self.to_string() }),
+        };
+
+        (status, Json(body)).into_response()
+    }
+}
@@ -0,0 +1,85 @@
+use crate::services::{database::Pool, queries::dead_letter};
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct ListDeadLettersParams {
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeadLetter {
+    outbox_id: i64,
+    event_id: Option<String>,
+    shop_domain: String,
+    recipient: Option<String>,
+    subject: Option<String>,
+    attempts: i32,
+    error: String,
+    /// Unix timestamp of when the notification was queued
+    created_at: i64,
+    /// Unix timestamp of when the notification was given up on
+    dead_lettered_at: i64,
+}
+
+/// Lists the most recent dead letters
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `params` - `limit`, the number of dead letters to return, at most 1000
+/// # Returns
+/// * `Json<Vec<DeadLetter>>` - The dead letters, most recent first
+pub async fn list_dead_letters(
+    Extension(db_client): Extension<Pool>,
+    Query(params): Query<ListDeadLettersParams>,
+) -> Result<Json<Vec<DeadLetter>>, StatusCode> {
+    let client = db_client.get_client().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let rows = dead_letter::get_all(&client, limit).await.map_err(|e| {
+        println!("Error getting dead letters: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(
+        rows.iter()
+            .map(|row| DeadLetter {
+                outbox_id: row.get("outbox_id"),
+                event_id: row.get("event_id"),
+                shop_domain: row.get("shop_domain"),
+                recipient: row.get("recipient"),
+                subject: row.get("subject"),
+                attempts: row.get("attempts"),
+                error: row.get("error"),
+                created_at: row.get("created_at"),
+                dead_lettered_at: row.get("dead_lettered_at"),
+            })
+            .collect(),
+    ))
+}
+
+/// Moves a dead letter back to the outbox, to be sent again by the workers
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `outbox_id` - The outbox id of the dead letter
+/// # Returns
+/// * `StatusCode` - `NOT_FOUND` if there is no such dead letter
+pub async fn requeue_dead_letter(Extension(db_client): Extension<Pool>, Path(outbox_id): Path<i64>) -> StatusCode {
+    let Ok(client) = db_client.get_client().await else {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    };
+
+    match dead_letter::requeue(&client, outbox_id).await {
+        Ok(true) => StatusCode::OK,
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            println!("Error requeueing dead letter {outbox_id}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
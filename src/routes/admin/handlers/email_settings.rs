@@ -0,0 +1,130 @@
+use super::partials::ShopFilter;
+use crate::routes::admin::error::AdminError;
+use crate::services::{
+    database::Pool,
+    email::header_name,
+    queries::email_setting::{self, EmailSetting},
+    template::validate,
+};
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::StatusCode,
+};
+use lettre::message::Mailbox;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tokio_postgres::Row;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EmailSettings {
+    #[serde(default, skip_deserializing)]
+    id: i32,
+    /// `None` for the settings shared by all shops
+    shop_id: Option<i32>,
+    /// `None` for the settings of every template type
+    template_type: Option<String>,
+    /// `None` keeps the addresses of less specific settings
+    cc: Option<Vec<String>>,
+    bcc: Option<Vec<String>>,
+    reply_to: Option<String>,
+    /// Header names and the Handlebars filling their value
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+}
+
+impl EmailSettings {
+    fn from_row(row: &Row) -> Self {
+        let headers: Value = row.get("headers");
+
+        Self {
+            id: row.get("id"),
+            shop_id: row.get("shop_id"),
+            template_type: row.get("template_type"),
+            cc: row.get("cc"),
+            bcc: row.get("bcc"),
+            reply_to: row.get("reply_to"),
+            headers: serde_json::from_value(headers).unwrap_or_default(),
+        }
+    }
+
+    // Checked before they are stored, an invalid address would only show up once an email fails to send
+    fn validate(&self) -> Result<(), AdminError> {
+        let addresses = self.cc.iter().chain(&self.bcc).flatten().chain(&self.reply_to);
+        if let Some(address) = addresses.into_iter().find(|address| address.parse::<Mailbox>().is_err()) {
+            return Err(AdminError::BadRequest(format!("{address} is not a valid email address")));
+        }
+
+        for (name, value) in &self.headers {
+            header_name(name).map_err(|e| AdminError::BadRequest(e.to_string()))?;
+            validate(value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Lists the email settings
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `filter` - `shop_id`, to only list the shop's own and the shared settings
+/// # Returns
+/// * `Json<Vec<EmailSettings>>` - The settings
+pub async fn list_email_settings(
+    Extension(db_client): Extension<Pool>,
+    Query(filter): Query<ShopFilter>,
+) -> Result<Json<Vec<EmailSettings>>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    let settings = email_setting::list(&client, filter.shop_id)
+        .await?
+        .iter()
+        .map(EmailSettings::from_row)
+        .collect();
+
+    Ok(Json(settings))
+}
+
+/// Stores the copies, Reply-To and headers of a shop's emails of a template type, replacing the current ones
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `settings` - The shop and template type the settings are for, and the settings
+/// # Returns
+/// * `Json<EmailSettings>` - The stored settings
+pub async fn store_email_settings(
+    Extension(db_client): Extension<Pool>,
+    Json(mut settings): Json<EmailSettings>,
+) -> Result<Json<EmailSettings>, AdminError> {
+    settings.validate()?;
+
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    let headers = serde_json::to_value(&settings.headers).unwrap_or_default();
+    let stored = EmailSetting {
+        shop_id: settings.shop_id,
+        template_type: settings.template_type.as_deref(),
+        cc: settings.cc.as_deref(),
+        bcc: settings.bcc.as_deref(),
+        reply_to: settings.reply_to.as_deref(),
+        headers: &headers,
+    };
+    settings.id = email_setting::upsert(&client, &stored)
+        .await?
+        .ok_or(AdminError::NotFound("Template type"))?;
+
+    Ok(Json(settings))
+}
+
+/// Deletes email settings
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The settings id
+/// # Returns
+/// * `StatusCode` - `NO_CONTENT` if the settings were deleted
+pub async fn delete_email_settings(Extension(db_client): Extension<Pool>, Path(id): Path<i32>) -> Result<StatusCode, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    if !email_setting::delete(&client, id).await? {
+        return Err(AdminError::NotFound("Email settings"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,140 @@
+use super::partials::ShopFilter;
+use crate::routes::admin::error::AdminError;
+use crate::services::{database::Pool, queries::image};
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+
+// Images are sent with every email showing them, a logo doesn't need more
+const MAX_IMAGE_SIZE: usize = 1024 * 1024;
+
+#[derive(Deserialize, Debug)]
+pub struct CreateImageRequest {
+    /// `None` makes the image available to all shops, a shop's own image overrides a shared one
+    shop_id: Option<i32>,
+    /// Templates show the image with `<img src="cid:{name}">`
+    name: String,
+    /// e.g. `image/png`
+    content_type: String,
+    /// Base64 encoded
+    content: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Image {
+    id: i32,
+    shop_id: Option<i32>,
+    name: String,
+    content_type: String,
+    /// In bytes
+    size: i32,
+}
+
+impl Image {
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            shop_id: row.get("shop_id"),
+            name: row.get("name"),
+            content_type: row.get("content_type"),
+            size: row.get("size"),
+        }
+    }
+}
+
+// The name becomes the Content-ID header of the image, which only allows some characters
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Lists the images, without their content
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `filter` - `shop_id`, to only list the shop's own and the shared images
+/// # Returns
+/// * `Json<Vec<Image>>` - The images
+pub async fn list_images(Extension(db_client): Extension<Pool>, Query(filter): Query<ShopFilter>) -> Result<Json<Vec<Image>>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    let images = image::list(&client, filter.shop_id).await?.iter().map(Image::from_row).collect();
+
+    Ok(Json(images))
+}
+
+/// Gets the content of an image
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The image id
+/// # Returns
+/// * `Response` - The image, with its content type
+pub async fn get_image(Extension(db_client): Extension<Pool>, Path(id): Path<i32>) -> Result<Response, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    let row = image::get_by_id(&client, id).await?.ok_or(AdminError::NotFound("Image"))?;
+    let content_type: String = row.get("content_type");
+    let content: Vec<u8> = row.get("content");
+
+    Ok(([(header::CONTENT_TYPE, content_type)], content).into_response())
+}
+
+/// Stores an image for templates to embed
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `request` - The shop, name, content type and base64 content of the image
+/// # Returns
+/// * `(StatusCode, Json<Image>)` - `CREATED` and the image
+pub async fn create_image(
+    Extension(db_client): Extension<Pool>,
+    Json(request): Json<CreateImageRequest>,
+) -> Result<(StatusCode, Json<Image>), AdminError> {
+    if !is_valid_name(&request.name) {
+        return Err(AdminError::BadRequest(
+            "Image names may only contain letters, digits, dashes, underscores and dots".to_string(),
+        ));
+    }
+    if !request.content_type.starts_with("image/") {
+        return Err(AdminError::BadRequest(format!("{} is not an image type", request.content_type)));
+    }
+    let content = STANDARD
+        .decode(&request.content)
+        .map_err(|_| AdminError::BadRequest("Image content is not valid base64".to_string()))?;
+    if content.len() > MAX_IMAGE_SIZE {
+        return Err(AdminError::BadRequest(format!("Images can be at most {MAX_IMAGE_SIZE} bytes")));
+    }
+
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    let id = image::create(&client, request.shop_id, &request.name, &request.content_type, &content)
+        .await?
+        .ok_or_else(|| AdminError::Conflict(format!("Image {} already exists", request.name)))?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(Image {
+            id,
+            shop_id: request.shop_id,
+            name: request.name,
+            content_type: request.content_type,
+            size: i32::try_from(content.len()).unwrap_or(i32::MAX),
+        }),
+    ))
+}
+
+/// Deletes an image
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The image id
+/// # Returns
+/// * `StatusCode` - `NO_CONTENT` if the image was deleted
+pub async fn delete_image(Extension(db_client): Extension<Pool>, Path(id): Path<i32>) -> Result<StatusCode, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    if !image::delete(&client, id).await? {
+        return Err(AdminError::NotFound("Image"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
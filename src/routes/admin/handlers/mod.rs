@@ -0,0 +1,18 @@
+pub mod dead_letters;
+pub mod email_settings;
+pub mod images;
+pub mod partials;
+pub mod previews;
+pub mod retention;
+pub mod templates;
+
+pub use dead_letters::{list_dead_letters, requeue_dead_letter};
+pub use email_settings::{delete_email_settings, list_email_settings, store_email_settings};
+pub use images::{create_image, delete_image, get_image, list_images};
+pub use partials::{create_partial, delete_partial, get_partial, list_partials, update_partial};
+pub use previews::{list_samples, preview_template, store_sample};
+pub use retention::retention_metrics;
+pub use templates::{
+    activate_template, create_template, delete_template, diff_templates, get_template, list_template_versions, list_templates, reload_templates,
+    rollback_template,
+};
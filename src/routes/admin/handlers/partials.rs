@@ -0,0 +1,144 @@
+use crate::routes::admin::error::AdminError;
+use crate::services::{database::Pool, locale, queries::partial, template::validate};
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+
+#[derive(Deserialize, Debug)]
+pub struct ShopFilter {
+    pub(super) shop_id: Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreatePartialRequest {
+    /// `None` makes the partial available to all shops, a shop's own partial overrides a shared one
+    shop_id: Option<i32>,
+    name: String,
+    /// `None` makes the partial available to every locale without its own
+    locale: Option<String>,
+    content: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdatePartialRequest {
+    content: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Partial {
+    id: i32,
+    shop_id: Option<i32>,
+    name: String,
+    locale: Option<String>,
+    content: String,
+}
+
+impl Partial {
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            shop_id: row.get("shop_id"),
+            name: row.get("name"),
+            locale: row.get("locale"),
+            content: row.get("content"),
+        }
+    }
+}
+
+/// Lists the partials
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `filter` - `shop_id`, to only list the shop's own and the shared partials
+/// # Returns
+/// * `Json<Vec<Partial>>` - The partials
+pub async fn list_partials(Extension(db_client): Extension<Pool>, Query(filter): Query<ShopFilter>) -> Result<Json<Vec<Partial>>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    let partials = partial::list(&client, filter.shop_id).await?.iter().map(Partial::from_row).collect();
+
+    Ok(Json(partials))
+}
+
+/// Gets a partial
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The partial id
+/// # Returns
+/// * `Json<Partial>` - The partial
+pub async fn get_partial(Extension(db_client): Extension<Pool>, Path(id): Path<i32>) -> Result<Json<Partial>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    let row = partial::get_by_id(&client, id).await?.ok_or(AdminError::NotFound("Partial"))?;
+
+    Ok(Json(Partial::from_row(&row)))
+}
+
+/// Creates a partial
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `request` - The shop, name, locale and Handlebars content of the partial
+/// # Returns
+/// * `(StatusCode, Json<Partial>)` - `CREATED` and the partial
+pub async fn create_partial(
+    Extension(db_client): Extension<Pool>,
+    Json(request): Json<CreatePartialRequest>,
+) -> Result<(StatusCode, Json<Partial>), AdminError> {
+    validate(&request.content)?;
+
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    let locale = request.locale.as_deref().map(locale::normalize);
+    let id = partial::create(&client, request.shop_id, &request.name, locale.as_deref(), &request.content)
+        .await?
+        .ok_or_else(|| AdminError::Conflict(format!("Partial {} already exists", request.name)))?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(Partial {
+            id,
+            shop_id: request.shop_id,
+            name: request.name,
+            locale,
+            content: request.content,
+        }),
+    ))
+}
+
+/// Updates the content of a partial
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The partial id
+/// * `request` - The new Handlebars content
+/// # Returns
+/// * `StatusCode` - `OK` if the partial was updated
+pub async fn update_partial(
+    Extension(db_client): Extension<Pool>,
+    Path(id): Path<i32>,
+    Json(request): Json<UpdatePartialRequest>,
+) -> Result<StatusCode, AdminError> {
+    validate(&request.content)?;
+
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    if !partial::update(&client, id, &request.content).await? {
+        return Err(AdminError::NotFound("Partial"));
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Deletes a partial
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The partial id
+/// # Returns
+/// * `StatusCode` - `NO_CONTENT` if the partial was deleted
+pub async fn delete_partial(Extension(db_client): Extension<Pool>, Path(id): Path<i32>) -> Result<StatusCode, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    if !partial::delete(&client, id).await? {
+        return Err(AdminError::NotFound("Partial"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,183 @@
+use crate::routes::admin::error::AdminError;
+use crate::services::{
+    database::Pool,
+    document::create_pdf,
+    queries::sample,
+    template::{validate, Managers},
+};
+use crate::utils::EmailAttachment;
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+// Sample used when the preview names neither a sample nor a payload
+const DEFAULT_SAMPLE: &str = "default";
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewFormat {
+    #[default]
+    Html,
+    Pdf,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PreviewParams {
+    /// `None` previews with the templates of the shop configured through environment variables
+    shop_id: Option<i32>,
+    /// Customer locale to render for, falling back the way webhooks do
+    locale: Option<String>,
+    #[serde(default)]
+    format: PreviewFormat,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PreviewRequest {
+    /// Name of a stored sample, used when there is no payload
+    sample: Option<String>,
+    payload: Option<Value>,
+    /// Draft content rendered instead of the active template
+    content: Option<String>,
+    /// Draft partials, replacing the stored ones with the same name
+    #[serde(default)]
+    partials: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Sample {
+    name: String,
+    payload: Value,
+}
+
+/// Renders a template type the way a webhook would, with a sample or the given payload. Emails come out like the HTML
+/// body that is sent, with the images it shows embedded
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `managers` - The template managers of every shop
+/// * `name` - The template type, e.g. `order_fulfilled`
+/// * `params` - `shop_id`, `locale`, and `format` to get the result as `html` or `pdf`
+/// * `request` - The sample or payload, and the draft template and partials to render instead of the stored ones
+/// # Returns
+/// * `Response` - The rendered HTML, or the PDF made from it
+pub async fn preview_template(
+    Extension(db_client): Extension<Pool>,
+    Extension(managers): Extension<Managers>,
+    Path(name): Path<String>,
+    Query(params): Query<PreviewParams>,
+    request: Option<Json<PreviewRequest>>,
+) -> Result<Response, AdminError> {
+    let request = request.map(|Json(request)| request).unwrap_or_default();
+    let manager = managers
+        .get(params.shop_id)
+        .ok_or(AdminError::NotFound("Shop"))?
+        .with_locale(params.locale.as_deref())
+        .draft();
+
+    for (partial_name, content) in &request.partials {
+        validate(content)?;
+        manager.upsert_partial(partial_name, content)?;
+    }
+    if let Some(content) = &request.content {
+        validate(content)?;
+        manager.upsert_template(&name, content)?;
+    }
+    if !manager.has_template(&name) {
+        return Err(AdminError::NotFound("Template"));
+    }
+
+    let payload = match request.payload {
+        Some(payload) => payload,
+        None => {
+            let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+            let sample_name = request.sample.as_deref().unwrap_or(DEFAULT_SAMPLE);
+
+            sample::get(&client, &name, sample_name)
+                .await?
+                .ok_or(AdminError::NotFound("Sample"))?
+                .get("payload")
+        }
+    };
+
+    let unrenderable = |_| AdminError::BadRequest("Template cannot be rendered with the payload".to_string());
+    // Emails get their CSS inlined and their preheader added like when they are sent, documents are rendered as is
+    let html = if manager.has_subject(&name) {
+        let email = manager.get_email_filled(&name, &payload).map_err(unrenderable)?;
+        embed_images(&email.html_body, &email.images)
+    } else {
+        manager.get_template_filled(&name, &payload).map_err(unrenderable)?
+    };
+
+    match params.format {
+        PreviewFormat::Html => Ok(Html(html).into_response()),
+        PreviewFormat::Pdf => {
+            let pdf = create_pdf(&html, &name).map_err(|_| AdminError::BadRequest("Template cannot be converted to a PDF".to_string()))?;
+
+            Ok(([(header::CONTENT_TYPE, "application/pdf")], pdf).into_response())
+        }
+    }
+}
+
+// Points the `cid:` references of the HTML at data URLs, a browser can't resolve them to the inline parts of an email
+fn embed_images(html: &str, images: &[EmailAttachment]) -> String {
+    let mut images: Vec<_> = images
+        .iter()
+        .filter_map(|image| image.content_id.as_deref().map(|content_id| (content_id, image)))
+        .collect();
+    // Longest first, so `cid:logo` doesn't replace the start of `cid:logo-dark`
+    images.sort_by_key(|(content_id, _)| std::cmp::Reverse(content_id.len()));
+
+    images.into_iter().fold(html.to_string(), |html, (content_id, image)| {
+        html.replace(
+            &format!("cid:{content_id}"),
+            &format!("data:{};base64,{}", image.content_type, STANDARD.encode(&image.content)),
+        )
+    })
+}
+
+/// Lists the sample payloads of a template type
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `name` - The template type
+/// # Returns
+/// * `Json<Vec<Sample>>` - The samples
+pub async fn list_samples(Extension(db_client): Extension<Pool>, Path(name): Path<String>) -> Result<Json<Vec<Sample>>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    let samples = sample::list(&client, &name)
+        .await?
+        .iter()
+        .map(|row| Sample {
+            name: row.get("name"),
+            payload: row.get("payload"),
+        })
+        .collect();
+
+    Ok(Json(samples))
+}
+
+/// Stores a sample payload of a template type, replacing the one with the same name
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `(name, sample_name)` - The template type and the name of the sample
+/// * `payload` - The payload, shaped like the webhook the template type is rendered for
+/// # Returns
+/// * `StatusCode` - `OK` if the sample was stored
+pub async fn store_sample(
+    Extension(db_client): Extension<Pool>,
+    Path((name, sample_name)): Path<(String, String)>,
+    Json(payload): Json<Value>,
+) -> Result<StatusCode, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    if !sample::upsert(&client, &name, &sample_name, &payload).await? {
+        return Err(AdminError::NotFound("Template type"));
+    }
+
+    Ok(StatusCode::OK)
+}
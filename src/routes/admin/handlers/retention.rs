@@ -0,0 +1,11 @@
+use crate::services::retention::{RetentionMetrics, RetentionSnapshot};
+use axum::extract::{Extension, Json};
+
+/// Shows how many rows the retention task has purged and redacted since the service started
+/// # Arguments
+/// * `metrics` - The retention task counters
+/// # Returns
+/// * `Json<RetentionSnapshot>` - The counters of the last run and in total
+pub async fn retention_metrics(Extension(metrics): Extension<RetentionMetrics>) -> Json<RetentionSnapshot> {
+    Json(metrics.snapshot())
+}
@@ -0,0 +1,331 @@
+use crate::routes::admin::error::AdminError;
+use crate::services::{
+    database::Pool,
+    locale,
+    queries::template::{self, NewTemplate},
+    template::{diff, validate, Managers},
+};
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+
+#[derive(Deserialize, Debug)]
+pub struct ShopFilter {
+    shop_id: Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateTemplateRequest {
+    /// `None` makes the template available to all shops
+    shop_id: Option<i32>,
+    /// An existing name creates the next version of that template
+    name: String,
+    /// Handlebars subject of the email, filled with the same payload as the content
+    subject: Option<String>,
+    /// Handlebars text shown next to the subject in the inbox
+    preheader: Option<String>,
+    content: String,
+    /// Handlebars plain text body, derived from the content when not set
+    text_content: Option<String>,
+    author: String,
+    /// What changed compared to the previous version
+    note: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ActivateTemplateRequest {
+    /// Name of the `template_types` entry, e.g. `order_created`
+    template_type: String,
+    /// `None` activates the template for all shops without their own
+    shop_id: Option<i32>,
+    /// `None` activates the template for every locale without its own, e.g. `de` is also used for `de-AT`
+    locale: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RollbackTemplateRequest {
+    template_type: String,
+    shop_id: Option<i32>,
+    locale: Option<String>,
+    /// Version of the active template to go back to, the previous one if not set
+    version: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Activation {
+    template_type: String,
+    shop_id: Option<i32>,
+    locale: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Template {
+    id: i32,
+    shop_id: Option<i32>,
+    name: String,
+    version: i32,
+    subject: Option<String>,
+    preheader: Option<String>,
+    content: String,
+    text_content: Option<String>,
+    author: Option<String>,
+    note: Option<String>,
+    /// Unix timestamp
+    created_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_for: Option<Vec<Activation>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TemplateDiff {
+    from: i32,
+    to: i32,
+    /// Unified diff of the content
+    diff: String,
+    /// Unified diffs of the subject, preheader and text content, empty when they are the same in both versions
+    subject: String,
+    preheader: String,
+    text_content: String,
+}
+
+impl Template {
+    fn from_row(row: &Row) -> Self {
+        Self {
+            id: row.get("id"),
+            shop_id: row.get("shop_id"),
+            name: row.get("name"),
+            version: row.get("version"),
+            subject: row.get("subject"),
+            preheader: row.get("preheader"),
+            content: row.get("content"),
+            text_content: row.get("text_content"),
+            author: row.get("author"),
+            note: row.get("note"),
+            created_at: row.get("created_at"),
+            active_for: None,
+        }
+    }
+}
+
+/// Lists every template version and what it is active for
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `filter` - `shop_id`, to only list the shop's own and the shared templates
+/// # Returns
+/// * `Json<Vec<Template>>` - The templates
+pub async fn list_templates(Extension(db_client): Extension<Pool>, Query(filter): Query<ShopFilter>) -> Result<Json<Vec<Template>>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    let templates = template::list(&client, filter.shop_id)
+        .await?
+        .iter()
+        .map(|row| Template {
+            active_for: serde_json::from_value(row.get("active_for")).ok(),
+            ..Template::from_row(row)
+        })
+        .collect();
+
+    Ok(Json(templates))
+}
+
+/// Gets a template version
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The template id
+/// # Returns
+/// * `Json<Template>` - The template
+pub async fn get_template(Extension(db_client): Extension<Pool>, Path(id): Path<i32>) -> Result<Json<Template>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+
+    let row = template::get_by_id(&client, id).await?.ok_or(AdminError::NotFound("Template"))?;
+
+    Ok(Json(Template::from_row(&row)))
+}
+
+/// Creates a template version, it is only used once activated
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `request` - The shop, name, Handlebars subject, preheader, content and text content, author and change note of the template
+/// # Returns
+/// * `(StatusCode, Json<Template>)` - `CREATED` and the template with its version number, `CONFLICT` if another version
+///   of the template was created at the same time
+pub async fn create_template(
+    Extension(db_client): Extension<Pool>,
+    Json(request): Json<CreateTemplateRequest>,
+) -> Result<(StatusCode, Json<Template>), AdminError> {
+    validate_all([
+        Some(request.content.as_str()),
+        request.subject.as_deref(),
+        request.preheader.as_deref(),
+        request.text_content.as_deref(),
+    ])?;
+
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    let row = template::create(
+        &client,
+        &NewTemplate {
+            shop_id: request.shop_id,
+            name: &request.name,
+            subject: request.subject.as_deref(),
+            preheader: request.preheader.as_deref(),
+            content: &request.content,
+            text_content: request.text_content.as_deref(),
+            author: &request.author,
+            note: request.note.as_deref(),
+        },
+    )
+    .await?
+    .ok_or_else(|| AdminError::Conflict(format!("Another version of template {} was just created, try again", request.name)))?;
+
+    Ok((StatusCode::CREATED, Json(Template::from_row(&row))))
+}
+
+/// Lists every version of a template, the latest first
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The id of any version of the template
+/// # Returns
+/// * `Json<Vec<Template>>` - The versions
+pub async fn list_template_versions(Extension(db_client): Extension<Pool>, Path(id): Path<i32>) -> Result<Json<Vec<Template>>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    let row = template::get_by_id(&client, id).await?.ok_or(AdminError::NotFound("Template"))?;
+
+    let versions = template::get_versions(&client, row.get("shop_id"), row.get("name")).await?;
+
+    Ok(Json(versions.iter().map(Template::from_row).collect()))
+}
+
+/// Compares the content, subject, preheader and text content of two template versions
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `(id, other_id)` - The template versions to compare, from and to
+/// # Returns
+/// * `Json<TemplateDiff>` - A unified diff per field
+pub async fn diff_templates(Extension(db_client): Extension<Pool>, Path((id, other_id)): Path<(i32, i32)>) -> Result<Json<TemplateDiff>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    let from = template::get_by_id(&client, id).await?.ok_or(AdminError::NotFound("Template"))?;
+    let to = template::get_by_id(&client, other_id).await?.ok_or(AdminError::NotFound("Template"))?;
+
+    let header = |row: &Row, field: &str| format!("{} v{}{field}", row.get::<_, &str>("name"), row.get::<_, i32>("version"));
+    // Subjects, preheaders and text contents that aren't set compare as empty
+    let diff_field = |field: &str| {
+        let old = from.get::<_, Option<&str>>(field).unwrap_or_default();
+        let new = to.get::<_, Option<&str>>(field).unwrap_or_default();
+
+        diff(old, new, &header(&from, &format!(" {field}")), &header(&to, &format!(" {field}")))
+    };
+
+    Ok(Json(TemplateDiff {
+        from: id,
+        to: other_id,
+        diff: diff(from.get("content"), to.get("content"), &header(&from, ""), &header(&to, "")),
+        subject: diff_field("subject"),
+        preheader: diff_field("preheader"),
+        text_content: diff_field("text_content"),
+    }))
+}
+
+/// Deletes a template version that is not active
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The template id
+/// # Returns
+/// * `StatusCode` - `NO_CONTENT` if the template was deleted
+pub async fn delete_template(Extension(db_client): Extension<Pool>, Path(id): Path<i32>) -> Result<StatusCode, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    template::get_by_id(&client, id).await?.ok_or(AdminError::NotFound("Template"))?;
+
+    if !template::delete(&client, id).await? {
+        return Err(AdminError::Conflict("Template is active, activate another version first".to_string()));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Makes a template version the one used for a template type
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `id` - The template id
+/// * `request` - The template type, and the shop and locale to activate it for
+/// # Returns
+/// * `StatusCode` - `OK` if the template was activated
+pub async fn activate_template(
+    Extension(db_client): Extension<Pool>,
+    Path(id): Path<i32>,
+    Json(request): Json<ActivateTemplateRequest>,
+) -> Result<StatusCode, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    let row = template::get_by_id(&client, id).await?.ok_or(AdminError::NotFound("Template"))?;
+
+    // A shop's own template can't be used by other shops
+    let template_shop_id: Option<i32> = row.get("shop_id");
+    if template_shop_id.is_some() && template_shop_id != request.shop_id {
+        return Err(AdminError::BadRequest("Template belongs to another shop".to_string()));
+    }
+
+    let locale = request.locale.as_deref().map(locale::normalize);
+    if !template::activate(&client, id, &request.template_type, request.shop_id, locale.as_deref()).await? {
+        return Err(AdminError::NotFound("Template type"));
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Reloads the templates and partials of every shop, without waiting for the database notification
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `managers` - The template managers of every shop
+/// # Returns
+/// * `StatusCode` - `OK` if the templates were reloaded, `UNPROCESSABLE_ENTITY` if one doesn't compile and the current ones were kept
+pub async fn reload_templates(Extension(db_client): Extension<Pool>, Extension(managers): Extension<Managers>) -> Result<StatusCode, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    managers.reload(&client).await?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Activates an earlier version of the template used for a template type, and reloads the templates of every shop
+/// # Arguments
+/// * `db_client` - The database pool
+/// * `managers` - The template managers of every shop
+/// * `request` - The template type, the shop and locale, and optionally the version to go back to
+/// # Returns
+/// * `Json<Template>` - The version now active, `UNPROCESSABLE_ENTITY` if it or another template doesn't compile. A version
+///   activated before the reload failed stays active and is picked up by the next reload that succeeds
+pub async fn rollback_template(
+    Extension(db_client): Extension<Pool>,
+    Extension(managers): Extension<Managers>,
+    Json(request): Json<RollbackTemplateRequest>,
+) -> Result<Json<Template>, AdminError> {
+    let client = db_client.get_client().await.map_err(|_| AdminError::FailedToGetClient)?;
+    let locale = request.locale.as_deref().map(locale::normalize);
+    let row = template::get_rollback_version(&client, &request.template_type, request.shop_id, locale.as_deref(), request.version)
+        .await?
+        .ok_or(AdminError::NotFound("Template version"))?;
+
+    // Versions written with plain SQL may not compile, activating one would make every reload fail
+    validate_all([
+        Some(row.get::<_, &str>("content")),
+        row.get("subject"),
+        row.get("preheader"),
+        row.get("text_content"),
+    ])?;
+    if !template::activate(&client, row.get("id"), &request.template_type, request.shop_id, locale.as_deref()).await? {
+        return Err(AdminError::NotFound("Template type"));
+    }
+    managers.reload(&client).await?;
+
+    Ok(Json(Template::from_row(&row)))
+}
+
+// Checks the content, subject, preheader and text content of a template compile
+fn validate_all<'a>(contents: impl IntoIterator<Item = Option<&'a str>>) -> Result<(), AdminError> {
+    for content in contents.into_iter().flatten() {
+        validate(content)?;
+    }
+
+    Ok(())
+}
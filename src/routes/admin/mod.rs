@@ -0,0 +1,46 @@
+pub mod error;
+pub mod handlers;
+
+use crate::{
+    middlewares::{verify_admin_token, AdminToken},
+    services::{database::Pool, retention::RetentionMetrics, template::Managers},
+};
+use axum::{middleware, routing::delete, routing::get, routing::post, routing::put, Extension, Router};
+use handlers::{
+    activate_template, create_image, create_partial, create_template, delete_email_settings, delete_image, delete_partial, delete_template,
+    diff_templates, get_image, get_partial, get_template, list_dead_letters, list_email_settings, list_images, list_partials, list_samples,
+    list_template_versions, list_templates, preview_template, reload_templates, requeue_dead_letter, retention_metrics, rollback_template,
+    store_email_settings, store_sample, update_partial,
+};
+
+/// Routes of the admin API, every route requires the admin token.
+///
+/// Templates are never edited in place, saving one under an existing name adds a version that can be activated or rolled back to.
+/// Template, partial, image and email settings changes are picked up by the shops once the database notifies the change,
+/// `/admin/templates/reload` reloads them right away.
+pub fn router(db_client: Pool, admin_token: AdminToken, retention: RetentionMetrics, managers: Managers) -> Router {
+    Router::new()
+        .route("/admin/dead-letters", get(list_dead_letters))
+        .route("/admin/dead-letters/:outbox_id/requeue", post(requeue_dead_letter))
+        .route("/admin/retention", get(retention_metrics))
+        .route("/admin/templates", get(list_templates).post(create_template))
+        .route("/admin/templates/reload", post(reload_templates))
+        .route("/admin/templates/rollback", post(rollback_template))
+        .route("/admin/templates/:id", get(get_template).delete(delete_template))
+        .route("/admin/templates/:id/activate", post(activate_template))
+        .route("/admin/templates/:id/versions", get(list_template_versions))
+        .route("/admin/templates/:id/diff/:other_id", get(diff_templates))
+        .route("/admin/templates/:name/preview", post(preview_template))
+        .route("/admin/templates/:name/samples", get(list_samples))
+        .route("/admin/templates/:name/samples/:sample", put(store_sample))
+        .route("/admin/partials", get(list_partials).post(create_partial))
+        .route("/admin/partials/:id", get(get_partial).put(update_partial).delete(delete_partial))
+        .route("/admin/images", get(list_images).post(create_image))
+        .route("/admin/images/:id", get(get_image).delete(delete_image))
+        .route("/admin/email-settings", get(list_email_settings).put(store_email_settings))
+        .route("/admin/email-settings/:id", delete(delete_email_settings))
+        .layer(Extension(db_client))
+        .layer(Extension(retention))
+        .layer(Extension(managers))
+        .route_layer(middleware::from_fn_with_state(admin_token, verify_admin_token))
+}
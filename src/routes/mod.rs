@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod health_check;
 pub mod webhooks;
 
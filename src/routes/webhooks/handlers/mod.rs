@@ -1,6 +1,8 @@
 pub mod order_cancelled;
 pub mod order_created;
 pub mod order_fulfilled;
+#[cfg(test)]
+mod test_support;
 
 pub use order_cancelled::order_cancelled;
 pub use order_created::order_created;
@@ -1,5 +1,8 @@
-use crate::services::{document::create_pdf, email::MailerTrait, template::Manager};
-use crate::utils::{shopify::webhook_types::Customer, Email};
+use crate::services::{document::create_pdf, outbox::OutboxTrait, template::Manager};
+use crate::utils::{
+    shopify::{webhook_event::WebhookEvent, webhook_types::Customer},
+    Email, EmailAttachment,
+};
 use axum::extract::{Extension, Json};
 use axum::http::StatusCode;
 use serde::{Deserialize, Serialize};
@@ -8,17 +11,22 @@ use serde::{Deserialize, Serialize};
 pub struct FulfilledOrderWebhook {
     order_number: String,
     customer: Customer,
+    /// Locale the customer shopped in, e.g. `de-AT`, which the templates are picked by
+    #[serde(default)]
+    customer_locale: Option<String>,
 }
 
 /// Handles the order fulfilled webhook
 /// <https://shopify.dev/docs/api/webhooks?reference=toml#list-of-topics-orders/fulfilled>
 /// # Returns
 /// * `StatusCode` - The status code of the response
-pub async fn order_fulfilled<T: MailerTrait>(
-    Extension(mailer): Extension<T>,
+pub async fn order_fulfilled<T: OutboxTrait>(
+    Extension(outbox): Extension<T>,
     Extension(template_manager): Extension<Manager>,
+    Extension(event): Extension<WebhookEvent>,
     Json(payload): Json<FulfilledOrderWebhook>,
 ) -> StatusCode {
+    let template_manager = template_manager.with_locale(payload.customer_locale.as_deref());
     let Ok(template_filled_invoice) = template_manager.get_template_filled("invoice", &payload) else {
         println!("Error getting template filled invoice for order {}", payload.order_number);
         return StatusCode::INTERNAL_SERVER_ERROR;
@@ -28,27 +36,27 @@ pub async fn order_fulfilled<T: MailerTrait>(
         return StatusCode::INTERNAL_SERVER_ERROR;
     };
 
-    let Ok(template_filled_mail_content) = template_manager.get_template_filled("order_fulfilled", &payload) else {
+    let Ok(filled) = template_manager.get_email_filled("order_fulfilled", &payload) else {
         println!("Error getting template filled mail content for order {}", payload.order_number);
         return StatusCode::INTERNAL_SERVER_ERROR;
     };
 
+    let mut attachments = filled.images;
+    attachments.push(EmailAttachment::new("invoice.pdf", "application/pdf", invoice));
+
     let email = Email {
         to: payload.customer.email,
-        subject: "Order Fulfilled".to_string(),
-        html_body: template_filled_mail_content,
-        attachment: Some(invoice),
-    };
-
-    let mail = match mailer.create_mail(email) {
-        Ok(mail) => mail,
-        Err(e) => {
-            println!("Error creating mail: {e}");
-            return StatusCode::INTERNAL_SERVER_ERROR;
-        }
+        cc: filled.cc,
+        bcc: filled.bcc,
+        reply_to: filled.reply_to,
+        headers: filled.headers,
+        subject: filled.subject,
+        html_body: filled.html_body,
+        text_body: Some(filled.text_body),
+        attachments,
     };
 
-    match mailer.send_mail(mail).await {
+    match outbox.enqueue(&event, email).await {
         Ok(()) => StatusCode::OK,
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
     }
@@ -57,55 +65,19 @@ pub async fn order_fulfilled<T: MailerTrait>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::services::email::MailerError;
+    use crate::routes::webhooks::handlers::test_support::{event, MockOutbox};
     use handlebars::Handlebars;
-    use lettre::Message;
-
-    #[derive(Clone)]
-    struct MockMailer {
-        should_fail_create: bool,
-        should_fail_send: bool,
-    }
-
-    #[async_trait::async_trait]
-    impl MailerTrait for MockMailer {
-        fn new(_: String, _: String, _: &str, _: String, _: u16) -> Self {
-            Self {
-                should_fail_create: false,
-                should_fail_send: false,
-            }
-        }
-
-        fn create_mail(&self, email: Email) -> Result<Message, MailerError> {
-            if self.should_fail_create {
-                return Err(MailerError::BuildEmailError);
-            }
-            Ok(Message::builder()
-                .from("test@test.com".parse().unwrap())
-                .to(email.to.parse().unwrap())
-                .subject(email.subject)
-                .body("Test body".to_string())
-                .unwrap())
-        }
-
-        async fn send_mail(&self, _: Message) -> Result<(), MailerError> {
-            if self.should_fail_send {
-                return Err(MailerError::SmtpSendError);
-            }
-            Ok(())
-        }
-    }
 
     #[tokio::test]
     async fn test_order_fulfilled_success() {
-        let mailer = MockMailer {
-            should_fail_create: false,
-            should_fail_send: false,
-        };
+        let outbox = MockOutbox { should_fail_enqueue: false };
         let mut handlebars = Handlebars::new();
         handlebars.register_template_string("invoice", "Test invoice template").unwrap();
         handlebars.register_template_string("order_fulfilled", "Test email template").unwrap();
         let template_manager = Manager::new(handlebars);
+        template_manager
+            .upsert_subject("order_fulfilled", "Order {{order_number}} fulfilled")
+            .unwrap();
 
         let payload = FulfilledOrderWebhook {
             customer: Customer {
@@ -114,19 +86,23 @@ mod tests {
                 last_name: "Doe".to_string(),
             },
             order_number: "1234".to_string(),
+            customer_locale: None,
         };
 
-        let result = order_fulfilled(Extension(mailer), Extension(template_manager), Json(payload)).await;
+        let result = order_fulfilled(
+            Extension(outbox),
+            Extension(template_manager),
+            Extension(event("orders/fulfilled")),
+            Json(payload),
+        )
+        .await;
 
         assert_eq!(result, StatusCode::OK);
     }
 
     #[tokio::test]
     async fn test_order_fulfilled_invoice_template_error() {
-        let mailer = MockMailer {
-            should_fail_create: false,
-            should_fail_send: false,
-        };
+        let outbox = MockOutbox { should_fail_enqueue: false };
         let handlebars = Handlebars::new(); // No template registered
         let template_manager = Manager::new(handlebars);
 
@@ -137,19 +113,23 @@ mod tests {
                 last_name: "Doe".to_string(),
             },
             order_number: "1234".to_string(),
+            customer_locale: None,
         };
 
-        let result = order_fulfilled(Extension(mailer), Extension(template_manager), Json(payload)).await;
+        let result = order_fulfilled(
+            Extension(outbox),
+            Extension(template_manager),
+            Extension(event("orders/fulfilled")),
+            Json(payload),
+        )
+        .await;
 
         assert_eq!(result, StatusCode::INTERNAL_SERVER_ERROR);
     }
 
     #[tokio::test]
     async fn test_order_fulfilled_email_template_error() {
-        let mailer = MockMailer {
-            should_fail_create: false,
-            should_fail_send: false,
-        };
+        let outbox = MockOutbox { should_fail_enqueue: false };
         let mut handlebars = Handlebars::new();
         handlebars.register_template_string("invoice", "Test invoice template").unwrap();
         // Not registering order_fulfilled template
@@ -162,48 +142,30 @@ mod tests {
                 last_name: "Doe".to_string(),
             },
             order_number: "1234".to_string(),
+            customer_locale: None,
         };
 
-        let result = order_fulfilled(Extension(mailer), Extension(template_manager), Json(payload)).await;
-
-        assert_eq!(result, StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    #[tokio::test]
-    async fn test_order_fulfilled_mail_creation_error() {
-        let mailer = MockMailer {
-            should_fail_create: true,
-            should_fail_send: false,
-        };
-        let mut handlebars = Handlebars::new();
-        handlebars.register_template_string("invoice", "Test invoice template").unwrap();
-        handlebars.register_template_string("order_fulfilled", "Test email template").unwrap();
-        let template_manager = Manager::new(handlebars);
-
-        let payload = FulfilledOrderWebhook {
-            customer: Customer {
-                email: "test@test.com".to_string(),
-                first_name: "John".to_string(),
-                last_name: "Doe".to_string(),
-            },
-            order_number: "1234".to_string(),
-        };
-
-        let result = order_fulfilled(Extension(mailer), Extension(template_manager), Json(payload)).await;
+        let result = order_fulfilled(
+            Extension(outbox),
+            Extension(template_manager),
+            Extension(event("orders/fulfilled")),
+            Json(payload),
+        )
+        .await;
 
         assert_eq!(result, StatusCode::INTERNAL_SERVER_ERROR);
     }
 
     #[tokio::test]
-    async fn test_order_fulfilled_mail_send_error() {
-        let mailer = MockMailer {
-            should_fail_create: false,
-            should_fail_send: true,
-        };
+    async fn test_order_fulfilled_enqueue_error() {
+        let outbox = MockOutbox { should_fail_enqueue: true };
         let mut handlebars = Handlebars::new();
         handlebars.register_template_string("invoice", "Test invoice template").unwrap();
         handlebars.register_template_string("order_fulfilled", "Test email template").unwrap();
         let template_manager = Manager::new(handlebars);
+        template_manager
+            .upsert_subject("order_fulfilled", "Order {{order_number}} fulfilled")
+            .unwrap();
 
         let payload = FulfilledOrderWebhook {
             customer: Customer {
@@ -212,19 +174,23 @@ mod tests {
                 last_name: "Doe".to_string(),
             },
             order_number: "1234".to_string(),
+            customer_locale: None,
         };
 
-        let result = order_fulfilled(Extension(mailer), Extension(template_manager), Json(payload)).await;
+        let result = order_fulfilled(
+            Extension(outbox),
+            Extension(template_manager),
+            Extension(event("orders/fulfilled")),
+            Json(payload),
+        )
+        .await;
 
         assert_eq!(result, StatusCode::INTERNAL_SERVER_ERROR);
     }
 
     #[tokio::test]
     async fn test_order_fulfilled_pdf_creation_error() {
-        let mailer = MockMailer {
-            should_fail_create: false,
-            should_fail_send: false,
-        };
+        let outbox = MockOutbox { should_fail_enqueue: false };
         let mut handlebars = Handlebars::new();
         handlebars.register_template_string("invoice", "<h1>Test invoice template").unwrap();
         let template_manager = Manager::new(handlebars);
@@ -236,9 +202,16 @@ mod tests {
                 last_name: "Doe".to_string(),
             },
             order_number: "1234".to_string(),
+            customer_locale: None,
         };
 
-        let result = order_fulfilled(Extension(mailer), Extension(template_manager), Json(payload)).await;
+        let result = order_fulfilled(
+            Extension(outbox),
+            Extension(template_manager),
+            Extension(event("orders/fulfilled")),
+            Json(payload),
+        )
+        .await;
 
         assert_eq!(result, StatusCode::INTERNAL_SERVER_ERROR);
     }
@@ -0,0 +1,31 @@
+use crate::services::outbox::{OutboxError, OutboxTrait};
+use crate::utils::{shopify::webhook_event::WebhookEvent, Email};
+
+/// Outbox accepting every email, or failing to queue any of them.
+#[derive(Clone)]
+pub(super) struct MockOutbox {
+    pub(super) should_fail_enqueue: bool,
+}
+
+#[async_trait::async_trait]
+impl OutboxTrait for MockOutbox {
+    async fn enqueue(&self, _: &WebhookEvent, _: Email) -> Result<(), OutboxError> {
+        if self.should_fail_enqueue {
+            return Err(OutboxError::Transaction);
+        }
+        Ok(())
+    }
+}
+
+/// A claimed event of the topic, as the Shopify origin middleware hands it to the handlers.
+pub(super) fn event(topic: &str) -> WebhookEvent {
+    WebhookEvent {
+        event_id: "1234567890".to_string(),
+        webhook_secret_id: "default".to_string(),
+        topic: topic.to_string(),
+        webhook_id: "b54557e4-bdd9-4b37-8a5f-bf7d70bcd043".to_string(),
+        shop_domain: "test.myshopify.com".to_string(),
+        api_version: "2024-10".to_string(),
+        locked_until: None,
+    }
+}
@@ -1,16 +1,20 @@
 use deadpool_postgres::{Client, Config, Runtime};
 use thiserror::Error;
-use tokio_postgres::NoTls;
+use tokio_postgres::{tls::NoTlsStream, Connection, NoTls, Socket};
 
 #[derive(Error, Debug, PartialEq)]
 pub enum PoolError {
     #[error("Failed to get client from pool")]
     FailedToGetClient,
+
+    #[error("Failed to connect to the database")]
+    FailedToConnect,
 }
 
 #[derive(Clone)]
 pub struct Pool {
     pool: deadpool_postgres::Pool,
+    config: tokio_postgres::Config,
 }
 
 impl Pool {
@@ -28,6 +32,7 @@ impl Pool {
         setup_config.password = Some(db_password);
 
         Self {
+            config: setup_config.get_pg_config().unwrap(),
             pool: setup_config.create_pool(Some(Runtime::Tokio1), NoTls).unwrap(),
         }
     }
@@ -40,6 +45,17 @@ impl Pool {
     pub async fn get_client(&self) -> Result<Client, PoolError> {
         self.pool.get().await.map_err(|_| PoolError::FailedToGetClient)
     }
+
+    /// Opens a connection outside of the pool, for sessions that have to stay open such as `LISTEN`.
+    ///
+    /// The connection has to be polled for the client to make progress.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PoolError::FailedToConnect` if the connection cannot be established.
+    pub async fn connect(&self) -> Result<(tokio_postgres::Client, Connection<Socket, NoTlsStream>), PoolError> {
+        self.config.connect(NoTls).await.map_err(|_| PoolError::FailedToConnect)
+    }
 }
 
 #[cfg(test)]
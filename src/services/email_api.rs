@@ -0,0 +1,204 @@
+use crate::services::email::MailerError;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lettre::Message;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+
+/// Adapts mails to the send API of a transactional email provider.
+pub trait EmailProvider: Send + Sync {
+    /// Converts a built mail into the JSON body of the provider's send request.
+    fn request(&self, message: &Message) -> Value;
+
+    /// Maps a response the provider didn't accept the mail with to an error, telling rejected mails from the ones
+    /// worth sending again later.
+    fn error(&self, status: StatusCode, body: &str) -> MailerError;
+}
+
+/// A provider taking the envelope and the raw MIME mail as JSON, answering errors with `{"code": ..., "message": ...}`.
+///
+/// Sending the mail as it was built keeps its attachments, headers and DKIM signature intact.
+pub struct JsonProvider;
+
+#[derive(Deserialize, Default)]
+struct JsonProviderError {
+    code: Option<String>,
+    message: Option<String>,
+}
+
+impl EmailProvider for JsonProvider {
+    fn request(&self, message: &Message) -> Value {
+        let envelope = message.envelope();
+
+        json!({
+            "from": envelope.from().map(ToString::to_string),
+            // The envelope includes the BCC recipients the headers leave out
+            "to": envelope.to().iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "subject": message.headers().get_raw("Subject"),
+            "raw": STANDARD.encode(message.formatted()),
+        })
+    }
+
+    fn error(&self, status: StatusCode, body: &str) -> MailerError {
+        let error: JsonProviderError = serde_json::from_str(body).unwrap_or_default();
+        let description = format!("{status} {}", error.message.as_deref().unwrap_or(body)).trim().to_string();
+
+        match error.code.as_deref() {
+            Some("invalid_recipient") => MailerError::InvalidRecipientEmail,
+            Some("rate_limited" | "unavailable") => MailerError::ApiTransientError(description),
+            _ if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT || status.is_server_error() => {
+                MailerError::ApiTransientError(description)
+            }
+            _ => MailerError::ApiPermanentError(description),
+        }
+    }
+}
+
+/// Sends mails through the HTTP API of a transactional email provider.
+#[derive(Clone)]
+pub struct HttpTransport {
+    client: Client,
+    endpoint: String,
+    api_key: String,
+    provider: Arc<dyn EmailProvider>,
+}
+
+impl HttpTransport {
+    /// Creates a transport posting to `endpoint`, authenticated with `api_key` as a bearer token unless it is empty.
+    #[must_use]
+    pub fn new(provider: impl EmailProvider + 'static, endpoint: String, api_key: String) -> Self {
+        Self {
+            client: Client::builder().timeout(Duration::from_secs(10)).build().unwrap_or_default(),
+            endpoint,
+            api_key,
+            provider: Arc::new(provider),
+        }
+    }
+
+    /// Sends a mail.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MailerError::ApiTransientError` if the provider cannot be reached or asks to try again later.
+    /// Returns `MailerError::ApiPermanentError` or `MailerError::InvalidRecipientEmail` if the provider rejected the mail.
+    pub async fn send(&self, message: &Message) -> Result<(), MailerError> {
+        let mut request = self.client.post(&self.endpoint).json(&self.provider.request(message));
+        if !self.api_key.is_empty() {
+            request = request.bearer_auth(&self.api_key);
+        }
+
+        let response = request.send().await.map_err(|e| MailerError::ApiTransientError(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        Err(self.provider.error(status, &body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::HeaderMap, routing::post, Json, Router};
+    use std::sync::Mutex;
+
+    type Received = Arc<Mutex<Vec<(Option<String>, Value)>>>;
+
+    // A stand-in for the provider, answering every request with the given status and body
+    async fn stub_provider(status: StatusCode, body: &'static str) -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new()
+            .route(
+                "/send",
+                post(
+                    move |State(received): State<Received>, headers: HeaderMap, Json(request): Json<Value>| async move {
+                        let authorization = headers.get("Authorization").map(|value| value.to_str().unwrap().to_string());
+                        received.lock().unwrap().push((authorization, request));
+                        (status, body)
+                    },
+                ),
+            )
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/send", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (endpoint, received)
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .from("Shop <shop@test.com>".parse().unwrap())
+            .to("customer@test.com".parse().unwrap())
+            .bcc("archive@test.com".parse().unwrap())
+            .subject("Your order")
+            .body("Test body".to_string())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let (endpoint, received) = stub_provider(StatusCode::ACCEPTED, r#"{"id": "1"}"#).await;
+        let transport = HttpTransport::new(JsonProvider, endpoint, "api_key".to_string());
+
+        transport.send(&message()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let (authorization, request) = &received[0];
+        assert_eq!(authorization.as_deref(), Some("Bearer api_key"));
+        assert_eq!(request["from"], "shop@test.com");
+        assert_eq!(request["to"], json!(["customer@test.com", "archive@test.com"]));
+        assert_eq!(request["subject"], "Your order");
+        let raw = String::from_utf8(STANDARD.decode(request["raw"].as_str().unwrap()).unwrap()).unwrap();
+        assert!(raw.contains("Subject: Your order\r\n"));
+        assert!(!raw.contains("archive@test.com"));
+    }
+
+    #[tokio::test]
+    async fn test_send_errors() {
+        let cases = [
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                r#"{"code": "invalid_recipient", "message": "No such mailbox"}"#,
+                false,
+            ),
+            (StatusCode::TOO_MANY_REQUESTS, r#"{"code": "rate_limited"}"#, true),
+            (StatusCode::SERVICE_UNAVAILABLE, "Maintenance", true),
+            (
+                StatusCode::UNAUTHORIZED,
+                r#"{"code": "invalid_api_key", "message": "Unknown API key"}"#,
+                false,
+            ),
+        ];
+
+        for (status, body, transient) in cases {
+            let (endpoint, _) = stub_provider(status, body).await;
+            let error = HttpTransport::new(JsonProvider, endpoint, String::new())
+                .send(&message())
+                .await
+                .unwrap_err();
+            assert_eq!(error.is_transient(), transient, "{status}: {error}");
+        }
+
+        let error = JsonProvider.error(StatusCode::UNPROCESSABLE_ENTITY, r#"{"code": "invalid_recipient"}"#);
+        assert!(matches!(error, MailerError::InvalidRecipientEmail));
+        let error = JsonProvider.error(StatusCode::UNAUTHORIZED, r#"{"code": "invalid_api_key", "message": "Unknown API key"}"#);
+        assert!(matches!(error, MailerError::ApiPermanentError(description) if description == "401 Unauthorized Unknown API key"));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_provider_is_transient() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/send", listener.local_addr().unwrap());
+        drop(listener);
+
+        let error = HttpTransport::new(JsonProvider, endpoint, String::new())
+            .send(&message())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, MailerError::ApiTransientError(_)));
+    }
+}
@@ -0,0 +1,272 @@
+use crate::services::locale::number_param;
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, JsonRender, JsonValue, Output, RenderContext, RenderError, RenderErrorReason, ScopedJson,
+};
+use url::Url;
+
+// Schemes `url` builds links for, anything else such as javascript: is refused
+const SAFE_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+fn derived<'rc>(value: impl Into<JsonValue>) -> Result<ScopedJson<'rc>, RenderError> {
+    Ok(ScopedJson::Derived(value.into()))
+}
+
+// Whole results stay integers so quantities aren't written as 3.0, and the noise of binary fractions is rounded
+// away so 19.9 * 3 is 59.7
+fn number_value(number: f64) -> JsonValue {
+    let number = (number * 1e9).round() / 1e9;
+
+    if number.fract() == 0.0 && number.abs() < 9_007_199_254_740_992.0 {
+        #[allow(clippy::cast_possible_truncation)]
+        return JsonValue::from(number as i64);
+    }
+
+    serde_json::Number::from_f64(number).map_or(JsonValue::Null, JsonValue::Number)
+}
+
+fn is_blank(value: &JsonValue) -> bool {
+    match value {
+        JsonValue::Null => true,
+        JsonValue::String(value) => value.trim().is_empty(),
+        _ => false,
+    }
+}
+
+/// `{{pluralize quantity "item"}}` or `{{pluralize quantity "person" "people"}}`, the plural defaults to the word with an s
+#[derive(Clone, Copy)]
+struct Pluralize;
+
+impl HelperDef for Pluralize {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let count = number_param(helper, "pluralize", 0)?;
+        let singular = helper
+            .param(1)
+            .and_then(|singular| singular.value().as_str())
+            .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("pluralize", 1))?;
+
+        if (count - 1.0).abs() < f64::EPSILON {
+            return derived(singular);
+        }
+
+        match helper.param(2).and_then(|plural| plural.value().as_str()) {
+            Some(plural) => derived(plural),
+            None => derived(format!("{singular}s")),
+        }
+    }
+}
+
+/// `{{default customer.first_name "customer"}}`, the first value that is set and not blank
+#[derive(Clone, Copy)]
+struct DefaultValue;
+
+impl HelperDef for DefaultValue {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        if helper.params().is_empty() {
+            return Err(RenderErrorReason::ParamNotFoundForIndex("default", 0).into());
+        }
+
+        let value = helper
+            .params()
+            .iter()
+            .map(|param| param.value())
+            .find(|value| !is_blank(value))
+            .cloned()
+            .unwrap_or(JsonValue::Null);
+
+        derived(value)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Add => "add",
+            Self::Subtract => "subtract",
+            Self::Multiply => "multiply",
+            Self::Divide => "divide",
+        }
+    }
+}
+
+/// `{{multiply price quantity}}`, also `add`, `subtract` and `divide`, with numbers or numeric strings
+#[derive(Clone, Copy)]
+struct Math(Operation);
+
+impl HelperDef for Math {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let left = number_param(helper, self.0.name(), 0)?;
+        let right = number_param(helper, self.0.name(), 1)?;
+
+        let result = match self.0 {
+            Operation::Add => left + right,
+            Operation::Subtract => left - right,
+            Operation::Multiply => left * right,
+            Operation::Divide if right == 0.0 => return Err(RenderErrorReason::Other("Division by zero".to_string()).into()),
+            Operation::Divide => left / right,
+        };
+
+        derived(number_value(result))
+    }
+}
+
+/// `{{format_address shipping_address}}`, the lines of a Shopify address joined by `<br>`, or by `separator=", "`
+///
+/// Each line is escaped, only the separator is written as is.
+#[derive(Clone, Copy)]
+struct FormatAddress;
+
+impl FormatAddress {
+    fn lines(address: &JsonValue) -> Vec<String> {
+        let field = |name: &str| {
+            address
+                .get(name)
+                .and_then(JsonValue::as_str)
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        let join = |parts: &[Option<&str>]| Some(parts.iter().flatten().copied().collect::<Vec<_>>().join(" ")).filter(|line| !line.is_empty());
+
+        let name = field("name")
+            .map(str::to_string)
+            .or_else(|| join(&[field("first_name"), field("last_name")]));
+
+        // Where the postal code goes differs by country, most of Europe writes it before the city
+        let locality = match field("country_code").map(str::to_ascii_uppercase).as_deref() {
+            Some("US" | "CA" | "AU") => {
+                let region = join(&[field("province_code").or(field("province")), field("zip")]);
+                match (field("city"), region) {
+                    (Some(city), Some(region)) => Some(format!("{city}, {region}")),
+                    (city, region) => city.map(str::to_string).or(region),
+                }
+            }
+            Some("GB" | "IE") => join(&[field("city"), field("zip")]),
+            _ => join(&[field("zip"), field("city")]),
+        };
+
+        [
+            name,
+            field("company").map(str::to_string),
+            field("address1").map(str::to_string),
+            field("address2").map(str::to_string),
+            locality,
+            field("country").map(str::to_string),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl HelperDef for FormatAddress {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        handlebars: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let address = helper
+            .param(0)
+            .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("format_address", 0))?
+            .value();
+        let separator = helper
+            .hash_get("separator")
+            .and_then(|separator| separator.value().as_str())
+            .unwrap_or("<br>");
+
+        let escape = handlebars.get_escape_fn();
+        let lines: Vec<String> = Self::lines(address).iter().map(|line| escape(line)).collect();
+
+        out.write(&lines.join(separator))?;
+        Ok(())
+    }
+}
+
+/// `{{url shop_url "orders" order_id token=token}}`, a link with its path segments and query values percent-encoded
+///
+/// Only http, https and mailto links are built, so a payload can't turn a button into a script.
+/// The link is HTML escaped like any other value, which browsers undo when reading an `href`.
+#[derive(Clone, Copy)]
+struct BuildUrl;
+
+impl HelperDef for BuildUrl {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let base = helper
+            .param(0)
+            .and_then(|base| base.value().as_str())
+            .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("url", 0))?;
+
+        let mut url = Url::parse(base).map_err(|_| RenderErrorReason::Other(format!("Invalid URL {base}")))?;
+        if !SAFE_SCHEMES.contains(&url.scheme()) {
+            return Err(RenderErrorReason::Other(format!("URL scheme {} is not allowed", url.scheme())).into());
+        }
+
+        if helper.params().len() > 1 {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|()| RenderErrorReason::Other(format!("URL {base} cannot have path segments")))?;
+            segments.pop_if_empty();
+            for segment in &helper.params()[1..] {
+                segments.push(&segment.value().render());
+            }
+        }
+
+        // Values that aren't set are left out rather than sent as empty parameters
+        let pairs: Vec<(&str, String)> = helper
+            .hash()
+            .iter()
+            .filter(|(_, value)| !is_blank(value.value()))
+            .map(|(name, value)| (*name, value.value().render()))
+            .collect();
+        if !pairs.is_empty() {
+            url.query_pairs_mut().extend_pairs(pairs);
+        }
+
+        derived(String::from(url))
+    }
+}
+
+/// Registers the helpers that don't depend on the locale: `pluralize`, `default`, `add`, `subtract`, `multiply`,
+/// `divide`, `format_address` and `url`.
+pub fn register(handlebars: &mut Handlebars<'static>) {
+    handlebars.register_helper("pluralize", Box::new(Pluralize));
+    handlebars.register_helper("default", Box::new(DefaultValue));
+    for operation in [Operation::Add, Operation::Subtract, Operation::Multiply, Operation::Divide] {
+        handlebars.register_helper(operation.name(), Box::new(Math(operation)));
+    }
+    handlebars.register_helper("format_address", Box::new(FormatAddress));
+    handlebars.register_helper("url", Box::new(BuildUrl));
+}
@@ -0,0 +1,410 @@
+use handlebars::{Context, Handlebars, Helper, HelperDef, JsonValue, RenderContext, RenderError, RenderErrorReason, ScopedJson};
+use time::{
+    format_description::{self, well_known::Rfc3339},
+    macros::format_description,
+    Date, OffsetDateTime, Time, UtcOffset,
+};
+
+/// Normalizes a locale such as `de_AT` or `de-AT` to `de-at`, the form templates are looked up by.
+#[must_use]
+pub fn normalize(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_ascii_lowercase()
+}
+
+/// Gets the locales to try in order, from the most specific, e.g. `de-AT`, `de`, then the shop default and its language.
+#[must_use]
+pub fn fallback_chain(locale: Option<&str>, default_locale: Option<&str>) -> Vec<String> {
+    let mut chain: Vec<String> = Vec::new();
+
+    for locale in [locale, default_locale].into_iter().flatten() {
+        let locale = normalize(locale);
+        let mut end = locale.len();
+
+        loop {
+            let candidate = &locale[..end];
+            if !candidate.is_empty() && !chain.iter().any(|known| known == candidate) {
+                chain.push(candidate.to_string());
+            }
+
+            match candidate.rfind('-') {
+                Some(separator) => end = separator,
+                None => break,
+            }
+        }
+    }
+
+    chain
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum DateOrder {
+    DayMonthYear,
+    MonthDayYear,
+    YearMonthDay,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum SymbolPosition {
+    Before,
+    After,
+}
+
+/// How a locale writes numbers, dates and amounts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Conventions {
+    decimal: char,
+    group: char,
+    date_order: DateOrder,
+    date_separator: char,
+    twelve_hour_clock: bool,
+    symbol: SymbolPosition,
+}
+
+const NARROW_NO_BREAK_SPACE: char = '\u{202f}';
+
+impl Conventions {
+    /// Gets the conventions of a locale, by its language and for English also its region.
+    ///
+    /// Unknown locales, and no locale at all, are written the US English way.
+    #[must_use]
+    pub fn of(locale: Option<&str>) -> Self {
+        let locale = locale.map(normalize).unwrap_or_default();
+        let (language, region) = locale.split_once('-').unwrap_or((&locale, ""));
+
+        let (decimal, group, date_order, date_separator, symbol) = match language {
+            "en" if !region.is_empty() && region != "us" => ('.', ',', DateOrder::DayMonthYear, '/', SymbolPosition::Before),
+            "de" | "da" | "nl" => (
+                ',',
+                '.',
+                DateOrder::DayMonthYear,
+                if language == "nl" { '-' } else { '.' },
+                SymbolPosition::After,
+            ),
+            "es" | "it" | "pt" => (',', '.', DateOrder::DayMonthYear, '/', SymbolPosition::After),
+            "fr" => (',', NARROW_NO_BREAK_SPACE, DateOrder::DayMonthYear, '/', SymbolPosition::After),
+            "fi" | "nb" | "no" | "pl" => (',', NARROW_NO_BREAK_SPACE, DateOrder::DayMonthYear, '.', SymbolPosition::After),
+            "sv" => (',', NARROW_NO_BREAK_SPACE, DateOrder::YearMonthDay, '-', SymbolPosition::After),
+            "ja" | "zh" | "ko" => ('.', ',', DateOrder::YearMonthDay, '/', SymbolPosition::Before),
+            _ => ('.', ',', DateOrder::MonthDayYear, '/', SymbolPosition::Before),
+        };
+
+        Self {
+            decimal,
+            group,
+            date_order,
+            date_separator,
+            // Only the places writing the month first also use AM and PM
+            twelve_hour_clock: date_order == DateOrder::MonthDayYear,
+            symbol,
+        }
+    }
+
+    /// Writes a number with the locale's separators, rounded to `decimals`.
+    #[must_use]
+    pub fn format_number(&self, number: f64, decimals: usize) -> String {
+        let formatted = format!("{:.*}", decimals, number.abs());
+        let (integer, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+
+        let mut grouped = String::with_capacity(formatted.len() + integer.len() / 3);
+        for (index, digit) in integer.chars().enumerate() {
+            if index > 0 && (integer.len() - index) % 3 == 0 {
+                grouped.push(self.group);
+            }
+            grouped.push(digit);
+        }
+
+        // Rounding can turn a tiny negative number into zero, which has no sign
+        let sign = if number < 0.0 && formatted.chars().any(|digit| digit.is_ascii_digit() && digit != '0') {
+            "-"
+        } else {
+            ""
+        };
+
+        if fraction.is_empty() {
+            format!("{sign}{grouped}")
+        } else {
+            format!("{sign}{grouped}{}{fraction}", self.decimal)
+        }
+    }
+
+    /// Writes an amount in a currency, e.g. `$1,234.50` or `1.234,50 €`.
+    #[must_use]
+    pub fn format_currency(&self, amount: f64, currency: &str) -> String {
+        let currency = currency.to_ascii_uppercase();
+        let (symbol, decimals) = match currency.as_str() {
+            "USD" => ("$", 2),
+            "EUR" => ("€", 2),
+            "GBP" => ("£", 2),
+            "JPY" => ("¥", 0),
+            "DKK" | "NOK" | "SEK" => ("kr.", 2),
+            _ => (currency.as_str(), 2),
+        };
+        let number = self.format_number(amount, decimals);
+
+        match self.symbol {
+            SymbolPosition::Before if symbol.chars().all(|c| !c.is_ascii_alphabetic()) => format!("{symbol}{number}"),
+            SymbolPosition::Before => format!("{symbol} {number}"),
+            SymbolPosition::After => format!("{number} {symbol}"),
+        }
+    }
+
+    /// Writes the date part of a date or timestamp, in the time zone it was given in.
+    #[must_use]
+    pub fn format_date(&self, date: Date) -> String {
+        let (day, month, year) = (date.day(), u8::from(date.month()), date.year());
+        let separator = self.date_separator;
+
+        match self.date_order {
+            DateOrder::DayMonthYear => format!("{day:02}{separator}{month:02}{separator}{year}"),
+            DateOrder::MonthDayYear => format!("{month:02}{separator}{day:02}{separator}{year}"),
+            DateOrder::YearMonthDay => format!("{year}{separator}{month:02}{separator}{day:02}"),
+        }
+    }
+
+    /// Writes the hours and minutes of a time, e.g. `2:05 PM` or `14:05`.
+    #[must_use]
+    pub fn format_time(&self, time: Time) -> String {
+        let (hour, minute) = (time.hour(), time.minute());
+
+        if self.twelve_hour_clock {
+            let period = if hour < 12 { "AM" } else { "PM" };
+            let hour = match hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            format!("{hour}:{minute:02} {period}")
+        } else {
+            format!("{hour:02}:{minute:02}")
+        }
+    }
+}
+
+// Shopify sends timestamps such as 2024-01-05T10:00:00-05:00 in the shop's time zone, and some fields only as a date.
+// Dates without a time are not moved to another time zone.
+fn parse_date(value: &str, timezone: Option<UtcOffset>) -> Option<Date> {
+    OffsetDateTime::parse(value, &Rfc3339)
+        .map(|datetime| timezone.map_or(datetime, |timezone| datetime.to_offset(timezone)).date())
+        .or_else(|_| Date::parse(value, format_description!("[year]-[month]-[day]")))
+        .ok()
+}
+
+// `timezone="UTC"` or an offset such as `timezone="+02:00"`, a time zone database isn't bundled so names like
+// Europe/Berlin can't be resolved
+fn timezone_hash(helper: &Helper) -> Result<Option<UtcOffset>, RenderError> {
+    let Some(timezone) = helper.hash_get("timezone").and_then(|timezone| timezone.value().as_str()) else {
+        return Ok(None);
+    };
+
+    if timezone.eq_ignore_ascii_case("utc") || timezone == "Z" {
+        return Ok(Some(UtcOffset::UTC));
+    }
+
+    UtcOffset::parse(timezone, format_description!("[offset_hour sign:mandatory]:[offset_minute]"))
+        .map(Some)
+        .map_err(|_| RenderErrorReason::Other(format!("Unknown time zone {timezone}, use UTC or an offset such as +02:00")).into())
+}
+
+/// Gets a parameter as a number, Shopify sends amounts as strings, e.g. "199.00".
+pub(crate) fn number_param(helper: &Helper, name: &'static str, index: usize) -> Result<f64, RenderError> {
+    let value = helper
+        .param(index)
+        .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex(name, index))?
+        .value();
+
+    match value {
+        JsonValue::Number(number) => number.as_f64(),
+        JsonValue::String(number) => number.trim().parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| RenderErrorReason::InvalidParamType("number").into())
+}
+
+/// `{{format_number value decimals=2}}`, decimals default to none for whole numbers and 2 otherwise
+#[derive(Clone, Copy)]
+struct FormatNumber(Conventions);
+
+impl HelperDef for FormatNumber {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let number = number_param(helper, "format_number", 0)?;
+        let decimals = match helper.hash_get("decimals").and_then(|decimals| decimals.value().as_u64()) {
+            Some(decimals) => usize::try_from(decimals).unwrap_or(2),
+            None if number.fract() == 0.0 => 0,
+            None => 2,
+        };
+
+        Ok(ScopedJson::Derived(JsonValue::String(self.0.format_number(number, decimals))))
+    }
+}
+
+/// `{{format_currency total_price currency}}`, `with_code=true` adds the currency code, e.g. `$10.00 USD`
+#[derive(Clone, Copy)]
+struct FormatCurrency(Conventions);
+
+impl HelperDef for FormatCurrency {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let amount = number_param(helper, "format_currency", 0)?;
+        let currency = helper
+            .param(1)
+            .and_then(|currency| currency.value().as_str())
+            .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("format_currency", 1))?;
+
+        let mut formatted = self.0.format_currency(amount, currency);
+        if helper
+            .hash_get("with_code")
+            .is_some_and(|with_code| with_code.value().as_bool() == Some(true))
+        {
+            formatted = format!("{formatted} {}", currency.to_ascii_uppercase());
+        }
+
+        Ok(ScopedJson::Derived(JsonValue::String(formatted)))
+    }
+}
+
+/// `{{format_date created_at timezone="UTC"}}`, values that are not dates are written as they are
+#[derive(Clone, Copy)]
+struct FormatDate(Conventions);
+
+impl HelperDef for FormatDate {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let value = helper
+            .param(0)
+            .and_then(|value| value.value().as_str())
+            .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("format_date", 0))?;
+
+        let formatted = parse_date(value, timezone_hash(helper)?).map_or_else(|| value.to_string(), |date| self.0.format_date(date));
+
+        Ok(ScopedJson::Derived(JsonValue::String(formatted)))
+    }
+}
+
+/// `{{format_datetime created_at timezone="+02:00" format="[day].[month]. [hour]:[minute]"}}`
+///
+/// Without a format the locale's date and time are written, `format` takes the `time` crate's format description.
+/// Values that are not timestamps are written as they are.
+#[derive(Clone, Copy)]
+struct FormatDatetime(Conventions);
+
+impl HelperDef for FormatDatetime {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'rc>, RenderError> {
+        let value = helper
+            .param(0)
+            .and_then(|value| value.value().as_str())
+            .ok_or_else(|| RenderErrorReason::ParamNotFoundForIndex("format_datetime", 0))?;
+        let timezone = timezone_hash(helper)?;
+
+        let Ok(datetime) = OffsetDateTime::parse(value, &Rfc3339) else {
+            return Ok(ScopedJson::Derived(JsonValue::String(value.to_string())));
+        };
+        let datetime = timezone.map_or(datetime, |timezone| datetime.to_offset(timezone));
+
+        let formatted = match helper.hash_get("format").and_then(|format| format.value().as_str()) {
+            Some(format) => format_description::parse_borrowed::<2>(format)
+                .ok()
+                .and_then(|format| datetime.format(&format).ok())
+                .ok_or_else(|| RenderErrorReason::Other(format!("Invalid datetime format {format}")))?,
+            None => format!("{} {}", self.0.format_date(datetime.date()), self.0.format_time(datetime.time())),
+        };
+
+        Ok(ScopedJson::Derived(JsonValue::String(formatted)))
+    }
+}
+
+/// Registers `format_number`, `format_currency`, `format_date` and `format_datetime`, replacing the ones registered with
+/// other conventions.
+pub fn register_helpers(handlebars: &mut Handlebars<'static>, conventions: Conventions) {
+    handlebars.register_helper("format_number", Box::new(FormatNumber(conventions)));
+    handlebars.register_helper("format_currency", Box::new(FormatCurrency(conventions)));
+    handlebars.register_helper("format_date", Box::new(FormatDate(conventions)));
+    handlebars.register_helper("format_datetime", Box::new(FormatDatetime(conventions)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_fallback_chain() {
+        assert_eq!(fallback_chain(Some("de_AT"), Some("en-US")), vec!["de-at", "de", "en-us", "en"]);
+        assert_eq!(fallback_chain(Some("en-GB"), Some("en")), vec!["en-gb", "en"]);
+        assert_eq!(fallback_chain(None, Some("fr")), vec!["fr"]);
+        assert!(fallback_chain(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(Conventions::of(Some("en-US")).format_number(1_234_567.891, 2), "1,234,567.89");
+        assert_eq!(Conventions::of(Some("de-AT")).format_number(1234.5, 2), "1.234,50");
+        assert_eq!(Conventions::of(None).format_number(-999.0, 0), "-999");
+        assert_eq!(Conventions::of(None).format_number(-0.001, 2), "0.00");
+    }
+
+    #[test]
+    fn test_format_currency() {
+        assert_eq!(Conventions::of(Some("en")).format_currency(1234.5, "usd"), "$1,234.50");
+        assert_eq!(Conventions::of(Some("de")).format_currency(1234.5, "EUR"), "1.234,50 €");
+        assert_eq!(Conventions::of(Some("en-GB")).format_currency(1234.0, "CHF"), "CHF 1,234.00");
+        assert_eq!(Conventions::of(Some("ja")).format_currency(1500.0, "JPY"), "¥1,500");
+    }
+
+    #[test]
+    fn test_format_date() {
+        let date = parse_date("2024-01-05T23:30:00-05:00", None).unwrap();
+
+        assert_eq!(Conventions::of(Some("en-US")).format_date(date), "01/05/2024");
+        assert_eq!(Conventions::of(Some("en-GB")).format_date(date), "05/01/2024");
+        assert_eq!(Conventions::of(Some("de")).format_date(date), "05.01.2024");
+        assert_eq!(
+            Conventions::of(Some("sv")).format_date(parse_date("2024-01-05", None).unwrap()),
+            "2024-01-05"
+        );
+    }
+
+    #[test]
+    fn test_format_time() {
+        let time = Time::from_hms(0, 5, 0).unwrap();
+
+        assert_eq!(Conventions::of(Some("en-US")).format_time(time), "12:05 AM");
+        assert_eq!(Conventions::of(Some("en-US")).format_time(Time::from_hms(14, 5, 0).unwrap()), "2:05 PM");
+        assert_eq!(Conventions::of(Some("de")).format_time(time), "00:05");
+    }
+
+    #[test]
+    fn test_helpers() {
+        let mut handlebars = Handlebars::new();
+        register_helpers(&mut handlebars, Conventions::of(Some("de-DE")));
+
+        let rendered = handlebars
+            .render_template(
+                "{{format_date created_at}} {{format_number quantity}} {{format_currency total_price currency}} {{format_date note}}",
+                &json!({ "created_at": "2024-01-05T10:00:00+01:00", "quantity": 1200, "total_price": "99.9", "currency": "EUR", "note": "soon" }),
+            )
+            .unwrap();
+
+        assert_eq!(rendered, "05.01.2024 1.200 99,90 € soon");
+    }
+}
@@ -1,5 +1,12 @@
 pub mod database;
 pub mod document;
 pub mod email;
+pub mod email_api;
+pub mod helpers;
+pub mod locale;
+pub mod outbox;
 pub mod queries;
+pub mod retention;
+pub mod shop;
 pub mod template;
+pub mod transport;
@@ -0,0 +1,244 @@
+use crate::error::types::QueryError;
+use crate::services::{
+    database::Pool,
+    email::Delivery,
+    email::MailerError,
+    email::MailerTrait,
+    queries::{dead_letter, delivery_attempt, event, outbox},
+    shop::Shops,
+};
+use crate::utils::{shopify::webhook_event::WebhookEvent, Email};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+// How long a claimed notification is held for the worker sending it, well above the default SMTP and API timeouts
+const LEASE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Error, Debug)]
+pub enum OutboxError {
+    #[error("Failed to get client from pool")]
+    FailedToGetClient,
+
+    #[error("Outbox transaction failed")]
+    Transaction,
+
+    #[error(transparent)]
+    Query(#[from] QueryError),
+
+    #[error("Failed to serialize email")]
+    Serialize,
+
+    #[error("Failed to deserialize email")]
+    Deserialize,
+
+    #[error("Shop {0} is not configured")]
+    UnknownShop(String),
+
+    #[error(transparent)]
+    Mailer(#[from] MailerError),
+}
+
+impl OutboxError {
+    /// Checks if delivering the notification again later may succeed.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Mailer(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+/// How often and how far apart a notification is retried before it is moved to the dead letters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: i32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            base_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Gets the delay before the next attempt, doubling with every failed attempt up to `max_delay`.
+    #[must_use]
+    pub fn delay(&self, failed_attempts: i32) -> Duration {
+        let exponent = u32::try_from(failed_attempts.saturating_sub(1)).unwrap_or(0);
+
+        self.base_delay.saturating_mul(2u32.saturating_pow(exponent)).min(self.max_delay)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait OutboxTrait {
+    /// Marks the claimed event as done and queues its notification in a single transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns `OutboxError` if the event is no longer claimed or the notification cannot be persisted,
+    /// in which case neither is.
+    async fn enqueue(&self, event: &WebhookEvent, email: Email) -> Result<(), OutboxError>;
+}
+
+/// Postgres backed outbox, drained by the workers started with `spawn_workers`.
+#[derive(Clone)]
+pub struct Outbox {
+    db_client: Pool,
+}
+
+impl Outbox {
+    #[must_use]
+    pub fn new(db_client: Pool) -> Self {
+        Self { db_client }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutboxTrait for Outbox {
+    async fn enqueue(&self, event: &WebhookEvent, email: Email) -> Result<(), OutboxError> {
+        let email = serde_json::to_value(email).map_err(|_| OutboxError::Serialize)?;
+
+        let mut client = self.db_client.get_client().await.map_err(|_| OutboxError::FailedToGetClient)?;
+        let transaction = client.transaction().await.map_err(|_| OutboxError::Transaction)?;
+
+        event::complete(&transaction, &event.event_id, event.locked_until).await?;
+        outbox::create(&transaction, &event.event_id, &event.shop_domain, &email).await?;
+
+        transaction.commit().await.map_err(|_| OutboxError::Transaction)
+    }
+}
+
+/// Starts the workers sending the queued notifications with the mailer of the shop they belong to.
+#[must_use]
+pub fn spawn_workers<T>(db_client: &Pool, shops: &Shops<T>, workers: usize, poll_interval: Duration, retry_policy: RetryPolicy) -> Vec<JoinHandle<()>>
+where
+    T: MailerTrait + Clone + Send + Sync + 'static,
+{
+    (0..workers)
+        .map(|_| {
+            let db_client = db_client.clone();
+            let shops = shops.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    match process_next(&db_client, &shops, &retry_policy).await {
+                        Ok(true) => {}
+                        Ok(false) => tokio::time::sleep(poll_interval).await,
+                        Err(e) => {
+                            println!("Error processing outbox: {e}");
+                            tokio::time::sleep(poll_interval).await;
+                        }
+                    }
+                }
+            })
+        })
+        .collect()
+}
+
+/// Sends the next due notification and records the attempt.
+///
+/// The notification is claimed with a lease and sent without holding a transaction or database connection, so a
+/// slow relay does not keep a row locked or a connection busy. The attempt is then recorded in a short transaction,
+/// which fails if the lease ran out meanwhile.
+///
+/// A notification that fails with a transient error is retried later with an exponential backoff, one that fails
+/// permanently or runs out of attempts is moved to the dead letters.
+///
+/// Returns `false` if there was nothing to send.
+///
+/// # Errors
+///
+/// Returns `OutboxError` if the outbox cannot be read or updated.
+pub async fn process_next<T: MailerTrait>(db_client: &Pool, shops: &Shops<T>, retry_policy: &RetryPolicy) -> Result<bool, OutboxError> {
+    let client = db_client.get_client().await.map_err(|_| OutboxError::FailedToGetClient)?;
+    let Some(row) = outbox::claim_next(&client, LEASE.as_secs_f64()).await? else {
+        return Ok(false);
+    };
+    drop(client);
+    let id: i64 = row.get("id");
+    let shop_domain: &str = row.get("shop_domain");
+    let leased_until: SystemTime = row.get("available_at");
+    let failed_attempts = row.get::<_, i32>("attempts") + 1;
+
+    let result = deliver(shops, shop_domain, row.get("email")).await;
+
+    let mut client = db_client.get_client().await.map_err(|_| OutboxError::FailedToGetClient)?;
+    let transaction = client.transaction().await.map_err(|_| OutboxError::Transaction)?;
+    match result {
+        Ok(delivery) => {
+            delivery_attempt::create(&transaction, id, delivery.relay.as_deref(), None).await?;
+            outbox::mark_sent(&transaction, id, leased_until).await?;
+        }
+        Err(e) => {
+            let error = e.to_string();
+            delivery_attempt::create(&transaction, id, None, Some(&error)).await?;
+
+            if e.is_transient() && failed_attempts < retry_policy.max_attempts {
+                println!("Error sending notification {id}, attempt {failed_attempts}: {error}");
+                outbox::defer(&transaction, id, leased_until, retry_policy.delay(failed_attempts).as_secs_f64(), &error).await?;
+            } else {
+                println!("Moving notification {id} to the dead letters after {failed_attempts} attempts: {error}");
+                dead_letter::create(&transaction, id, leased_until, &error).await?;
+            }
+        }
+    }
+
+    transaction.commit().await.map_err(|_| OutboxError::Transaction)?;
+
+    Ok(true)
+}
+
+async fn deliver<T: MailerTrait>(shops: &Shops<T>, shop_domain: &str, email: serde_json::Value) -> Result<Delivery, OutboxError> {
+    let shop = shops.get(shop_domain).ok_or_else(|| OutboxError::UnknownShop(shop_domain.to_string()))?;
+    let email: Email = serde_json::from_value(email).map_err(|_| OutboxError::Deserialize)?;
+
+    let mail = shop.mailer.create_mail(email)?;
+
+    Ok(shop.mailer.send_mail(mail).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay_doubles() {
+        let retry_policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(60 * 60),
+        };
+
+        assert_eq!(retry_policy.delay(1), Duration::from_secs(30));
+        assert_eq!(retry_policy.delay(2), Duration::from_secs(60));
+        assert_eq!(retry_policy.delay(4), Duration::from_secs(240));
+    }
+
+    #[test]
+    fn test_retry_delay_is_capped() {
+        let retry_policy = RetryPolicy {
+            max_attempts: 100,
+            base_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(60 * 60),
+        };
+
+        assert_eq!(retry_policy.delay(8), Duration::from_secs(60 * 60));
+        assert_eq!(retry_policy.delay(100), Duration::from_secs(60 * 60));
+    }
+
+    #[test]
+    fn test_only_transient_mailer_errors_are_retried() {
+        assert!(OutboxError::Mailer(MailerError::SmtpTransientError("421 Try again later".to_string())).is_transient());
+        assert!(!OutboxError::Mailer(MailerError::SmtpPermanentError("550 No such user".to_string())).is_transient());
+        assert!(!OutboxError::UnknownShop("unknown.myshopify.com".to_string()).is_transient());
+    }
+}
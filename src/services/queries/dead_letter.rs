@@ -0,0 +1,74 @@
+use crate::error::types::QueryError;
+use deadpool_postgres::GenericClient;
+use std::time::SystemTime;
+use tokio_postgres::Row;
+
+/// Moves a claimed notification from the outbox to the dead letters, counting the attempt that failed.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("dead letter")` if the notification cannot be moved or the lease ending at
+/// `leased_until` ran out and it was claimed again.
+pub async fn create(client: &impl GenericClient, outbox_id: i64, leased_until: SystemTime, error: &str) -> Result<(), QueryError> {
+    let query = client
+        .prepare_cached(
+            "WITH moved AS (DELETE FROM outbox WHERE id = $1 AND sent_at IS NULL AND available_at = $2 RETURNING *)
+            INSERT INTO dead_letters (outbox_id, event_id, shop_domain, email, created_at, attempts, error)
+            SELECT id, event_id, shop_domain, email, created_at, attempts + 1, $3 FROM moved",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let moved = client
+        .execute(&query, &[&outbox_id, &leased_until, &error])
+        .await
+        .map_err(|_| QueryError::Insert("dead letter"))?;
+    if moved == 0 {
+        return Err(QueryError::Insert("dead letter"));
+    }
+
+    Ok(())
+}
+
+/// Gets the most recent dead letters, without the email body.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("dead letters")` if the dead letters cannot be retrieved.
+pub async fn get_all(client: &impl GenericClient, limit: i64) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT outbox_id, event_id, shop_domain, email->>'to' AS recipient, email->>'subject' AS subject, attempts, error,
+                extract(epoch FROM created_at)::BIGINT AS created_at,
+                extract(epoch FROM dead_lettered_at)::BIGINT AS dead_lettered_at
+            FROM dead_letters
+            ORDER BY dead_lettered_at DESC
+            LIMIT $1",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query(&query, &[&limit]).await.map_err(|_| QueryError::Get("dead letters"))
+}
+
+/// Moves a dead letter back to the outbox to be sent right away, with its attempts reset.
+///
+/// Returns `false` if there is no dead letter with the outbox id.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("outbox")` if the notification cannot be moved back.
+pub async fn requeue(client: &impl GenericClient, outbox_id: i64) -> Result<bool, QueryError> {
+    let query = client
+        .prepare_cached(
+            "WITH moved AS (DELETE FROM dead_letters WHERE outbox_id = $1 RETURNING *)
+            INSERT INTO outbox (id, event_id, shop_domain, email, created_at)
+            SELECT outbox_id, event_id, shop_domain, email, created_at FROM moved",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let requeued = client.execute(&query, &[&outbox_id]).await.map_err(|_| QueryError::Insert("outbox"))?;
+
+    Ok(requeued > 0)
+}
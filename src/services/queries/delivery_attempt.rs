@@ -0,0 +1,51 @@
+use crate::error::types::QueryError;
+use deadpool_postgres::GenericClient;
+
+/// Records an attempt at sending a notification, `error` is `None` if the notification was sent.
+///
+/// `relay` is the SMTP relay that accepted the notification.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("delivery attempt")` if the attempt cannot be recorded.
+pub async fn create(client: &impl GenericClient, outbox_id: i64, relay: Option<&str>, error: Option<&str>) -> Result<(), QueryError> {
+    let query = client
+        .prepare_cached("INSERT INTO delivery_attempts (outbox_id, relay, error) VALUES ($1, $2, $3)")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .execute(&query, &[&outbox_id, &relay, &error])
+        .await
+        .map_err(|_| QueryError::Insert("delivery attempt"))?;
+
+    Ok(())
+}
+
+/// Deletes up to `limit` attempts made more than `older_than_secs` ago, except those of notifications that
+/// are still pending or dead lettered.
+///
+/// Returns the number of attempts deleted.
+///
+/// # Errors
+///
+/// Returns `QueryError::Delete("delivery attempts")` if the attempts cannot be deleted.
+pub async fn purge(client: &impl GenericClient, older_than_secs: f64, limit: i64) -> Result<u64, QueryError> {
+    let query = client
+        .prepare_cached(
+            "DELETE FROM delivery_attempts WHERE id IN (
+                SELECT a.id FROM delivery_attempts a
+                WHERE a.attempted_at < now() - make_interval(secs => $1)
+                    AND NOT EXISTS (SELECT 1 FROM outbox o WHERE o.id = a.outbox_id AND o.sent_at IS NULL)
+                    AND NOT EXISTS (SELECT 1 FROM dead_letters d WHERE d.outbox_id = a.outbox_id)
+                LIMIT $2
+            )",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .execute(&query, &[&older_than_secs, &limit])
+        .await
+        .map_err(|_| QueryError::Delete("delivery attempts"))
+}
@@ -0,0 +1,113 @@
+use crate::error::types::QueryError;
+use deadpool_postgres::{Client, GenericClient};
+use serde_json::Value;
+use tokio_postgres::Row;
+
+/// Gets the email settings that apply to a shop, the least specific first.
+///
+/// Shared settings come before the shop's own, and within those the settings for every template type
+/// (`template_type` is `NULL`) come before the ones of a single type. Passing `None` only returns the shared settings.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("email settings")` if the settings cannot be retrieved.
+pub async fn get_all(client: &Client, shop_id: Option<i32>) -> Result<Vec<Row>, QueryError> {
+    let query = "
+        SELECT tt.name AS template_type, es.cc, es.bcc, es.reply_to, es.headers
+        FROM email_settings es
+        LEFT JOIN template_types tt ON tt.id = es.template_type_id
+        WHERE es.shop_id IS NULL OR es.shop_id = $1
+        ORDER BY es.shop_id NULLS FIRST, es.template_type_id NULLS FIRST
+    ";
+    let rows = client.query(query, &[&shop_id]).await.map_err(|_| QueryError::Get("email settings"))?;
+
+    Ok(rows)
+}
+
+/// Gets every email setting, passing a shop only returns its own settings and the shared ones.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("email settings")` if the settings cannot be retrieved.
+pub async fn list(client: &impl GenericClient, shop_id: Option<i32>) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT es.id, es.shop_id, tt.name AS template_type, es.cc, es.bcc, es.reply_to, es.headers
+            FROM email_settings es
+            LEFT JOIN template_types tt ON tt.id = es.template_type_id
+            WHERE $1::INTEGER IS NULL OR es.shop_id IS NULL OR es.shop_id = $1
+            ORDER BY es.id",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query(&query, &[&shop_id]).await.map_err(|_| QueryError::Get("email settings"))
+}
+
+/// The settings stored by `upsert`, `None` fields are inherited from less specific settings.
+pub struct EmailSetting<'a> {
+    pub shop_id: Option<i32>,
+    pub template_type: Option<&'a str>,
+    pub cc: Option<&'a [String]>,
+    pub bcc: Option<&'a [String]>,
+    pub reply_to: Option<&'a str>,
+    pub headers: &'a Value,
+}
+
+/// Creates or replaces the email settings of a shop and template type.
+///
+/// Returns `None` if there is no template type with the name.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("email settings")` if the settings cannot be stored.
+pub async fn upsert(client: &impl GenericClient, setting: &EmailSetting<'_>) -> Result<Option<i32>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "INSERT INTO email_settings (shop_id, template_type_id, cc, bcc, reply_to, headers)
+            SELECT $1::INTEGER, tt.id, $3::TEXT[], $4::TEXT[], $5::VARCHAR, $6::JSONB
+            FROM (SELECT $2::VARCHAR AS name) requested
+            LEFT JOIN template_types tt ON tt.name = requested.name
+            WHERE requested.name IS NULL OR tt.id IS NOT NULL
+            ON CONFLICT (shop_id, template_type_id) DO UPDATE
+            SET cc = EXCLUDED.cc, bcc = EXCLUDED.bcc, reply_to = EXCLUDED.reply_to, headers = EXCLUDED.headers
+            RETURNING id",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let row = client
+        .query_opt(
+            &query,
+            &[
+                &setting.shop_id,
+                &setting.template_type,
+                &setting.cc,
+                &setting.bcc,
+                &setting.reply_to,
+                setting.headers,
+            ],
+        )
+        .await
+        .map_err(|_| QueryError::Insert("email settings"))?;
+
+    Ok(row.map(|row| row.get("id")))
+}
+
+/// Deletes email settings.
+///
+/// Returns `false` if there are no settings with the id.
+///
+/// # Errors
+///
+/// Returns `QueryError::Delete("email settings")` if the settings cannot be deleted.
+pub async fn delete(client: &impl GenericClient, id: i32) -> Result<bool, QueryError> {
+    let query = client
+        .prepare_cached("DELETE FROM email_settings WHERE id = $1")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let deleted = client.execute(&query, &[&id]).await.map_err(|_| QueryError::Delete("email settings"))?;
+
+    Ok(deleted > 0)
+}
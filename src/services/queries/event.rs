@@ -1,19 +1,122 @@
 use crate::error::types::QueryError;
-use deadpool_postgres::Client;
+use crate::utils::shopify::webhook_event::WebhookEvent;
+use deadpool_postgres::{Client, GenericClient};
+use serde_json::Value;
+use std::time::SystemTime;
 use tokio_postgres::Row;
 
-/// Creates an event.
+// Events with the state of the notification they queued, newest first
+const SELECT_EVENT_LOG: &str = "SELECT e.event_id, e.topic, e.webhook_id, e.shop_domain, e.api_version, e.status, e.error, e.payload,
+        extract(epoch FROM e.received_at)::BIGINT AS received_at,
+        extract(epoch FROM e.processed_at)::BIGINT AS processed_at,
+        CASE
+            WHEN o.sent_at IS NOT NULL THEN 'sent'
+            WHEN o.id IS NOT NULL THEN 'pending'
+            WHEN d.outbox_id IS NOT NULL THEN 'dead_lettered'
+        END AS delivery_status,
+        coalesce(o.attempts, d.attempts) AS delivery_attempts,
+        coalesce(o.last_error, d.error) AS delivery_error,
+        (SELECT a.relay FROM delivery_attempts a WHERE a.outbox_id = o.id AND a.error IS NULL ORDER BY a.id DESC LIMIT 1) AS delivery_relay
+    FROM events e
+    LEFT JOIN outbox o ON o.event_id = e.event_id
+    LEFT JOIN dead_letters d ON d.event_id = e.event_id";
+
+/// Claims an event for processing, storing the webhook it came with.
+///
+/// The event is only claimed if it is new, has failed before, or its lock has expired, so two requests for
+/// the same event can never both claim it. The claim is held for `lock_secs`.
+///
+/// Returns when the claim expires, which identifies it to `complete` and `fail`, or `None` if the event could not
+/// be claimed.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("event")` if the event cannot be claimed.
+pub async fn claim(
+    client: &impl GenericClient,
+    event: &WebhookEvent,
+    payload: Option<&Value>,
+    lock_secs: f64,
+) -> Result<Option<SystemTime>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "INSERT INTO events (event_id, webhook_secret_id, topic, webhook_id, shop_domain, api_version, payload, status, locked_until)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, 'processing', now() + make_interval(secs => $8))
+            ON CONFLICT (event_id) DO UPDATE
+            SET webhook_secret_id = EXCLUDED.webhook_secret_id, topic = EXCLUDED.topic, webhook_id = EXCLUDED.webhook_id,
+                shop_domain = EXCLUDED.shop_domain, api_version = EXCLUDED.api_version, payload = EXCLUDED.payload,
+                status = 'processing', error = NULL, processed_at = NULL, locked_until = EXCLUDED.locked_until
+            WHERE events.status = 'failed' OR (events.status = 'processing' AND events.locked_until < now())
+            RETURNING locked_until",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let claimed = client
+        .query_opt(
+            &query,
+            &[
+                &event.event_id,
+                &event.webhook_secret_id,
+                &event.topic,
+                &event.webhook_id,
+                &event.shop_domain,
+                &event.api_version,
+                &payload,
+                &lock_secs,
+            ],
+        )
+        .await
+        .map_err(|_| QueryError::Insert("event"))?;
+
+    Ok(claimed.map(|row| row.get("locked_until")))
+}
+
+/// Marks a claimed event as done, as long as the claim expiring at `locked_until` still holds it.
+///
+/// # Errors
+///
+/// Returns `QueryError::Update("event")` if the claim expired and the event was claimed again or released.
+pub async fn complete(client: &impl GenericClient, event_id: &str, locked_until: Option<SystemTime>) -> Result<(), QueryError> {
+    let query = client
+        .prepare_cached(
+            "UPDATE events SET status = 'done', processed_at = now(), locked_until = NULL
+            WHERE event_id = $1 AND status = 'processing' AND locked_until = $2",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let completed = client
+        .execute(&query, &[&event_id, &locked_until])
+        .await
+        .map_err(|_| QueryError::Update("event"))?;
+    if completed == 0 {
+        return Err(QueryError::Update("event"));
+    }
+
+    Ok(())
+}
+
+/// Marks a claimed event as failed with the reason, so Shopify's retry of it can claim it again.
+///
+/// A claim that expired leaves the event untouched, it may be processed by another request by now.
 ///
 /// # Errors
 ///
-/// Returns `QueryError::Insert("event")` if the event cannot be created.
-pub async fn create(client: &Client, event_id: &str) -> Result<(), QueryError> {
+/// Returns `QueryError::Update("event")` if the event cannot be updated.
+pub async fn fail(client: &impl GenericClient, event_id: &str, locked_until: Option<SystemTime>, error: &str) -> Result<(), QueryError> {
     let query = client
-        .prepare_cached("INSERT INTO events (event_id) VALUES ($1)")
+        .prepare_cached(
+            "UPDATE events SET status = 'failed', error = $3, processed_at = now(), locked_until = NULL
+            WHERE event_id = $1 AND status = 'processing' AND locked_until = $2",
+        )
         .await
         .map_err(|_| QueryError::PrepareStatement)?;
 
-    client.execute(&query, &[&event_id]).await.map_err(|_| QueryError::Insert("event"))?;
+    client
+        .execute(&query, &[&event_id, &locked_until, &error])
+        .await
+        .map_err(|_| QueryError::Update("event"))?;
 
     Ok(())
 }
@@ -33,3 +136,87 @@ pub async fn get(client: &Client, event_id: &str) -> Result<Row, QueryError> {
 
     Ok(row)
 }
+
+/// Gets the events for an order number, with the state of the notification they queued.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("events")` if the events cannot be retrieved.
+pub async fn get_by_order_number(client: &impl GenericClient, order_number: &str) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(&format!(
+            "{SELECT_EVENT_LOG} WHERE e.payload->>'order_number' = $1 ORDER BY e.received_at DESC"
+        ))
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query(&query, &[&order_number]).await.map_err(|_| QueryError::Get("events"))
+}
+
+/// Gets the events for a customer email, ignoring case, with the state of the notification they queued.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("events")` if the events cannot be retrieved.
+pub async fn get_by_customer_email(client: &impl GenericClient, email: &str) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(&format!(
+            "{SELECT_EVENT_LOG} WHERE lower(e.payload->'customer'->>'email') = lower($1) ORDER BY e.received_at DESC"
+        ))
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query(&query, &[&email]).await.map_err(|_| QueryError::Get("events"))
+}
+
+/// Deletes up to `limit` events with the status that were processed more than `older_than_secs` ago.
+///
+/// Returns the number of events deleted.
+///
+/// # Errors
+///
+/// Returns `QueryError::Delete("events")` if the events cannot be deleted.
+pub async fn purge(client: &impl GenericClient, status: &str, older_than_secs: f64, limit: i64) -> Result<u64, QueryError> {
+    let query = client
+        .prepare_cached(
+            "DELETE FROM events WHERE event_id IN (
+                SELECT event_id FROM events
+                WHERE status = $1 AND processed_at < now() - make_interval(secs => $2)
+                LIMIT $3
+            )",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .execute(&query, &[&status, &older_than_secs, &limit])
+        .await
+        .map_err(|_| QueryError::Delete("events"))
+}
+
+/// Strips the payload of up to `limit` events processed more than `older_than_secs` ago down to the order number,
+/// so customer details aren't kept longer than needed.
+///
+/// Returns the number of payloads redacted.
+///
+/// # Errors
+///
+/// Returns `QueryError::Update("events")` if the payloads cannot be redacted.
+pub async fn redact_payloads(client: &impl GenericClient, older_than_secs: f64, limit: i64) -> Result<u64, QueryError> {
+    let query = client
+        .prepare_cached(
+            "UPDATE events SET payload = jsonb_build_object('order_number', payload->'order_number'), payload_redacted_at = now()
+            WHERE event_id IN (
+                SELECT event_id FROM events
+                WHERE payload_redacted_at IS NULL AND payload IS NOT NULL AND processed_at < now() - make_interval(secs => $1)
+                LIMIT $2
+            )",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .execute(&query, &[&older_than_secs, &limit])
+        .await
+        .map_err(|_| QueryError::Update("events"))
+}
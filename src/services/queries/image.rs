@@ -0,0 +1,104 @@
+use crate::error::types::QueryError;
+use deadpool_postgres::{Client, GenericClient};
+use tokio_postgres::Row;
+
+/// Gets the images a shop's emails can embed.
+///
+/// Images belonging to the shop take precedence over the ones shared by all shops with the same name.
+/// Passing `None` only returns the shared images.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("images")` if the images cannot be retrieved.
+pub async fn get_all(client: &Client, shop_id: Option<i32>) -> Result<Vec<Row>, QueryError> {
+    let query = "
+        SELECT DISTINCT ON (name) name, content_type, content
+        FROM template_images
+        WHERE shop_id IS NULL OR shop_id = $1
+        ORDER BY name, shop_id NULLS LAST
+    ";
+    let rows = client.query(query, &[&shop_id]).await.map_err(|_| QueryError::Get("images"))?;
+
+    Ok(rows)
+}
+
+/// Gets every image without its content, passing a shop only returns its own images and the shared ones.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("images")` if the images cannot be retrieved.
+pub async fn list(client: &impl GenericClient, shop_id: Option<i32>) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT id, shop_id, name, content_type, octet_length(content) AS size FROM template_images
+            WHERE $1::INTEGER IS NULL OR shop_id IS NULL OR shop_id = $1
+            ORDER BY id",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query(&query, &[&shop_id]).await.map_err(|_| QueryError::Get("images"))
+}
+
+/// Gets an image with its content by id.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("image")` if the image cannot be retrieved.
+pub async fn get_by_id(client: &impl GenericClient, id: i32) -> Result<Option<Row>, QueryError> {
+    let query = client
+        .prepare_cached("SELECT id, shop_id, name, content_type, content FROM template_images WHERE id = $1")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query_opt(&query, &[&id]).await.map_err(|_| QueryError::Get("image"))
+}
+
+/// Stores an image, `None` makes it available to all shops.
+///
+/// Returns `None` if the shop already has an image with the name.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("image")` if the image cannot be stored.
+pub async fn create(
+    client: &impl GenericClient,
+    shop_id: Option<i32>,
+    name: &str,
+    content_type: &str,
+    content: &[u8],
+) -> Result<Option<i32>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "INSERT INTO template_images (shop_id, name, content_type, content) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (shop_id, name) DO NOTHING
+            RETURNING id",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let row = client
+        .query_opt(&query, &[&shop_id, &name, &content_type, &content])
+        .await
+        .map_err(|_| QueryError::Insert("image"))?;
+
+    Ok(row.map(|row| row.get("id")))
+}
+
+/// Deletes an image.
+///
+/// Returns `false` if there is no image with the id.
+///
+/// # Errors
+///
+/// Returns `QueryError::Delete("image")` if the image cannot be deleted.
+pub async fn delete(client: &impl GenericClient, id: i32) -> Result<bool, QueryError> {
+    let query = client
+        .prepare_cached("DELETE FROM template_images WHERE id = $1")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let deleted = client.execute(&query, &[&id]).await.map_err(|_| QueryError::Delete("image"))?;
+
+    Ok(deleted > 0)
+}
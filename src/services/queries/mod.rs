@@ -1,3 +1,10 @@
+pub mod dead_letter;
+pub mod delivery_attempt;
+pub mod email_setting;
 pub mod event;
+pub mod image;
+pub mod outbox;
 pub mod partial;
+pub mod sample;
+pub mod shop;
 pub mod template;
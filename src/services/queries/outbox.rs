@@ -0,0 +1,159 @@
+use crate::error::types::QueryError;
+use deadpool_postgres::GenericClient;
+use serde_json::Value;
+use std::time::SystemTime;
+use tokio_postgres::Row;
+
+/// Adds a notification to the outbox.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("outbox")` if the notification cannot be added.
+pub async fn create(client: &impl GenericClient, event_id: &str, shop_domain: &str, email: &Value) -> Result<(), QueryError> {
+    let query = client
+        .prepare_cached("INSERT INTO outbox (event_id, shop_domain, email) VALUES ($1, $2, $3)")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .execute(&query, &[&event_id, &shop_domain, &email])
+        .await
+        .map_err(|_| QueryError::Insert("outbox"))?;
+
+    Ok(())
+}
+
+/// Claims the oldest notification that is due to be sent by moving it `lease_secs` into the future.
+///
+/// The notification is not handed to another worker until the lease runs out, so it can be sent without keeping a
+/// transaction open. Returns the notification with the `available_at` the lease ends at, which has to be passed
+/// back when recording the attempt.
+///
+/// # Errors
+///
+/// Returns `QueryError::Update("outbox")` if the outbox cannot be read or updated.
+pub async fn claim_next(client: &impl GenericClient, lease_secs: f64) -> Result<Option<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "UPDATE outbox SET available_at = now() + make_interval(secs => $1)
+            WHERE id = (
+                SELECT id FROM outbox
+                WHERE sent_at IS NULL AND available_at <= now()
+                ORDER BY id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, shop_domain, email, attempts, available_at",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query_opt(&query, &[&lease_secs]).await.map_err(|_| QueryError::Update("outbox"))
+}
+
+/// Marks a claimed notification as sent, counting the attempt that sent it.
+///
+/// # Errors
+///
+/// Returns `QueryError::Update("outbox")` if the notification cannot be updated or the lease ending at `leased_until`
+/// ran out and it was claimed again.
+pub async fn mark_sent(client: &impl GenericClient, id: i64, leased_until: SystemTime) -> Result<(), QueryError> {
+    let query = client
+        .prepare_cached(
+            "UPDATE outbox SET sent_at = now(), attempts = attempts + 1, last_error = NULL
+            WHERE id = $1 AND sent_at IS NULL AND available_at = $2",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let updated = client
+        .execute(&query, &[&id, &leased_until])
+        .await
+        .map_err(|_| QueryError::Update("outbox"))?;
+    if updated == 0 {
+        return Err(QueryError::Update("outbox"));
+    }
+
+    Ok(())
+}
+
+/// Postpones a claimed notification that could not be sent, counting the failed attempt.
+///
+/// The delay is randomized between half and the full `retry_in_secs`, so notifications that failed together
+/// are not all retried at the same moment.
+///
+/// # Errors
+///
+/// Returns `QueryError::Update("outbox")` if the notification cannot be updated or the lease ending at `leased_until`
+/// ran out and it was claimed again.
+pub async fn defer(client: &impl GenericClient, id: i64, leased_until: SystemTime, retry_in_secs: f64, error: &str) -> Result<(), QueryError> {
+    let query = client
+        .prepare_cached(
+            "UPDATE outbox
+            SET available_at = now() + make_interval(secs => $3 * (0.5 + random() / 2)), attempts = attempts + 1, last_error = $4
+            WHERE id = $1 AND sent_at IS NULL AND available_at = $2",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let updated = client
+        .execute(&query, &[&id, &leased_until, &retry_in_secs, &error])
+        .await
+        .map_err(|_| QueryError::Update("outbox"))?;
+    if updated == 0 {
+        return Err(QueryError::Update("outbox"));
+    }
+
+    Ok(())
+}
+
+/// Deletes up to `limit` notifications that were sent more than `older_than_secs` ago.
+///
+/// Returns the number of notifications deleted.
+///
+/// # Errors
+///
+/// Returns `QueryError::Delete("outbox")` if the notifications cannot be deleted.
+pub async fn purge_sent(client: &impl GenericClient, older_than_secs: f64, limit: i64) -> Result<u64, QueryError> {
+    let query = client
+        .prepare_cached(
+            "DELETE FROM outbox WHERE id IN (
+                SELECT id FROM outbox
+                WHERE sent_at < now() - make_interval(secs => $1)
+                LIMIT $2
+            )",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .execute(&query, &[&older_than_secs, &limit])
+        .await
+        .map_err(|_| QueryError::Delete("outbox"))
+}
+
+/// Strips the email of up to `limit` notifications sent more than `older_than_secs` ago down to the subject.
+///
+/// Returns the number of emails redacted.
+///
+/// # Errors
+///
+/// Returns `QueryError::Update("outbox")` if the emails cannot be redacted.
+pub async fn redact_sent(client: &impl GenericClient, older_than_secs: f64, limit: i64) -> Result<u64, QueryError> {
+    let query = client
+        .prepare_cached(
+            "UPDATE outbox SET email = jsonb_build_object('subject', email->'subject')
+            WHERE id IN (
+                SELECT id FROM outbox
+                WHERE sent_at < now() - make_interval(secs => $1) AND email ? 'to'
+                LIMIT $2
+            )",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .execute(&query, &[&older_than_secs, &limit])
+        .await
+        .map_err(|_| QueryError::Update("outbox"))
+}
@@ -1,15 +1,126 @@
 use crate::error::types::QueryError;
-use deadpool_postgres::Client;
+use deadpool_postgres::{Client, GenericClient};
 use tokio_postgres::Row;
 
-/// Gets all email template partials.
+/// Gets all email template partials for a shop.
+///
+/// There is one partial per name and locale, `locale` is `NULL` for the one used for any locale.
+/// Partials belonging to the shop take precedence over the ones shared by all shops.
+/// Passing `None` only returns the shared partials.
 ///
 /// # Errors
 ///
 /// Returns `QueryError::Get("partials")` if the partials cannot be retrieved.
-pub async fn get_all(client: &Client) -> Result<Vec<Row>, QueryError> {
-    let query = "SELECT * FROM template_partials";
-    let rows = client.query(query, &[]).await.map_err(|_| QueryError::Get("partials"))?;
+pub async fn get_all(client: &Client, shop_id: Option<i32>) -> Result<Vec<Row>, QueryError> {
+    let query = "
+        SELECT DISTINCT ON (name, locale) name, locale, content
+        FROM template_partials
+        WHERE shop_id IS NULL OR shop_id = $1
+        ORDER BY name, locale, shop_id NULLS LAST
+    ";
+    let rows = client.query(query, &[&shop_id]).await.map_err(|_| QueryError::Get("partials"))?;
 
     Ok(rows)
 }
+
+/// Gets every partial, passing a shop only returns its own partials and the shared ones.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("partials")` if the partials cannot be retrieved.
+pub async fn list(client: &impl GenericClient, shop_id: Option<i32>) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT id, shop_id, name, locale, content FROM template_partials
+            WHERE $1::INTEGER IS NULL OR shop_id IS NULL OR shop_id = $1
+            ORDER BY id",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query(&query, &[&shop_id]).await.map_err(|_| QueryError::Get("partials"))
+}
+
+/// Gets a partial by id.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("partial")` if the partial cannot be retrieved.
+pub async fn get_by_id(client: &impl GenericClient, id: i32) -> Result<Option<Row>, QueryError> {
+    let query = client
+        .prepare_cached("SELECT id, shop_id, name, locale, content FROM template_partials WHERE id = $1")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query_opt(&query, &[&id]).await.map_err(|_| QueryError::Get("partial"))
+}
+
+/// Creates a partial, `None` makes it available to all shops.
+///
+/// Returns `None` if the shop already has a partial with the name for the locale.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("partial")` if the partial cannot be created.
+pub async fn create(
+    client: &impl GenericClient,
+    shop_id: Option<i32>,
+    name: &str,
+    locale: Option<&str>,
+    content: &str,
+) -> Result<Option<i32>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "INSERT INTO template_partials (shop_id, name, locale, content) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (shop_id, name, locale) DO NOTHING
+            RETURNING id",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let row = client
+        .query_opt(&query, &[&shop_id, &name, &locale, &content])
+        .await
+        .map_err(|_| QueryError::Insert("partial"))?;
+
+    Ok(row.map(|row| row.get("id")))
+}
+
+/// Updates the content of a partial.
+///
+/// Returns `false` if there is no partial with the id.
+///
+/// # Errors
+///
+/// Returns `QueryError::Update("partial")` if the partial cannot be updated.
+pub async fn update(client: &impl GenericClient, id: i32, content: &str) -> Result<bool, QueryError> {
+    let query = client
+        .prepare_cached("UPDATE template_partials SET content = $2 WHERE id = $1")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let updated = client
+        .execute(&query, &[&id, &content])
+        .await
+        .map_err(|_| QueryError::Update("partial"))?;
+
+    Ok(updated > 0)
+}
+
+/// Deletes a partial.
+///
+/// Returns `false` if there is no partial with the id.
+///
+/// # Errors
+///
+/// Returns `QueryError::Delete("partial")` if the partial cannot be deleted.
+pub async fn delete(client: &impl GenericClient, id: i32) -> Result<bool, QueryError> {
+    let query = client
+        .prepare_cached("DELETE FROM template_partials WHERE id = $1")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let deleted = client.execute(&query, &[&id]).await.map_err(|_| QueryError::Delete("partial"))?;
+
+    Ok(deleted > 0)
+}
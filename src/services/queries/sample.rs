@@ -0,0 +1,68 @@
+use crate::error::types::QueryError;
+use deadpool_postgres::GenericClient;
+use tokio_postgres::Row;
+
+/// Gets the sample payloads stored for a template type.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("samples")` if the samples cannot be retrieved.
+pub async fn list(client: &impl GenericClient, template_type: &str) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT s.name, s.payload FROM template_samples s
+            INNER JOIN template_types tt ON tt.id = s.template_type_id
+            WHERE tt.name = $1
+            ORDER BY s.name",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query(&query, &[&template_type]).await.map_err(|_| QueryError::Get("samples"))
+}
+
+/// Gets a sample payload of a template type by name.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("sample")` if the sample cannot be retrieved.
+pub async fn get(client: &impl GenericClient, template_type: &str, name: &str) -> Result<Option<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT s.name, s.payload FROM template_samples s
+            INNER JOIN template_types tt ON tt.id = s.template_type_id
+            WHERE tt.name = $1 AND s.name = $2",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .query_opt(&query, &[&template_type, &name])
+        .await
+        .map_err(|_| QueryError::Get("sample"))
+}
+
+/// Creates or replaces a sample payload of a template type.
+///
+/// Returns `false` if there is no template type with the name.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("sample")` if the sample cannot be stored.
+pub async fn upsert(client: &impl GenericClient, template_type: &str, name: &str, payload: &serde_json::Value) -> Result<bool, QueryError> {
+    let query = client
+        .prepare_cached(
+            "INSERT INTO template_samples (template_type_id, name, payload)
+            SELECT tt.id, $2::VARCHAR, $3::JSONB FROM template_types tt WHERE tt.name = $1
+            ON CONFLICT (template_type_id, name) DO UPDATE SET payload = EXCLUDED.payload",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let stored = client
+        .execute(&query, &[&template_type, &name, payload])
+        .await
+        .map_err(|_| QueryError::Insert("sample"))?;
+
+    Ok(stored > 0)
+}
@@ -0,0 +1,20 @@
+use crate::error::types::QueryError;
+use deadpool_postgres::Client;
+use tokio_postgres::Row;
+
+/// Gets all shops.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("shops")` if the shops cannot be retrieved.
+pub async fn get_all(client: &Client) -> Result<Vec<Row>, QueryError> {
+    let query = "
+        SELECT id, domain, webhook_secret, api_version, origin_email, smtp_host, smtp_port, smtp_username, smtp_password, default_locale,
+            dkim_selector, dkim_domain, dkim_private_key, email_api_endpoint, email_api_key
+        FROM shops
+    ";
+
+    let rows = client.query(query, &[]).await.map_err(|_| QueryError::Get("shops"))?;
+
+    Ok(rows)
+}
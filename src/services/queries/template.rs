@@ -1,21 +1,27 @@
 use crate::error::types::QueryError;
-use deadpool_postgres::Client;
+use deadpool_postgres::{Client, GenericClient};
 use tokio_postgres::Row;
 
-/// Gets all email templates.
+/// Gets all active email templates for a shop.
+///
+/// There is one template per template type and locale it is activated for, `locale` is `NULL` for the one used for any locale.
+/// Templates activated for the shop take precedence over the ones shared by all shops.
+/// Passing `None` only returns the shared templates.
 ///
 /// # Errors
 ///
 /// Returns `QueryError::Get("templates")` if the templates cannot be retrieved.
-pub async fn get_all(db: &Client) -> Result<Vec<Row>, QueryError> {
+pub async fn get_all(db: &Client, shop_id: Option<i32>) -> Result<Vec<Row>, QueryError> {
     let query = "
-        SELECT et.content, tt.name 
+        SELECT DISTINCT ON (tt.name, at.locale) et.content, et.subject, et.preheader, et.text_content, tt.name, at.locale
         FROM templates et
         INNER JOIN active_templates at ON et.id = at.template_id
         INNER JOIN template_types tt ON at.template_type_id = tt.id
+        WHERE at.shop_id IS NULL OR at.shop_id = $1
+        ORDER BY tt.name, at.locale, at.shop_id NULLS LAST
     ";
 
-    let rows = db.query(query, &[]).await.map_err(|_| QueryError::Get("templates"))?;
+    let rows = db.query(query, &[&shop_id]).await.map_err(|_| QueryError::Get("templates"))?;
 
     Ok(rows)
 }
@@ -32,3 +38,209 @@ pub async fn get(client: &Client, name: &str) -> Result<Row, QueryError> {
 
     Ok(row)
 }
+
+/// Gets every template version, with the template types and shops it is active for.
+///
+/// Passing a shop only returns its own templates and the shared ones.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("templates")` if the templates cannot be retrieved.
+pub async fn list(client: &impl GenericClient, shop_id: Option<i32>) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT t.id, t.shop_id, t.name, t.version, t.subject, t.preheader, t.content, t.text_content, t.author, t.note,
+                extract(epoch FROM t.created_at)::BIGINT AS created_at,
+                coalesce(
+                    jsonb_agg(jsonb_build_object('template_type', tt.name, 'shop_id', at.shop_id, 'locale', at.locale)) FILTER (WHERE at.id IS NOT NULL),
+                    '[]'
+                ) AS active_for
+            FROM templates t
+            LEFT JOIN active_templates at ON at.template_id = t.id
+            LEFT JOIN template_types tt ON tt.id = at.template_type_id
+            WHERE $1::INTEGER IS NULL OR t.shop_id IS NULL OR t.shop_id = $1
+            GROUP BY t.id
+            ORDER BY t.id",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query(&query, &[&shop_id]).await.map_err(|_| QueryError::Get("templates"))
+}
+
+/// Gets a template version by id.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("template")` if the template cannot be retrieved.
+pub async fn get_by_id(client: &impl GenericClient, id: i32) -> Result<Option<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT id, shop_id, name, version, subject, preheader, content, text_content, author, note, extract(epoch FROM created_at)::BIGINT AS created_at
+            FROM templates WHERE id = $1",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client.query_opt(&query, &[&id]).await.map_err(|_| QueryError::Get("template"))
+}
+
+/// Gets every version of a template, the latest first.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("template versions")` if the versions cannot be retrieved.
+pub async fn get_versions(client: &impl GenericClient, shop_id: Option<i32>, name: &str) -> Result<Vec<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT id, shop_id, name, version, subject, preheader, content, text_content, author, note, extract(epoch FROM created_at)::BIGINT AS created_at
+            FROM templates
+            WHERE shop_id IS NOT DISTINCT FROM $1 AND name = $2
+            ORDER BY version DESC",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .query(&query, &[&shop_id, &name])
+        .await
+        .map_err(|_| QueryError::Get("template versions"))
+}
+
+/// A template version to create, `shop_id` `None` makes it available to all shops.
+#[derive(Debug)]
+pub struct NewTemplate<'a> {
+    pub shop_id: Option<i32>,
+    pub name: &'a str,
+    pub subject: Option<&'a str>,
+    pub preheader: Option<&'a str>,
+    pub content: &'a str,
+    pub text_content: Option<&'a str>,
+    pub author: &'a str,
+    pub note: Option<&'a str>,
+}
+
+/// Creates the next version of a template.
+///
+/// The first version of a name is `1`. Returns `None` if another version of the name was created at the same time
+/// and took the version number.
+///
+/// # Errors
+///
+/// Returns `QueryError::Insert("template")` if the template cannot be created.
+pub async fn create(client: &impl GenericClient, template: &NewTemplate<'_>) -> Result<Option<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "INSERT INTO templates (shop_id, name, version, subject, preheader, content, text_content, author, note)
+            SELECT $1::INTEGER, $2::VARCHAR, coalesce(max(version), 0) + 1, $3, $4, $5, $6, $7::VARCHAR, $8
+            FROM templates WHERE shop_id IS NOT DISTINCT FROM $1 AND name = $2
+            ON CONFLICT (shop_id, name, version) DO NOTHING
+            RETURNING id, shop_id, name, version, subject, preheader, content, text_content, author, note, extract(epoch FROM created_at)::BIGINT AS created_at",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .query_opt(
+            &query,
+            &[
+                &template.shop_id,
+                &template.name,
+                &template.subject,
+                &template.preheader,
+                &template.content,
+                &template.text_content,
+                &template.author,
+                &template.note,
+            ],
+        )
+        .await
+        .map_err(|_| QueryError::Insert("template"))
+}
+
+/// Deletes a template version that is not active for any template type.
+///
+/// Returns `false` if there is no such template, or it is still active.
+///
+/// # Errors
+///
+/// Returns `QueryError::Delete("template")` if the template cannot be deleted.
+pub async fn delete(client: &impl GenericClient, id: i32) -> Result<bool, QueryError> {
+    let query = client
+        .prepare_cached("DELETE FROM templates WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM active_templates WHERE template_id = $1)")
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let deleted = client.execute(&query, &[&id]).await.map_err(|_| QueryError::Delete("template"))?;
+
+    Ok(deleted > 0)
+}
+
+/// Makes a template version the active one for a template type, for a shop or for all shops with `None`.
+///
+/// The locale is stored as given, callers normalize it. `None` activates the template for any locale without its own.
+/// Returns `false` if there is no template type with the name.
+///
+/// # Errors
+///
+/// Returns `QueryError::Update("active template")` if the template cannot be activated.
+pub async fn activate(
+    client: &impl GenericClient,
+    id: i32,
+    template_type: &str,
+    shop_id: Option<i32>,
+    locale: Option<&str>,
+) -> Result<bool, QueryError> {
+    let query = client
+        .prepare_cached(
+            "INSERT INTO active_templates (shop_id, template_type_id, template_id, locale)
+            SELECT $3, tt.id, $1, $4 FROM template_types tt WHERE tt.name = $2
+            ON CONFLICT (shop_id, template_type_id, locale) DO UPDATE SET template_id = EXCLUDED.template_id",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    let activated = client
+        .execute(&query, &[&id, &template_type, &shop_id, &locale])
+        .await
+        .map_err(|_| QueryError::Update("active template"))?;
+
+    Ok(activated > 0)
+}
+
+/// Finds the version to roll a template type back to, among the versions of the template active for it.
+///
+/// Without a version, it is the one before the active version.
+/// Returns `None` if nothing is active for the template type, or there is no such version.
+///
+/// # Errors
+///
+/// Returns `QueryError::Get("template version")` if the version cannot be retrieved.
+pub async fn get_rollback_version(
+    client: &impl GenericClient,
+    template_type: &str,
+    shop_id: Option<i32>,
+    locale: Option<&str>,
+    version: Option<i32>,
+) -> Result<Option<Row>, QueryError> {
+    let query = client
+        .prepare_cached(
+            "SELECT p.id, p.shop_id, p.name, p.version, p.subject, p.preheader, p.content, p.text_content, p.author, p.note,
+                extract(epoch FROM p.created_at)::BIGINT AS created_at
+            FROM active_templates at
+            INNER JOIN template_types tt ON tt.id = at.template_type_id
+            INNER JOIN templates t ON t.id = at.template_id
+            INNER JOIN templates p ON p.shop_id IS NOT DISTINCT FROM t.shop_id AND p.name = t.name
+            WHERE tt.name = $1 AND at.shop_id IS NOT DISTINCT FROM $2 AND at.locale IS NOT DISTINCT FROM $4
+                AND (p.version = $3 OR $3::INTEGER IS NULL AND p.version < t.version)
+            ORDER BY p.version DESC
+            LIMIT 1",
+        )
+        .await
+        .map_err(|_| QueryError::PrepareStatement)?;
+
+    client
+        .query_opt(&query, &[&template_type, &shop_id, &version, &locale])
+        .await
+        .map_err(|_| QueryError::Get("template version"))
+}
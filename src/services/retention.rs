@@ -0,0 +1,219 @@
+use crate::error::types::QueryError;
+use crate::services::{
+    database::Pool,
+    queries::{delivery_attempt, event, outbox},
+};
+use serde::Serialize;
+use std::{
+    future::Future,
+    ops::AddAssign,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+// Rows are deleted in batches, so a large backlog doesn't hold locks for long
+const BATCH_SIZE: i64 = 1000;
+
+pub(crate) const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Shortest time between two runs, the task would otherwise keep the database busy purging
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum RetentionError {
+    #[error("Failed to get client from pool")]
+    FailedToGetClient,
+
+    #[error(transparent)]
+    Query(#[from] QueryError),
+}
+
+/// How long processed events and sent notifications are kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetentionPolicy {
+    /// Events that were processed, along with their sent notifications
+    pub keep_done: Duration,
+    /// Events that failed, kept longer to look into what went wrong
+    pub keep_failed: Duration,
+    /// Payloads and sent emails are stripped of customer details after this, `None` keeps them until purged
+    pub redact_after: Option<Duration>,
+    /// How often the retention task runs
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            keep_done: 30 * DAY,
+            keep_failed: 90 * DAY,
+            redact_after: None,
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Rows removed or redacted by the retention task.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct RetentionCounts {
+    pub events: u64,
+    pub failed_events: u64,
+    pub outbox: u64,
+    pub delivery_attempts: u64,
+    pub redacted_payloads: u64,
+    pub redacted_emails: u64,
+}
+
+impl AddAssign for RetentionCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.events += other.events;
+        self.failed_events += other.failed_events;
+        self.outbox += other.outbox;
+        self.delivery_attempts += other.delivery_attempts;
+        self.redacted_payloads += other.redacted_payloads;
+        self.redacted_emails += other.redacted_emails;
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RetentionSnapshot {
+    pub runs: u64,
+    pub failed_runs: u64,
+    /// Unix timestamp of the last successful run
+    pub last_run_at: Option<u64>,
+    pub last_run: RetentionCounts,
+    pub total: RetentionCounts,
+}
+
+/// Counters of the retention task since the service started, shared with the admin API.
+#[derive(Clone, Default)]
+pub struct RetentionMetrics {
+    snapshot: Arc<Mutex<RetentionSnapshot>>,
+}
+
+impl RetentionMetrics {
+    pub fn record(&self, counts: RetentionCounts) {
+        let mut snapshot = self.snapshot.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        snapshot.runs += 1;
+        snapshot.last_run_at = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|now| now.as_secs());
+        snapshot.last_run = counts;
+        snapshot.total += counts;
+    }
+
+    pub fn record_failure(&self) {
+        self.snapshot.lock().unwrap_or_else(std::sync::PoisonError::into_inner).failed_runs += 1;
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> RetentionSnapshot {
+        self.snapshot.lock().unwrap_or_else(std::sync::PoisonError::into_inner).clone()
+    }
+}
+
+/// Starts the task enforcing the retention policy every `policy.interval`, at most once a second.
+pub fn spawn(db_client: &Pool, policy: RetentionPolicy, metrics: RetentionMetrics) {
+    let db_client = db_client.clone();
+
+    tokio::spawn(async move {
+        // A zero interval would make tokio panic and stop the task for good
+        let mut interval = tokio::time::interval(policy.interval.max(MIN_INTERVAL));
+
+        loop {
+            interval.tick().await;
+
+            match run(&db_client, &policy).await {
+                Ok(counts) => {
+                    println!("Retention purged {counts:?}");
+                    metrics.record(counts);
+                }
+                Err(e) => {
+                    println!("Error enforcing retention: {e}");
+                    metrics.record_failure();
+                }
+            }
+        }
+    });
+}
+
+/// Deletes the events and sent notifications that are past retention, and redacts the ones past `redact_after`.
+///
+/// Events still being processed and notifications still pending or dead lettered are never touched.
+///
+/// # Errors
+///
+/// Returns `RetentionError::FailedToGetClient` if no database client can be retrieved.
+/// Returns `RetentionError::Query` if rows cannot be deleted or redacted.
+pub async fn run(db_client: &Pool, policy: &RetentionPolicy) -> Result<RetentionCounts, RetentionError> {
+    let client = db_client.get_client().await.map_err(|_| RetentionError::FailedToGetClient)?;
+    let keep_done = policy.keep_done.as_secs_f64();
+    let keep_failed = policy.keep_failed.as_secs_f64();
+
+    let mut counts = RetentionCounts {
+        events: in_batches(|| event::purge(&client, "done", keep_done, BATCH_SIZE)).await?,
+        failed_events: in_batches(|| event::purge(&client, "failed", keep_failed, BATCH_SIZE)).await?,
+        outbox: in_batches(|| outbox::purge_sent(&client, keep_done, BATCH_SIZE)).await?,
+        delivery_attempts: in_batches(|| delivery_attempt::purge(&client, keep_done, BATCH_SIZE)).await?,
+        ..RetentionCounts::default()
+    };
+
+    if let Some(redact_after) = policy.redact_after {
+        let redact_after = redact_after.as_secs_f64();
+        counts.redacted_payloads = in_batches(|| event::redact_payloads(&client, redact_after, BATCH_SIZE)).await?;
+        counts.redacted_emails = in_batches(|| outbox::redact_sent(&client, redact_after, BATCH_SIZE)).await?;
+    }
+
+    Ok(counts)
+}
+
+async fn in_batches<F, Fut>(mut batch: F) -> Result<u64, QueryError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<u64, QueryError>>,
+{
+    let mut total = 0;
+    loop {
+        let rows = batch().await?;
+        total += rows;
+
+        if rows < BATCH_SIZE.unsigned_abs() {
+            return Ok(total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_record() {
+        let metrics = RetentionMetrics::default();
+        let counts = RetentionCounts {
+            events: 3,
+            redacted_payloads: 2,
+            ..RetentionCounts::default()
+        };
+
+        metrics.record(counts);
+        metrics.record(RetentionCounts {
+            events: 1,
+            ..RetentionCounts::default()
+        });
+        metrics.record_failure();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.runs, 2);
+        assert_eq!(snapshot.failed_runs, 1);
+        assert!(snapshot.last_run_at.is_some());
+        assert_eq!(snapshot.last_run.events, 1);
+        assert_eq!(snapshot.total.events, 4);
+        assert_eq!(snapshot.total.redacted_payloads, 2);
+    }
+
+    #[test]
+    fn test_default_policy_keeps_failed_events_longer() {
+        let policy = RetentionPolicy::default();
+        assert!(policy.keep_failed > policy.keep_done);
+        assert_eq!(policy.redact_after, None);
+    }
+}
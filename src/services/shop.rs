@@ -0,0 +1,262 @@
+use crate::config::Config;
+use crate::error::types::QueryError;
+use crate::services::{
+    database::Pool,
+    email::{DkimSigner, MailerError, MailerTrait},
+    email_api::{HttpTransport, JsonProvider},
+    queries::shop,
+    template::{Manager, ManagerError, Managers},
+    transport::Transport,
+};
+use crate::utils::shopify::webhook_secrets::WebhookSecrets;
+use lettre::message::Mailbox;
+use rustc_hash::FxHashMap;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ShopError {
+    #[error("Failed to get client from pool")]
+    FailedToGetClient,
+
+    #[error(transparent)]
+    Query(#[from] QueryError),
+
+    #[error(transparent)]
+    Template(#[from] ManagerError),
+
+    #[error("Invalid webhook secret for shop {0}")]
+    InvalidWebhookSecret(String),
+
+    #[error("Invalid SMTP port for shop {0}")]
+    InvalidSmtpPort(String),
+
+    #[error("Invalid DKIM configuration for shop {0}")]
+    InvalidDkimConfig(String),
+
+    #[error("Invalid SMTP configuration for shop {0}: {1}")]
+    InvalidSmtpConfig(String, MailerError),
+}
+
+/// A Shopify store the service sends notifications for.
+#[derive(Clone)]
+pub struct Shop<T> {
+    /// `None` for the shop from the configuration
+    pub id: Option<i32>,
+    pub domain: String,
+    pub api_version: String,
+    pub webhook_secrets: WebhookSecrets,
+    pub mailer: T,
+    pub template_manager: Manager,
+}
+
+/// All shops the service accepts webhooks from, keyed by `X-Shopify-Shop-Domain`.
+#[derive(Clone)]
+pub struct Shops<T> {
+    shops: Arc<FxHashMap<String, Shop<T>>>,
+}
+
+impl<T> Shops<T> {
+    #[must_use]
+    pub fn new(shops: Vec<Shop<T>>) -> Self {
+        Self {
+            shops: Arc::new(shops.into_iter().map(|shop| (shop.domain.clone(), shop)).collect()),
+        }
+    }
+
+    /// Gets a shop by its myshopify domain.
+    #[must_use]
+    pub fn get(&self, domain: &str) -> Option<&Shop<T>> {
+        self.shops.get(domain)
+    }
+
+    /// Gets the template managers of all shops, to reload them together.
+    #[must_use]
+    pub fn template_managers(&self) -> Managers {
+        Managers::new(self.shops.values().map(|shop| shop.template_manager.clone()).collect())
+    }
+}
+
+impl<T: MailerTrait> Shops<T> {
+    /// Loads the shops from the `shops` table, each with its own mailer and templates.
+    ///
+    /// The shop from the configuration is kept for single shop setups, unless a shop with the same domain exists in
+    /// the database. Shops with an email API endpoint send through the API instead of their SMTP server, and with an
+    /// email transport configured every shop hands its mails over to that transport. The TLS mode, pool and timeout of
+    /// the SMTP connections are shared by all shops.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ShopError::FailedToGetClient` if no database client can be retrieved.
+    /// Returns `ShopError::Query` if the shops cannot be retrieved.
+    /// Returns `ShopError::Template` if the templates or partials cannot be retrieved.
+    /// Returns `ShopError::InvalidWebhookSecret` if a shop has a malformed webhook secret.
+    /// Returns `ShopError::InvalidSmtpPort` if a shop has an SMTP port outside the valid range.
+    /// Returns `ShopError::InvalidDkimConfig` if a shop has an unreadable DKIM key or no domain to sign for.
+    /// Returns `ShopError::InvalidSmtpConfig` if a shop has an SMTP relay that cannot be parsed or set up.
+    pub async fn load(db_client: &Pool, config: &Config) -> Result<Self, ShopError> {
+        let client = db_client.get_client().await.map_err(|_| ShopError::FailedToGetClient)?;
+        let transport = config.email_transport.as_ref();
+        let mut shops = Vec::new();
+
+        if let Some(shop) = &config.shop {
+            let dkim = dkim_signer(
+                &shop.domain,
+                &shop.origin_email,
+                shop.dkim_selector.clone(),
+                shop.dkim_domain.clone(),
+                shop.dkim_private_key.clone(),
+            )?;
+            let api = api_transport(shop.email_api_endpoint.clone(), shop.email_api_key.clone());
+            let mailer = T::new(
+                shop.smtp_username.clone(),
+                shop.smtp_password.clone(),
+                &shop.smtp_host,
+                shop.origin_email.clone(),
+                shop.smtp_port,
+                &config.smtp_options,
+            )
+            .map_err(|e| ShopError::InvalidSmtpConfig(shop.domain.clone(), e))?;
+
+            shops.push(Shop {
+                id: None,
+                api_version: shop.api_version.clone(),
+                webhook_secrets: shop.webhook_secrets.clone(),
+                mailer: configure(mailer, dkim, transport.or(api.as_ref())),
+                template_manager: Manager::load(&client, None, shop.default_locale.clone()).await?,
+                domain: shop.domain.clone(),
+            });
+        }
+
+        for row in shop::get_all(&client).await? {
+            let id: i32 = row.get("id");
+            let domain: String = row.get("domain");
+            let webhook_secrets = WebhookSecrets::parse(row.get("webhook_secret")).map_err(|_| ShopError::InvalidWebhookSecret(domain.clone()))?;
+            let smtp_port = u16::try_from(row.get::<_, i32>("smtp_port")).map_err(|_| ShopError::InvalidSmtpPort(domain.clone()))?;
+            let origin_email: String = row.get("origin_email");
+            let dkim = dkim_signer(
+                &domain,
+                &origin_email,
+                row.get("dkim_selector"),
+                row.get("dkim_domain"),
+                row.get("dkim_private_key"),
+            )?;
+            let api = api_transport(row.get("email_api_endpoint"), row.get("email_api_key"));
+            let mailer = T::new(
+                row.get("smtp_username"),
+                row.get("smtp_password"),
+                row.get("smtp_host"),
+                origin_email,
+                smtp_port,
+                &config.smtp_options,
+            )
+            .map_err(|e| ShopError::InvalidSmtpConfig(domain.clone(), e))?;
+
+            // Shops from the database take precedence over the one from the configuration
+            shops.retain(|shop| shop.domain != domain);
+            println!("Shop registered and ready: {domain}");
+            shops.push(Shop {
+                id: Some(id),
+                api_version: row.get("api_version"),
+                webhook_secrets,
+                mailer: configure(mailer, dkim, transport.or(api.as_ref())),
+                template_manager: Manager::load(&client, Some(id), row.get("default_locale")).await?,
+                domain,
+            });
+        }
+
+        Ok(Self::new(shops))
+    }
+}
+
+// Signing is enabled by a selector and a private key, the domain defaults to the one mails are sent from
+fn dkim_signer(
+    shop: &str,
+    origin_email: &str,
+    selector: Option<String>,
+    domain: Option<String>,
+    private_key: Option<String>,
+) -> Result<Option<DkimSigner>, ShopError> {
+    let (Some(selector), Some(private_key)) = (selector.filter(|s| !s.is_empty()), private_key.filter(|key| !key.is_empty())) else {
+        return Ok(None);
+    };
+    let domain = domain
+        .filter(|domain| !domain.is_empty())
+        .or_else(|| origin_email.parse::<Mailbox>().ok().map(|mailbox| mailbox.email.domain().to_string()))
+        .ok_or_else(|| ShopError::InvalidDkimConfig(shop.to_string()))?;
+
+    DkimSigner::new(&selector, &domain, &private_key)
+        .map(Some)
+        .map_err(|_| ShopError::InvalidDkimConfig(shop.to_string()))
+}
+
+fn api_transport(endpoint: Option<String>, api_key: Option<String>) -> Option<Transport> {
+    let endpoint = endpoint.filter(|endpoint| !endpoint.is_empty())?;
+
+    Some(Transport::Http(HttpTransport::new(JsonProvider, endpoint, api_key.unwrap_or_default())))
+}
+
+fn configure<T: MailerTrait>(mut mailer: T, dkim: Option<DkimSigner>, transport: Option<&Transport>) -> T {
+    if let Some(signer) = dkim {
+        mailer = mailer.with_dkim(signer);
+    }
+    if let Some(transport) = transport {
+        mailer = mailer.with_transport(transport.clone());
+    }
+
+    mailer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlebars::Handlebars;
+
+    fn shop(domain: &str) -> Shop<()> {
+        Shop {
+            id: None,
+            domain: domain.to_string(),
+            api_version: "2024-10".to_string(),
+            webhook_secrets: WebhookSecrets::single("secret"),
+            mailer: (),
+            template_manager: Manager::new(Handlebars::new()),
+        }
+    }
+
+    #[test]
+    fn test_get_shop_by_domain() {
+        let shops = Shops::new(vec![shop("first.myshopify.com"), shop("second.myshopify.com")]);
+
+        assert_eq!(shops.get("second.myshopify.com").unwrap().domain, "second.myshopify.com");
+        assert!(shops.get("unknown.myshopify.com").is_none());
+    }
+
+    #[test]
+    fn test_dkim_signer() {
+        let key = || Some("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_string());
+        let signer = |selector: &str, domain: &str, origin_email: &str| {
+            dkim_signer(
+                "shop.myshopify.com",
+                origin_email,
+                Some(selector.to_string()),
+                Some(domain.to_string()),
+                key(),
+            )
+        };
+
+        // Empty environment variables leave signing disabled
+        assert!(signer("", "", "Shop <shop@example.com>").unwrap().is_none());
+        assert!(signer("mail", "", "Shop <shop@example.com>").unwrap().is_some());
+        assert!(signer("mail", "example.com", "invalid").unwrap().is_some());
+        assert!(matches!(signer("mail", "", "invalid"), Err(ShopError::InvalidDkimConfig(_))));
+
+        let invalid_key = dkim_signer(
+            "shop.myshopify.com",
+            "shop@example.com",
+            Some("mail".to_string()),
+            None,
+            Some("key".to_string()),
+        );
+        assert!(matches!(invalid_key, Err(ShopError::InvalidDkimConfig(shop)) if shop == "shop.myshopify.com"));
+    }
+}
@@ -1,19 +1,446 @@
-use handlebars::Handlebars;
+use crate::error::types::QueryError;
+use crate::services::{
+    database::Pool,
+    helpers,
+    locale::{self, Conventions},
+    queries::{email_setting, image, partial, template},
+};
+use crate::utils::{css, html, EmailAttachment};
+use deadpool_postgres::Client;
+use handlebars::{no_escape, Handlebars, Template, TemplateError};
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::Serialize;
+use similar::TextDiff;
+use std::{
+    collections::BTreeMap,
+    future::poll_fn,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
 use thiserror::Error;
+use tokio_postgres::{AsyncMessage, Row};
+
+/// Channel notified by the triggers on `templates`, `template_partials`, `template_images`, `email_settings` and `active_templates`
+pub const TEMPLATE_CHANGES_CHANNEL: &str = "template_changes";
+
+// How long to wait before listening again after the listening connection was lost
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Error)]
 pub enum ManagerError {
     #[error("Failed to get template")]
     FailedToGetTemplate,
 
+    #[error("Template {0} has no subject or it cannot be filled")]
+    FailedToGetSubject(String),
+
+    #[error("Header {0} cannot be filled")]
+    FailedToGetHeader(String),
+
     #[error("Error registering template")]
     TemplateRegistrationError,
+
+    #[error("Template {0} does not compile: {1}")]
+    InvalidTemplate(String, TemplateSyntaxError),
+
+    #[error(transparent)]
+    Query(#[from] QueryError),
+
+    #[error("Failed to get client from pool")]
+    FailedToGetClient,
+
+    #[error("Lost the connection listening for template changes")]
+    ListenerDisconnected,
+}
+
+/// Why a template or partial could not be parsed, with the position when Handlebars knows it.
+#[derive(Debug, Error, PartialEq, Serialize)]
+#[error("{message}")]
+pub struct TemplateSyntaxError {
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl From<&TemplateError> for TemplateSyntaxError {
+    fn from(e: &TemplateError) -> Self {
+        Self {
+            message: e.reason().to_string(),
+            line: e.pos().map(|(line, _)| line),
+            column: e.pos().map(|(_, column)| column),
+        }
+    }
+}
+
+/// Checks that a template or partial is valid Handlebars, without registering it.
+///
+/// # Errors
+///
+/// Returns `TemplateSyntaxError` with the parser error if the content cannot be compiled.
+pub fn validate(content: &str) -> Result<(), TemplateSyntaxError> {
+    Template::compile(content).map(|_| ()).map_err(|e| TemplateSyntaxError::from(&e))
+}
+
+/// Compares two versions of a template line by line, as a unified diff.
+#[must_use]
+pub fn diff(old: &str, new: &str, old_header: &str, new_header: &str) -> String {
+    TextDiff::from_lines(old, new).unified_diff().header(old_header, new_header).to_string()
+}
+
+/// A filled email template.
+#[derive(Debug, PartialEq)]
+pub struct FilledEmail {
+    pub subject: String,
+    /// The template, with the preheader hidden at the start of the body when the template has one
+    pub html_body: String,
+    /// The text template when there is one, otherwise derived from the HTML
+    pub text_body: String,
+    /// The stored images the HTML shows with `cid:name`, to send as inline attachments
+    pub images: Vec<EmailAttachment>,
+    pub cc: Vec<String>,
+    pub bcc: Vec<String>,
+    pub reply_to: Option<String>,
+    /// Filled with the same arguments as the template, headers that come out empty are left out
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Copies, Reply-To and headers of the emails of a template type, or of every type when it has none.
+#[derive(Debug, Clone, PartialEq)]
+struct EmailSettings {
+    template_type: Option<String>,
+    // `None` keeps what less specific settings have
+    cc: Option<Vec<String>>,
+    bcc: Option<Vec<String>>,
+    reply_to: Option<String>,
+    // Header names and the Handlebars filling their value
+    headers: Vec<(String, String)>,
+}
+
+impl EmailSettings {
+    fn from_row(row: &Row) -> Self {
+        let headers: serde_json::Value = row.get("headers");
+
+        Self {
+            template_type: row.get("template_type"),
+            cc: row.get("cc"),
+            bcc: row.get("bcc"),
+            reply_to: row.get("reply_to"),
+            headers: headers
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+                .collect(),
+        }
+    }
+
+    fn applies_to(&self, template_name: &str) -> bool {
+        self.template_type.as_deref().is_none_or(|template_type| template_type == template_name)
+    }
+
+    fn check(&self) -> Result<(), ManagerError> {
+        for (name, value) in &self.headers {
+            validate(value).map_err(|e| ManagerError::InvalidTemplate(format!("header {name}"), e))?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
-pub struct Manager {
+struct Registry {
     templates: Handlebars<'static>,
+    // Subjects and text bodies are not HTML, escaping would show entities such as &amp; in the inbox
+    plain: Handlebars<'static>,
+    // How the locale helpers write dates, numbers and amounts
+    conventions: Conventions,
+}
+
+impl Registry {
+    fn new(templates: Handlebars<'static>, conventions: Conventions) -> Self {
+        let mut plain = Handlebars::new();
+        plain.register_escape_fn(no_escape);
+
+        let mut templates = templates;
+        helpers::register(&mut templates);
+        helpers::register(&mut plain);
+
+        let mut registry = Self {
+            templates,
+            plain,
+            conventions,
+        };
+        registry.use_conventions(conventions);
+        registry
+    }
+
+    fn use_conventions(&mut self, conventions: Conventions) {
+        locale::register_helpers(&mut self.templates, conventions);
+        locale::register_helpers(&mut self.plain, conventions);
+        self.conventions = conventions;
+    }
+
+    // Registers the content of an active template, along with its subject, preheader and text version
+    fn register_template(&mut self, template: &Row) -> Result<(), TemplateError> {
+        let name: &str = template.get("name");
+        self.templates.register_template_string(name, template.get::<_, &str>("content"))?;
+
+        if let Some(subject) = template.get::<_, Option<&str>>("subject") {
+            self.plain.register_template_string(name, subject)?;
+        }
+        if let Some(text_content) = template.get::<_, Option<&str>>("text_content") {
+            self.plain.register_template_string(&text_name(name), text_content)?;
+        }
+        if let Some(preheader) = template.get::<_, Option<&str>>("preheader") {
+            self.templates.register_template_string(&preheader_name(name), preheader)?;
+        }
+
+        Ok(())
+    }
+
+    fn register_partial(&mut self, partial: &Row) -> Result<(), TemplateError> {
+        self.templates.register_partial(partial.get("name"), partial.get::<_, &str>("content"))
+    }
+}
+
+/// The registries of a shop, one per locale with templates or partials of its own.
+#[derive(Clone)]
+struct Registries {
+    // Only the templates and partials without a locale
+    fallback: Arc<Registry>,
+    // Keyed by normalized locale, each with the templates of the locales it falls back to underneath its own
+    localized: FxHashMap<String, Arc<Registry>>,
+    // Copies of the registries above rendering with the conventions of a more specific locale, keyed by the locale of
+    // the copied registry and the conventions, so there are only as many as there are different conventions
+    derived: FxHashMap<(Option<String>, Conventions), Arc<Registry>>,
+    // Images don't differ by locale, their content id is their name
+    images: Arc<Vec<EmailAttachment>>,
+    // The least specific first, so the ones applied later override them
+    settings: Arc<Vec<EmailSettings>>,
+}
+
+impl Registries {
+    fn new(fallback: Registry) -> Self {
+        Self {
+            fallback: Arc::new(fallback),
+            localized: FxHashMap::default(),
+            derived: FxHashMap::default(),
+            images: Arc::default(),
+            settings: Arc::default(),
+        }
+    }
+
+    // The images an email shows, the others are left out so they don't count towards the size limit
+    fn images_in(&self, html_body: &str) -> Vec<EmailAttachment> {
+        self.images
+            .iter()
+            .filter(|image| image.content_id.as_deref().is_some_and(|content_id| references(html_body, content_id)))
+            .cloned()
+            .collect()
+    }
+
+    // Picks the registry of the first locale in the chain that has one, along with that locale
+    fn select(&self, chain: &[String]) -> (Option<&String>, &Arc<Registry>) {
+        chain
+            .iter()
+            .find_map(|locale| self.localized.get_key_value(locale))
+            .map_or((None, &self.fallback), |(locale, registry)| (Some(locale), registry))
+    }
+}
+
+/// The templates, partials, images and email settings of a shop as stored, before the templates are compiled per locale.
+struct Sources {
+    templates: Vec<Row>,
+    partials: Vec<Row>,
+    images: Vec<Row>,
+    settings: Vec<EmailSettings>,
+}
+
+impl Sources {
+    async fn get(client: &Client, shop_id: Option<i32>) -> Result<Self, QueryError> {
+        Ok(Self {
+            templates: template::get_all(client, shop_id).await?,
+            partials: partial::get_all(client, shop_id).await?,
+            images: image::get_all(client, shop_id).await?,
+            settings: email_setting::get_all(client, shop_id)
+                .await?
+                .iter()
+                .map(EmailSettings::from_row)
+                .collect(),
+        })
+    }
+
+    // Leaves out what doesn't compile, so one broken template doesn't keep the others from being used
+    fn without_invalid(mut self) -> Self {
+        self.templates.retain(|template| {
+            let name: &str = template.get("name");
+            let valid = check_template(template).is_ok();
+            if valid {
+                println!("Template registered and ready: {name}{}", locale_suffix(template));
+            } else {
+                println!("Error registering template: {name}{}", locale_suffix(template));
+            }
+            valid
+        });
+
+        self.partials.retain(|partial| {
+            let name: &str = partial.get("name");
+            let valid = check_partial(partial).is_ok();
+            if valid {
+                println!("Partial template registered and ready: {name}{}", locale_suffix(partial));
+            } else {
+                println!("Error registering partial template: {name}{}", locale_suffix(partial));
+            }
+            valid
+        });
+
+        self.settings.retain(|settings| {
+            let valid = settings.check().is_ok();
+            if !valid {
+                println!(
+                    "Error registering email headers: {}",
+                    settings.template_type.as_deref().unwrap_or("every template type")
+                );
+            }
+            valid
+        });
+
+        self
+    }
+
+    fn check(&self) -> Result<(), ManagerError> {
+        self.templates.iter().try_for_each(check_template)?;
+        self.partials.iter().try_for_each(check_partial)?;
+        self.settings.iter().try_for_each(EmailSettings::check)
+    }
+
+    // Builds a registry per locale, starting from the templates without a locale and registering
+    // the ones of each locale in its fallback chain over them, the most specific last
+    fn compile(&self, default_locale: Option<&str>) -> Result<Registries, ManagerError> {
+        let mut registries = Registries::new(self.compile_locale(None, default_locale)?);
+        registries.images = Arc::new(self.images.iter().map(inline_image).collect());
+        registries.settings = Arc::new(self.settings.clone());
+
+        let locales: FxHashSet<String> = self
+            .templates
+            .iter()
+            .chain(&self.partials)
+            .filter_map(|row| row.get::<_, Option<&str>>("locale").map(locale::normalize))
+            .collect();
+
+        for locale in locales {
+            let registry = self.compile_locale(Some(&locale), default_locale)?;
+            registries.localized.insert(locale, Arc::new(registry));
+        }
+
+        Ok(registries)
+    }
+
+    fn compile_locale(&self, locale: Option<&str>, default_locale: Option<&str>) -> Result<Registry, ManagerError> {
+        let mut registry = Registry::new(Handlebars::new(), Conventions::of(locale.or(default_locale)));
+
+        // The fallback registry only gets the templates without a locale, the others are selected before it
+        let chain = locale
+            .map(|locale| locale::fallback_chain(Some(locale), default_locale))
+            .unwrap_or_default();
+        let layers = std::iter::once(None).chain(chain.iter().rev().map(|locale| Some(locale.as_str())));
+
+        for layer in layers {
+            let in_layer = |row: &&Row| row.get::<_, Option<&str>>("locale").map(locale::normalize).as_deref() == layer;
+
+            for template in self.templates.iter().filter(in_layer) {
+                registry.register_template(template).map_err(|e| invalid(template, &e))?;
+            }
+            for partial in self.partials.iter().filter(in_layer) {
+                registry.register_partial(partial).map_err(|e| invalid(partial, &e))?;
+            }
+        }
+
+        Ok(registry)
+    }
+}
+
+fn check_template(template: &Row) -> Result<(), ManagerError> {
+    for content in [
+        Some(template.get::<_, &str>("content")),
+        template.get("subject"),
+        template.get("preheader"),
+        template.get("text_content"),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        validate(content).map_err(|e| ManagerError::InvalidTemplate(template.get("name"), e))?;
+    }
+
+    Ok(())
+}
+
+fn check_partial(partial: &Row) -> Result<(), ManagerError> {
+    validate(partial.get("content")).map_err(|e| ManagerError::InvalidTemplate(partial.get("name"), e))
+}
+
+fn invalid(row: &Row, e: &TemplateError) -> ManagerError {
+    ManagerError::InvalidTemplate(row.get("name"), TemplateSyntaxError::from(e))
+}
+
+fn locale_suffix(row: &Row) -> String {
+    row.get::<_, Option<&str>>("locale")
+        .map(|locale| format!(" ({locale})"))
+        .unwrap_or_default()
+}
+
+fn inline_image(image: &Row) -> EmailAttachment {
+    let name: String = image.get("name");
+    EmailAttachment {
+        content_id: Some(name.clone()),
+        ..EmailAttachment::new(name, image.get::<_, String>("content_type"), image.get("content"))
+    }
+}
+
+// Whether the HTML has `cid:{content_id}`, and not merely the start of a longer id such as `cid:logo-dark` for `logo`
+fn references(html_body: &str, content_id: &str) -> bool {
+    let reference = format!("cid:{content_id}");
+    html_body.match_indices(&reference).any(|(start, _)| {
+        !html_body[start + reference.len()..].starts_with(|c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+    })
+}
+
+fn preheader_name(template_name: &str) -> String {
+    format!("{template_name}.preheader")
+}
+
+fn text_name(template_name: &str) -> String {
+    format!("{template_name}.text")
+}
+
+fn single_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Inbox previews show the first text of the body, so the preheader goes right after the opening body tag
+fn insert_preheader(html_body: &str, preheader: &str) -> String {
+    let hidden = format!(r#"<div style="display:none;max-height:0;overflow:hidden;mso-hide:all;">{preheader}</div>"#);
+    let position = html_body
+        .to_ascii_lowercase()
+        .find("<body")
+        .and_then(|body| html_body[body..].find('>').map(|end| body + end + 1))
+        .unwrap_or(0);
+
+    let mut html = String::with_capacity(html_body.len() + hidden.len());
+    html.push_str(&html_body[..position]);
+    html.push_str(&hidden);
+    html.push_str(&html_body[position..]);
+    html
+}
+
+#[derive(Clone)]
+pub struct Manager {
+    // Shared by every clone, so a reload is seen by all requests holding the manager
+    registries: Arc<RwLock<Arc<Registries>>>,
+    shop_id: Option<i32>,
+    default_locale: Option<String>,
+    // The locale templates are rendered in, set per request
+    locale: Option<String>,
 }
 
 impl Manager {
@@ -24,7 +451,46 @@ impl Manager {
     /// Panics if the templates cannot be registered.
     #[must_use]
     pub fn new(templates: Handlebars<'static>) -> Self {
-        Self { templates }
+        Self {
+            registries: Arc::new(RwLock::new(Arc::new(Registries::new(Registry::new(templates, Conventions::of(None)))))),
+            shop_id: None,
+            default_locale: None,
+            locale: None,
+        }
+    }
+
+    /// Creates a template manager with the templates, partials and images of a shop.
+    ///
+    /// Passing `None` only loads the ones shared by all shops.
+    /// Templates and partials that don't compile are left out, so one broken template doesn't keep the service from starting.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ManagerError::Query` if the templates, partials or images cannot be retrieved.
+    pub async fn load(client: &Client, shop_id: Option<i32>, default_locale: Option<String>) -> Result<Self, ManagerError> {
+        let registries = Sources::get(client, shop_id)
+            .await?
+            .without_invalid()
+            .compile(default_locale.as_deref())?;
+
+        Ok(Self {
+            registries: Arc::new(RwLock::new(Arc::new(registries))),
+            shop_id,
+            default_locale,
+            locale: None,
+        })
+    }
+
+    /// Copies the manager to render in a customer's locale, e.g. `de-AT`.
+    ///
+    /// Templates are looked up for the locale, then its language, then the shop's default locale and
+    /// finally among the ones without a locale. `None` renders in the shop's default locale.
+    #[must_use]
+    pub fn with_locale(&self, locale: Option<&str>) -> Self {
+        Self {
+            locale: locale.map(locale::normalize).filter(|locale| !locale.is_empty()),
+            ..self.clone()
+        }
     }
 
     /// Gets a filled template.
@@ -33,23 +499,311 @@ impl Manager {
     ///
     /// Returns `ManagerError::FailedToGetTemplate` if the template cannot be retrieved.
     pub fn get_template_filled<T: Serialize>(&self, template_name: &str, template_args: T) -> Result<String, ManagerError> {
-        match self.templates.render(template_name, &template_args) {
+        match self.registry().templates.render(template_name, &template_args) {
             Ok(rendered_template) => Ok(rendered_template),
             Err(_) => Err(ManagerError::FailedToGetTemplate),
         }
     }
 
-    /// Upserts a template.
+    /// Gets the subject, HTML and text body of an email template, filled with the same arguments.
+    ///
+    /// The rules of the HTML's style blocks are inlined into `style` attributes, and the stored images it
+    /// references by `cid:` come along to be sent inline.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ManagerError::FailedToGetSubject` if the template has no subject or it cannot be filled.
+    /// Returns `ManagerError::FailedToGetTemplate` if the template, its preheader or its text version cannot be filled.
+    /// Returns `ManagerError::FailedToGetHeader` if a header of the template type's email settings cannot be filled.
+    pub fn get_email_filled<T: Serialize>(&self, template_name: &str, template_args: T) -> Result<FilledEmail, ManagerError> {
+        let registry = self.registry();
+        let registries = self.registries();
+
+        let subject = registry
+            .plain
+            .render(template_name, &template_args)
+            .map_err(|_| ManagerError::FailedToGetSubject(template_name.to_string()))?;
+        let mut html_body = registry
+            .templates
+            .render(template_name, &template_args)
+            .map_err(|_| ManagerError::FailedToGetTemplate)?;
+
+        // Derived before the preheader is added, it is only meant for the inbox preview
+        let text_name = text_name(template_name);
+        let text_body = if registry.plain.has_template(&text_name) {
+            registry
+                .plain
+                .render(&text_name, &template_args)
+                .map_err(|_| ManagerError::FailedToGetTemplate)?
+        } else {
+            html::to_text(&html_body)
+        };
+        html_body = css::inline(&html_body);
+
+        let preheader_name = preheader_name(template_name);
+        if registry.templates.has_template(&preheader_name) {
+            let preheader = registry
+                .templates
+                .render(&preheader_name, &template_args)
+                .map_err(|_| ManagerError::FailedToGetTemplate)?;
+            html_body = insert_preheader(&html_body, &preheader);
+        }
+
+        let mut email = FilledEmail {
+            // A line break in a template would end the header
+            subject: single_line(&subject),
+            images: registries.images_in(&html_body),
+            html_body,
+            text_body,
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            reply_to: None,
+            headers: BTreeMap::new(),
+        };
+
+        let mut headers = BTreeMap::new();
+        for settings in registries.settings.iter().filter(|settings| settings.applies_to(template_name)) {
+            if let Some(cc) = &settings.cc {
+                email.cc.clone_from(cc);
+            }
+            if let Some(bcc) = &settings.bcc {
+                email.bcc.clone_from(bcc);
+            }
+            if settings.reply_to.is_some() {
+                email.reply_to.clone_from(&settings.reply_to);
+            }
+            headers.extend(settings.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        }
+
+        for (name, value) in headers {
+            let value = registry
+                .plain
+                .render_template(value, &template_args)
+                .map_err(|_| ManagerError::FailedToGetHeader(name.to_string()))?;
+            let value = single_line(&value);
+            if !value.is_empty() {
+                email.headers.insert(name.to_string(), value);
+            }
+        }
+
+        Ok(email)
+    }
+
+    /// Copies the manager with registries of its own, so its templates can be changed without affecting the shop.
+    #[must_use]
+    pub fn draft(&self) -> Self {
+        Self {
+            registries: Arc::new(RwLock::new(self.registries())),
+            ..self.clone()
+        }
+    }
+
+    /// Checks if a template is registered.
+    #[must_use]
+    pub fn has_template(&self, template_name: &str) -> bool {
+        self.registry().templates.has_template(template_name)
+    }
+
+    /// Checks if a template has a subject, which documents such as invoices don't.
+    #[must_use]
+    pub fn has_subject(&self, template_name: &str) -> bool {
+        self.registry().plain.has_template(template_name)
+    }
+
+    /// Upserts a template, for every locale.
     ///
     /// # Errors
     ///
     /// Returns `ManagerError::TemplateRegistrationError` if the template cannot be registered.
-    #[allow(unused)]
-    pub fn upsert_template(&mut self, template_name: &str, template: &str) -> Result<(), ManagerError> {
-        match self.templates.register_template_string(template_name, template) {
-            Ok(()) => Ok(()),
-            Err(_) => Err(ManagerError::TemplateRegistrationError),
+    pub fn upsert_template(&self, template_name: &str, template: &str) -> Result<(), ManagerError> {
+        self.update(|registry| registry.templates.register_template_string(template_name, template))
+    }
+
+    /// Upserts the subject of a template, for every locale.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ManagerError::TemplateRegistrationError` if the subject cannot be registered.
+    pub fn upsert_subject(&self, template_name: &str, subject: &str) -> Result<(), ManagerError> {
+        self.update(|registry| registry.plain.register_template_string(template_name, subject))
+    }
+
+    /// Upserts the text version of a template, for every locale.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ManagerError::TemplateRegistrationError` if the text version cannot be registered.
+    pub fn upsert_text(&self, template_name: &str, text: &str) -> Result<(), ManagerError> {
+        self.update(|registry| registry.plain.register_template_string(&text_name(template_name), text))
+    }
+
+    /// Upserts a partial, for every locale.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ManagerError::TemplateRegistrationError` if the partial cannot be registered.
+    pub fn upsert_partial(&self, partial_name: &str, partial: &str) -> Result<(), ManagerError> {
+        self.update(|registry| registry.templates.register_partial(partial_name, partial))
+    }
+
+    fn registries(&self) -> Arc<Registries> {
+        self.registries.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    // The registry of the locale, rendering with the conventions of the requested locale even when its templates come
+    // from one it falls back to, e.g. British dates with templates in `en`
+    fn registry(&self) -> Arc<Registry> {
+        let registries = self.registries();
+        let chain = locale::fallback_chain(self.locale.as_deref(), self.default_locale.as_deref());
+        let (selected, registry) = registries.select(&chain);
+
+        let Some(requested) = chain.first() else {
+            return registry.clone();
+        };
+        let conventions = Conventions::of(Some(requested));
+        if registry.conventions == conventions {
+            return registry.clone();
+        }
+
+        let key = (selected.cloned(), conventions);
+        if let Some(derived) = registries.derived.get(&key) {
+            return derived.clone();
+        }
+
+        let mut derived = (**registry).clone();
+        derived.use_conventions(conventions);
+        let derived = Arc::new(derived);
+
+        // Kept for the next request with the same conventions, unless the registries were reloaded meanwhile
+        let mut current = self.registries.write().unwrap_or_else(PoisonError::into_inner);
+        if Arc::ptr_eq(&current, &registries) {
+            let mut updated = (**current).clone();
+            updated.derived.insert(key, derived.clone());
+            *current = Arc::new(updated);
+        }
+
+        derived
+    }
+
+    fn swap(&self, registries: Registries) {
+        *self.registries.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(registries);
+    }
+
+    // Changes copies of the registries, so requests rendering meanwhile keep consistent ones
+    fn update(&self, register: impl Fn(&mut Registry) -> Result<(), TemplateError>) -> Result<(), ManagerError> {
+        let mut registries = (*self.registries()).clone();
+
+        for registry in std::iter::once(&mut registries.fallback).chain(registries.localized.values_mut()) {
+            let mut updated = (**registry).clone();
+            register(&mut updated).map_err(|_| ManagerError::TemplateRegistrationError)?;
+            *registry = Arc::new(updated);
+        }
+        // Copied again from the updated registries when they are next needed
+        registries.derived.clear();
+        self.swap(registries);
+
+        Ok(())
+    }
+}
+
+/// The template managers of every shop, reloaded together since shops share templates and partials.
+#[derive(Clone, Default)]
+pub struct Managers {
+    managers: Arc<Vec<Manager>>,
+}
+
+impl Managers {
+    #[must_use]
+    pub fn new(managers: Vec<Manager>) -> Self {
+        Self {
+            managers: Arc::new(managers),
+        }
+    }
+
+    /// Gets the template manager of a shop, `None` for the shop configured through environment variables.
+    #[must_use]
+    pub fn get(&self, shop_id: Option<i32>) -> Option<&Manager> {
+        self.managers.iter().find(|manager| manager.shop_id == shop_id)
+    }
+
+    /// Reloads the templates, partials and images of every shop from the database.
+    ///
+    /// The new sets are only swapped in once all of them compile, otherwise every shop keeps its current templates.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ManagerError::Query` if the templates, partials or images cannot be retrieved.
+    /// Returns `ManagerError::InvalidTemplate` with the first template or partial that doesn't compile.
+    pub async fn reload(&self, client: &Client) -> Result<(), ManagerError> {
+        let mut registries = Vec::with_capacity(self.managers.len());
+        for manager in self.managers.iter() {
+            let sources = Sources::get(client, manager.shop_id).await?;
+            sources.check()?;
+            registries.push(sources.compile(manager.default_locale.as_deref())?);
+        }
+
+        for (manager, registries) in self.managers.iter().zip(registries) {
+            manager.swap(registries);
+        }
+
+        Ok(())
+    }
+}
+
+/// Starts the task reloading every shop's templates when they change in the database.
+pub fn spawn_reloader(db_client: &Pool, managers: Managers) {
+    let db_client = db_client.clone();
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen_for_changes(&db_client, &managers).await {
+                println!("Error listening for template changes: {e}");
+            }
+
+            tokio::time::sleep(RECONNECT_DELAY).await;
         }
+    });
+}
+
+async fn listen_for_changes(db_client: &Pool, managers: &Managers) -> Result<(), ManagerError> {
+    let (listener, mut connection) = db_client.connect().await.map_err(|_| ManagerError::ListenerDisconnected)?;
+    let (notify, mut notifications) = tokio::sync::mpsc::unbounded_channel();
+
+    // Notifications only arrive while the connection itself is polled
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = poll_fn(|cx| connection.poll_message(cx)).await {
+            if matches!(message, AsyncMessage::Notification(_)) && notify.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    listener
+        .batch_execute(&format!("LISTEN {TEMPLATE_CHANGES_CHANNEL}"))
+        .await
+        .map_err(|_| ManagerError::ListenerDisconnected)?;
+
+    // Changes made while nothing was listening would be missed otherwise
+    reload(db_client, managers).await;
+
+    while notifications.recv().await.is_some() {
+        // Saving a template and activating it notifies more than once, one reload covers them all
+        while notifications.try_recv().is_ok() {}
+        reload(db_client, managers).await;
+    }
+
+    Err(ManagerError::ListenerDisconnected)
+}
+
+async fn reload(db_client: &Pool, managers: &Managers) {
+    let result = match db_client.get_client().await {
+        Ok(client) => managers.reload(&client).await,
+        Err(_) => Err(ManagerError::FailedToGetClient),
+    };
+
+    match result {
+        Ok(()) => println!("Templates reloaded"),
+        Err(e) => println!("Error reloading templates, keeping the current ones: {e}"),
     }
 }
 
@@ -63,7 +817,7 @@ mod tests {
     fn test_manager_new() {
         let handlebars = Handlebars::new();
         let manager = Manager::new(handlebars);
-        assert!(manager.templates.get_template("non_existent").is_none());
+        assert!(manager.registry().templates.get_template("non_existent").is_none());
     }
 
     #[test]
@@ -88,16 +842,345 @@ mod tests {
 
     #[test]
     fn test_upsert_template_success() {
-        let mut manager = Manager::new(Handlebars::new());
+        let manager = Manager::new(Handlebars::new());
+        let request_manager = manager.clone();
         let result = manager.upsert_template("test_template", "Hello {{name}}!");
 
         assert!(result.is_ok());
-        assert!(manager.templates.has_template("test_template"));
+        assert!(request_manager.has_template("test_template"));
+    }
+
+    #[test]
+    fn test_get_email_filled() {
+        let mut handlebars = Handlebars::new();
+        handlebars
+            .register_template_string("test_template", "<html><body class=\"main\"><p>Hello {{name}}!</p></body></html>")
+            .unwrap();
+        handlebars
+            .register_template_string("test_template.preheader", "Order {{number}}")
+            .unwrap();
+        let manager = Manager::new(handlebars);
+        manager.upsert_subject("test_template", "#{{number}}:\n  Thanks {{name}}").unwrap();
+
+        let email = manager
+            .get_email_filled("test_template", json!({"name": "Tom & Jerry", "number": 1001}))
+            .unwrap();
+
+        assert_eq!(email.subject, "#1001: Thanks Tom & Jerry");
+        assert_eq!(
+            email.html_body,
+            "<html><body class=\"main\"><div style=\"display:none;max-height:0;overflow:hidden;mso-hide:all;\">Order 1001</div>\
+            <p>Hello Tom &amp; Jerry!</p></body></html>"
+        );
+        // Derived from the HTML, without the preheader
+        assert_eq!(email.text_body, "Hello Tom & Jerry!");
+    }
+
+    #[test]
+    fn test_get_email_filled_with_text_template() {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_template_string("test_template", "<p>Hello {{name}}!</p>").unwrap();
+        let manager = Manager::new(handlebars);
+        manager.upsert_subject("test_template", "Thanks").unwrap();
+        manager.upsert_text("test_template", "Hello {{name}},\n\nthanks!").unwrap();
+
+        let email = manager.get_email_filled("test_template", json!({"name": "Tom & Jerry"})).unwrap();
+
+        assert_eq!(email.text_body, "Hello Tom & Jerry,\n\nthanks!");
+    }
+
+    #[test]
+    fn test_get_email_filled_inlines_styles_and_images() {
+        let mut handlebars = Handlebars::new();
+        handlebars
+            .register_template_string(
+                "test_template",
+                "<style>p { color: #333 }</style><img src=\"cid:logo\" alt=\"Shop\"><p>Hello {{name}}!</p>",
+            )
+            .unwrap();
+        let manager = Manager::new(handlebars);
+        manager.upsert_subject("test_template", "Thanks").unwrap();
+
+        let image = |name: &str| EmailAttachment {
+            content_id: Some(name.to_string()),
+            ..EmailAttachment::new(name, "image/png", vec![137, 80, 78, 71])
+        };
+        manager.swap(Registries {
+            images: Arc::new(vec![image("logo"), image("logo-dark")]),
+            ..(*manager.registries()).clone()
+        });
+
+        let email = manager.get_email_filled("test_template", json!({"name": "World"})).unwrap();
+
+        assert_eq!(
+            email.html_body,
+            "<img src=\"cid:logo\" alt=\"Shop\"><p style=\"color: #333\">Hello World!</p>"
+        );
+        assert_eq!(email.text_body, "Shop\n\nHello World!");
+        assert_eq!(email.images, [image("logo")]);
+    }
+
+    #[test]
+    fn test_get_email_filled_with_settings() {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_template_string("order_fulfilled", "<p>Shipped</p>").unwrap();
+        handlebars.register_template_string("order_created", "<p>Thanks</p>").unwrap();
+        let manager = Manager::new(handlebars);
+        manager.upsert_subject("order_fulfilled", "Shipped").unwrap();
+        manager.upsert_subject("order_created", "Thanks").unwrap();
+
+        let settings = |template_type: Option<&str>, bcc: Option<&str>, reply_to: Option<&str>, headers: &[(&str, &str)]| EmailSettings {
+            template_type: template_type.map(str::to_string),
+            cc: None,
+            bcc: bcc.map(|bcc| vec![bcc.to_string()]),
+            reply_to: reply_to.map(str::to_string),
+            headers: headers.iter().map(|(name, value)| ((*name).to_string(), (*value).to_string())).collect(),
+        };
+        manager.swap(Registries {
+            settings: Arc::new(vec![
+                settings(
+                    None,
+                    None,
+                    Some("support@example.com"),
+                    &[("X-Order-Id", "{{order_number}}"), ("X-Note", "{{note}}")],
+                ),
+                settings(
+                    Some("order_fulfilled"),
+                    Some("archive@example.com"),
+                    None,
+                    &[("X-Order-Id", "#{{order_number}}")],
+                ),
+            ]),
+            ..(*manager.registries()).clone()
+        });
+        let args = json!({"order_number": 1001});
+
+        let email = manager.get_email_filled("order_fulfilled", &args).unwrap();
+        assert_eq!(email.bcc, ["archive@example.com"]);
+        assert_eq!(email.reply_to.as_deref(), Some("support@example.com"));
+        // Headers that come out empty are left out
+        assert_eq!(email.headers, BTreeMap::from([("X-Order-Id".to_string(), "#1001".to_string())]));
+
+        let email = manager.get_email_filled("order_created", &args).unwrap();
+        assert!(email.bcc.is_empty());
+        assert_eq!(email.headers["X-Order-Id"], "1001");
+    }
+
+    #[test]
+    fn test_references() {
+        assert!(references("<img src=\"cid:logo\">", "logo"));
+        assert!(references("background: url(cid:logo)", "logo"));
+        assert!(!references("<img src=\"cid:logo-dark\">", "logo"));
+        assert!(!references("<img src=\"logo.png\">", "logo"));
+    }
+
+    #[test]
+    fn test_get_email_filled_without_subject() {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_template_string("test_template", "Hello {{name}}!").unwrap();
+        let manager = Manager::new(handlebars);
+
+        let result = manager.get_email_filled("test_template", json!({"name": "World"}));
+        assert!(matches!(result, Err(ManagerError::FailedToGetSubject(_))));
+    }
+
+    #[test]
+    fn test_preheader_without_body_tag() {
+        assert_eq!(
+            insert_preheader("<p>Hi</p>", "Preview"),
+            "<div style=\"display:none;max-height:0;overflow:hidden;mso-hide:all;\">Preview</div><p>Hi</p>"
+        );
+    }
+
+    #[test]
+    fn test_draft_does_not_change_the_manager() {
+        let mut handlebars = Handlebars::new();
+        handlebars.register_template_string("test_template", "Hello {{name}}!").unwrap();
+        let manager = Manager::new(handlebars);
+
+        let draft = manager.draft();
+        draft.upsert_partial("greeting", "Hi").unwrap();
+        draft.upsert_template("test_template", "{{> greeting}} {{name}}!").unwrap();
+
+        assert_eq!(draft.get_template_filled("test_template", json!({"name": "World"})).unwrap(), "Hi World!");
+        assert_eq!(
+            manager.get_template_filled("test_template", json!({"name": "World"})).unwrap(),
+            "Hello World!"
+        );
+    }
+
+    #[test]
+    fn test_with_locale_formats_for_the_customer() {
+        let mut handlebars = Handlebars::new();
+        handlebars
+            .register_template_string("test_template", "{{format_currency total currency}} {{format_date created_at}}")
+            .unwrap();
+        let manager = Manager::new(handlebars);
+        let args = json!({"total": "1234.5", "currency": "EUR", "created_at": "2024-01-05T10:00:00+01:00"});
+
+        assert_eq!(
+            manager.with_locale(Some("de_AT")).get_template_filled("test_template", &args).unwrap(),
+            "1.234,50 € 05.01.2024"
+        );
+        assert_eq!(manager.get_template_filled("test_template", &args).unwrap(), "€1,234.50 01/05/2024");
+    }
+
+    #[test]
+    fn test_locales_with_the_same_conventions_share_a_registry() {
+        let mut handlebars = Handlebars::new();
+        handlebars
+            .register_template_string("test_template", "{{format_number total decimals=2}}")
+            .unwrap();
+        let manager = Manager::new(handlebars);
+        let args = json!({"total": 1234.5});
+
+        for locale in ["de", "de-AT", "de-CH", "en-GB", "en-AU", "en-NZ", "en-US", "xx-YY"] {
+            manager.with_locale(Some(locale)).get_template_filled("test_template", &args).unwrap();
+        }
+
+        // German and English outside the US, the others write numbers like the registry already does
+        assert_eq!(manager.registries().derived.len(), 2);
+        assert_eq!(
+            manager.with_locale(Some("de-LU")).get_template_filled("test_template", &args).unwrap(),
+            "1.234,50"
+        );
+        assert_eq!(manager.registries().derived.len(), 2);
+    }
+
+    fn render(template: &str, args: &serde_json::Value) -> Result<String, ManagerError> {
+        let manager = Manager::new(Handlebars::new());
+        manager.upsert_template("test_template", template)?;
+        manager.get_template_filled("test_template", args)
+    }
+
+    #[test]
+    fn test_format_currency_helper() {
+        let args = json!({"total_price": "19.9", "currency": "USD"});
+
+        assert_eq!(render("{{format_currency total_price currency}}", &args).unwrap(), "$19.90");
+        assert_eq!(
+            render("{{format_currency total_price currency with_code=true}}", &args).unwrap(),
+            "$19.90 USD"
+        );
+        assert!(render("{{format_currency note currency}}", &json!({"note": "soon", "currency": "USD"})).is_err());
+    }
+
+    #[test]
+    fn test_format_datetime_helper() {
+        let args = json!({"created_at": "2024-01-05T23:30:00-05:00"});
+
+        assert_eq!(render("{{format_datetime created_at}}", &args).unwrap(), "01/05/2024 11:30 PM");
+        assert_eq!(
+            render("{{format_datetime created_at timezone=\"UTC\"}}", &args).unwrap(),
+            "01/06/2024 4:30 AM"
+        );
+        assert_eq!(
+            render(
+                "{{format_datetime created_at timezone=\"+01:00\" format=\"[day].[month]. [hour]:[minute]\"}}",
+                &args
+            )
+            .unwrap(),
+            "06.01. 05:30"
+        );
+        assert_eq!(render("{{format_date created_at timezone=\"UTC\"}}", &args).unwrap(), "01/06/2024");
+        assert!(render("{{format_datetime created_at timezone=\"Europe/Berlin\"}}", &args).is_err());
+    }
+
+    #[test]
+    fn test_pluralize_helper() {
+        let template = "{{quantity}} {{pluralize quantity \"item\"}}, {{pluralize quantity \"box\" \"boxes\"}}";
+
+        assert_eq!(render(template, &json!({"quantity": 1})).unwrap(), "1 item, box");
+        assert_eq!(render(template, &json!({"quantity": 3})).unwrap(), "3 items, boxes");
+        assert_eq!(render(template, &json!({"quantity": "0"})).unwrap(), "0 items, boxes");
+    }
+
+    #[test]
+    fn test_default_helper() {
+        let template = "Hi {{default customer.first_name customer.email \"there\"}}";
+
+        assert_eq!(render(template, &json!({"customer": {"first_name": "Jane"}})).unwrap(), "Hi Jane");
+        assert_eq!(
+            render(template, &json!({"customer": {"first_name": " ", "email": "jane@example.com"}})).unwrap(),
+            "Hi jane@example.com"
+        );
+        assert_eq!(render(template, &json!({})).unwrap(), "Hi there");
+    }
+
+    #[test]
+    fn test_math_helpers() {
+        let args = json!({"price": "19.90", "quantity": 3, "discount": "5"});
+
+        assert_eq!(render("{{multiply price quantity}}", &args).unwrap(), "59.7");
+        assert_eq!(
+            render("{{format_currency (subtract (multiply price quantity) discount) \"EUR\"}}", &args).unwrap(),
+            "€54.70"
+        );
+        assert_eq!(render("{{add quantity 2}} {{divide quantity 2}}", &args).unwrap(), "5 1.5");
+        assert!(render("{{divide quantity 0}}", &args).is_err());
+    }
+
+    #[test]
+    fn test_format_address_helper() {
+        let args = json!({
+            "us": {"first_name": "Jane", "last_name": "Doe", "address1": "1 Main St", "city": "Springfield", "province_code": "IL",
+                "zip": "62701", "country": "United States", "country_code": "US"},
+            "de": {"name": "Max Müller", "company": "Müller & Söhne", "address1": "Hauptstraße 1", "city": "Berlin", "zip": "10115",
+                "country": "Germany", "country_code": "DE"}
+        });
+
+        assert_eq!(
+            render("{{format_address us}}", &args).unwrap(),
+            "Jane Doe<br>1 Main St<br>Springfield, IL 62701<br>United States"
+        );
+        assert_eq!(
+            render("{{format_address de separator=\", \"}}", &args).unwrap(),
+            "Max Müller, Müller &amp; Söhne, Hauptstraße 1, 10115 Berlin, Germany"
+        );
+    }
+
+    #[test]
+    fn test_url_helper() {
+        let args = json!({"shop_url": "https://shop.example.com/", "order_id": "10 01", "token": "a&b", "ref": null});
+
+        assert_eq!(
+            render("{{url shop_url \"orders\" order_id token=token ref=ref}}", &args).unwrap(),
+            "https://shop.example.com/orders/10%2001?token&#x3D;a%26b"
+        );
+        assert_eq!(
+            render("<a href=\"{{url shop_url q=token}}\">", &args).unwrap(),
+            "<a href=\"https://shop.example.com/?q&#x3D;a%26b\">"
+        );
+        assert!(render("{{url link}}", &json!({"link": "javascript:alert(1)"})).is_err());
+    }
+
+    #[test]
+    fn test_validate_success() {
+        assert!(validate("Hello {{customer.first_name}}! {{> signature}}").is_ok());
+    }
+
+    #[test]
+    fn test_validate_reports_position() {
+        let result = validate("<p>Hello</p>\n<p>{{#if customer}}{{customer.first_name}}</p>");
+        let error = result.unwrap_err();
+
+        assert_eq!(error.line, Some(2));
+        assert!(error.column.is_some());
+        assert!(!error.message.is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let diff = diff("<p>Hello</p>\n<p>Bye</p>\n", "<p>Hello</p>\n<p>See you</p>\n", "v1", "v2");
+
+        assert!(diff.starts_with("--- v1\n+++ v2\n"));
+        assert!(diff.contains("-<p>Bye</p>\n+<p>See you</p>\n"));
+        assert!(diff.contains(" <p>Hello</p>\n"));
     }
 
     #[test]
     fn test_upsert_template_error() {
-        let mut manager = Manager::new(Handlebars::new());
+        let manager = Manager::new(Handlebars::new());
         let result = manager.upsert_template("test_template", "{{#invalid}}");
 
         assert!(matches!(result, Err(ManagerError::TemplateRegistrationError)));
@@ -0,0 +1,535 @@
+use crate::services::{
+    email::{Delivery, MailerError},
+    email_api::HttpTransport,
+};
+use lettre::{
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Certificate, Tls, TlsParameters},
+        extension::ClientId,
+        PoolConfig,
+    },
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{
+    fs,
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("Unknown email transport {0}, expected smtp, file, stdout or memory")]
+    Unknown(String),
+
+    #[error("The file transport needs email_transport_dir")]
+    MissingDirectory,
+
+    #[error("Failed to create the email directory {0}")]
+    Directory(String),
+
+    #[error("Unknown SMTP TLS mode {0}, expected implicit, required, opportunistic or none")]
+    TlsMode(String),
+
+    #[error("Failed to set up TLS for {0}")]
+    Tls(String),
+}
+
+/// How the connections to the SMTP relays are secured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TlsMode {
+    /// TLS from the start of the connection, usually on port 465
+    #[default]
+    Implicit,
+    /// STARTTLS, relays that don't offer it are not sent to
+    Required,
+    /// STARTTLS if the relay offers it, plain text otherwise
+    Opportunistic,
+    /// Plain text, for local servers such as mailpit
+    None,
+}
+
+impl FromStr for TlsMode {
+    type Err = TransportError;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "implicit" => Ok(Self::Implicit),
+            "required" => Ok(Self::Required),
+            "opportunistic" => Ok(Self::Opportunistic),
+            "none" => Ok(Self::None),
+            _ => Err(TransportError::TlsMode(mode.to_string())),
+        }
+    }
+}
+
+/// Settings for the connections to every shop's SMTP relays.
+#[derive(Clone)]
+pub struct SmtpOptions {
+    pub tls: TlsMode,
+    /// Trusted besides the system's certificate authorities, for relays with a private CA
+    pub ca_certificate: Option<Certificate>,
+    /// Connections kept open to each relay while idle
+    pub pool_min_idle: u32,
+    /// Connections open at most to each relay
+    pub pool_max_size: u32,
+    pub timeout: Duration,
+    /// Sent with EHLO, the machine's hostname if `None`
+    pub hello_name: Option<String>,
+}
+
+impl Default for SmtpOptions {
+    fn default() -> Self {
+        Self {
+            tls: TlsMode::default(),
+            ca_certificate: None,
+            pool_min_idle: 0,
+            pool_max_size: 10,
+            timeout: Duration::from_secs(10),
+            hello_name: None,
+        }
+    }
+}
+
+impl SmtpOptions {
+    /// Creates the transport to a relay, authenticating only if `username` isn't empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TransportError::Tls` if the TLS connector cannot be set up.
+    pub fn transport(&self, host: &str, port: u16, username: &str, password: &str) -> Result<AsyncSmtpTransport<Tokio1Executor>, TransportError> {
+        // Dangerous only in that it starts without TLS, which is set right after
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            .port(port)
+            .tls(self.tls(host)?)
+            .timeout(Some(self.timeout))
+            .pool_config(PoolConfig::new().min_idle(self.pool_min_idle).max_size(self.pool_max_size));
+        if !username.is_empty() {
+            builder = builder.credentials(Credentials::new(username.to_string(), password.to_string()));
+        }
+        if let Some(hello_name) = &self.hello_name {
+            builder = builder.hello_name(ClientId::Domain(hello_name.clone()));
+        }
+
+        Ok(builder.build())
+    }
+
+    /// Sets up the TLS of the connections to a relay, which `transport` does as well, without starting a pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TransportError::Tls` if the TLS connector cannot be set up.
+    pub fn tls(&self, host: &str) -> Result<Tls, TransportError> {
+        let tls_parameters = || {
+            let mut builder = TlsParameters::builder(host.to_string());
+            if let Some(certificate) = &self.ca_certificate {
+                builder = builder.add_root_certificate(certificate.clone());
+            }
+            builder.build().map_err(|_| TransportError::Tls(host.to_string()))
+        };
+
+        Ok(match self.tls {
+            TlsMode::Implicit => Tls::Wrapper(tls_parameters()?),
+            TlsMode::Required => Tls::Required(tls_parameters()?),
+            TlsMode::Opportunistic => Tls::Opportunistic(tls_parameters()?),
+            TlsMode::None => Tls::None,
+        })
+    }
+}
+
+/// Where a mailer hands its mails over to.
+#[derive(Clone)]
+pub enum Transport {
+    /// The shop's SMTP relays
+    Smtp(SmtpRelays),
+    /// The shop's transactional email provider
+    Http(HttpTransport),
+    /// Writes every mail to an `.eml` file in a directory
+    File(AsyncFileTransport<Tokio1Executor>),
+    /// Prints every mail, for local development
+    Stdout,
+    /// Keeps the mails in memory, for tests
+    Memory(MemoryTransport),
+}
+
+impl Transport {
+    /// Creates a transport by name, `None` standing for SMTP.
+    ///
+    /// # Errors
+    ///
+    /// Returns `TransportError::Unknown` if the transport doesn't exist.
+    /// Returns `TransportError::MissingDirectory` if the file transport has no directory.
+    /// Returns `TransportError::Directory` if the directory cannot be created.
+    pub fn new(kind: &str, directory: Option<&str>) -> Result<Option<Self>, TransportError> {
+        match kind {
+            "" | "smtp" => Ok(None),
+            "file" => {
+                let directory = directory.ok_or(TransportError::MissingDirectory)?;
+                fs::create_dir_all(directory).map_err(|_| TransportError::Directory(directory.to_string()))?;
+
+                Ok(Some(Self::File(AsyncFileTransport::new(directory))))
+            }
+            "stdout" => Ok(Some(Self::Stdout)),
+            "memory" => Ok(Some(Self::Memory(MemoryTransport::shared()))),
+            _ => Err(TransportError::Unknown(kind.to_string())),
+        }
+    }
+
+    /// Hands a mail over, telling which SMTP relay accepted it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MailerError::SmtpTransientError` or `MailerError::SmtpPermanentError` if the SMTP server doesn't accept the mail.
+    /// Returns `MailerError::ApiTransientError` or `MailerError::ApiPermanentError` if the email API doesn't accept the mail.
+    /// Returns `MailerError::TransportError` if the mail cannot be written to its file.
+    pub async fn send(&self, message: Message) -> Result<Delivery, MailerError> {
+        match self {
+            Self::Smtp(relays) => return relays.send(message).await,
+            Self::Http(transport) => transport.send(&message).await?,
+            Self::File(transport) => {
+                transport.send(message).await.map_err(|e| MailerError::TransportError(e.to_string()))?;
+            }
+            Self::Stdout => println!("{}", String::from_utf8_lossy(&message.formatted())),
+            Self::Memory(transport) => transport.push(message),
+        }
+
+        Ok(Delivery::default())
+    }
+}
+
+struct Relay {
+    address: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    unavailable_until: Mutex<Option<Instant>>,
+}
+
+impl Relay {
+    fn is_available(&self) -> bool {
+        self.unavailable_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_none_or(|until| until <= Instant::now())
+    }
+
+    fn set_unavailable_until(&self, until: Option<Instant>) {
+        *self.unavailable_until.lock().unwrap_or_else(PoisonError::into_inner) = until;
+    }
+}
+
+/// SMTP relays tried in order, a relay that cannot be reached is skipped for a cooldown.
+///
+/// Clones share the health of the relays.
+#[derive(Clone)]
+pub struct SmtpRelays {
+    relays: Arc<Vec<Relay>>,
+    cooldown: Duration,
+}
+
+impl SmtpRelays {
+    /// Creates the relays from their addresses and transports, in the order they are tried.
+    #[must_use]
+    pub fn new(relays: Vec<(String, AsyncSmtpTransport<Tokio1Executor>)>, cooldown: Duration) -> Self {
+        let relays = relays
+            .into_iter()
+            .map(|(address, transport)| Relay {
+                address,
+                transport,
+                unavailable_until: Mutex::new(None),
+            })
+            .collect();
+
+        Self {
+            relays: Arc::new(relays),
+            cooldown,
+        }
+    }
+
+    /// Gets the addresses of the relays skipped until their cooldown is over.
+    #[must_use]
+    pub fn unavailable(&self) -> Vec<&str> {
+        self.relays
+            .iter()
+            .filter(|relay| !relay.is_available())
+            .map(|relay| relay.address.as_str())
+            .collect()
+    }
+
+    /// Sends a mail through the first relay that can be reached.
+    ///
+    /// A relay that answers is not failed over from, its reply is about the mail rather than the relay.
+    ///
+    /// # Errors
+    ///
+    /// Returns `MailerError::SmtpTransientError` if no relay can be reached, or the relay asks to try again later.
+    /// Returns `MailerError::SmtpPermanentError` if the relay rejected the mail.
+    pub async fn send(&self, message: Message) -> Result<Delivery, MailerError> {
+        let mut last_error = None;
+
+        for relay in self.relays.iter().filter(|relay| relay.is_available()) {
+            match relay.transport.send(message.clone()).await {
+                Ok(_) => {
+                    relay.set_unavailable_until(None);
+                    return Ok(Delivery {
+                        relay: Some(relay.address.clone()),
+                    });
+                }
+                // Without a reply the relay is down, unreachable or timed out
+                Err(e) if !e.is_transient() && !e.is_permanent() => {
+                    println!("SMTP relay {} failed, skipping it for {:?}: {e}", relay.address, self.cooldown);
+                    relay.set_unavailable_until(Some(Instant::now() + self.cooldown));
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(last_error.map_or_else(|| MailerError::SmtpTransientError("No SMTP relay is available".to_string()), Into::into))
+    }
+}
+
+/// Captures mails instead of sending them, clones share the captured mails.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    messages: Arc<Mutex<Vec<Message>>>,
+}
+
+impl MemoryTransport {
+    /// Gets the transport `email_transport=memory` selects, shared by every mailer of the process.
+    #[must_use]
+    pub fn shared() -> Self {
+        static SHARED: OnceLock<MemoryTransport> = OnceLock::new();
+
+        SHARED.get_or_init(Self::default).clone()
+    }
+
+    /// Gets the mails captured so far, oldest first.
+    #[must_use]
+    pub fn messages(&self) -> Vec<Message> {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Forgets the captured mails.
+    pub fn clear(&self) {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    fn push(&self, message: Message) {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner).push(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    // A relay accepting every mail, or answering RCPT TO with `rcpt_reply`
+    async fn stub_relay(rcpt_reply: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // lettre keeps connections open in its pool, each one is served on its own
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+                    let mut in_data = false;
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply = match line.as_str() {
+                            "." if in_data => {
+                                in_data = false;
+                                "250 Queued"
+                            }
+                            _ if in_data => continue,
+                            "DATA" => {
+                                in_data = true;
+                                "354 Go ahead"
+                            }
+                            "QUIT" => "221 Bye",
+                            line if line.starts_with("RCPT") => rcpt_reply,
+                            _ => "250 OK",
+                        };
+                        writer.write_all(format!("{reply}\r\n").as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    // The address of a relay nothing listens on
+    async fn dead_relay() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn relays(addresses: &[&str], cooldown: Duration) -> SmtpRelays {
+        let relays = addresses
+            .iter()
+            .map(|address| {
+                let (host, port) = address.split_once(':').unwrap();
+                let options = SmtpOptions {
+                    tls: TlsMode::None,
+                    ..SmtpOptions::default()
+                };
+                let transport = options.transport(host, port.parse().unwrap(), "", "").unwrap();
+
+                ((*address).to_string(), transport)
+            })
+            .collect();
+
+        SmtpRelays::new(relays, cooldown)
+    }
+
+    fn message(subject: &str) -> Message {
+        Message::builder()
+            .from("shop@test.com".parse().unwrap())
+            .to("customer@test.com".parse().unwrap())
+            .subject(subject)
+            .body("Test body".to_string())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_new_transport() {
+        assert!(Transport::new("", None).unwrap().is_none());
+        assert!(Transport::new("smtp", None).unwrap().is_none());
+        assert!(matches!(Transport::new("stdout", None), Ok(Some(Transport::Stdout))));
+        assert!(matches!(Transport::new("file", None), Err(TransportError::MissingDirectory)));
+        assert!(matches!(Transport::new("sendmail", None), Err(TransportError::Unknown(kind)) if kind == "sendmail"));
+    }
+
+    #[test]
+    fn test_tls_mode() {
+        assert_eq!("implicit".parse::<TlsMode>().unwrap(), TlsMode::Implicit);
+        assert_eq!("required".parse::<TlsMode>().unwrap(), TlsMode::Required);
+        assert_eq!("opportunistic".parse::<TlsMode>().unwrap(), TlsMode::Opportunistic);
+        assert_eq!("none".parse::<TlsMode>().unwrap(), TlsMode::None);
+        assert!(matches!("starttls".parse::<TlsMode>(), Err(TransportError::TlsMode(mode)) if mode == "starttls"));
+    }
+
+    #[tokio::test]
+    async fn test_smtp_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let greeting = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            BufReader::new(reader).lines().next_line().await.unwrap().unwrap()
+        });
+
+        let options = SmtpOptions {
+            tls: TlsMode::None,
+            hello_name: Some("mail.test.com".to_string()),
+            timeout: Duration::from_secs(1),
+            ..SmtpOptions::default()
+        };
+        let transport = options.transport("127.0.0.1", port, "", "").unwrap();
+        assert!(transport.send(message("Hello")).await.is_err());
+        assert_eq!(greeting.await.unwrap(), "EHLO mail.test.com");
+
+        // The stub relay doesn't offer STARTTLS
+        let address = stub_relay("250 OK").await;
+        let (host, port) = address.split_once(':').unwrap();
+        let port = port.parse().unwrap();
+        let required = SmtpOptions {
+            tls: TlsMode::Required,
+            ..SmtpOptions::default()
+        };
+        assert!(required.transport(host, port, "", "").unwrap().send(message("Required")).await.is_err());
+        let opportunistic = SmtpOptions {
+            tls: TlsMode::Opportunistic,
+            ..SmtpOptions::default()
+        };
+        opportunistic
+            .transport(host, port, "", "")
+            .unwrap()
+            .send(message("Opportunistic"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let transport = MemoryTransport::default();
+        Transport::Memory(transport.clone()).send(message("First")).await.unwrap();
+        Transport::Memory(transport.clone()).send(message("Second")).await.unwrap();
+
+        let subjects: Vec<_> = transport
+            .messages()
+            .iter()
+            .map(|message| message.headers().get_raw("Subject").unwrap().to_string())
+            .collect();
+        assert_eq!(subjects, ["First", "Second"]);
+
+        transport.clear();
+        assert!(transport.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_failover() {
+        let (dead, live) = (dead_relay().await, stub_relay("250 OK").await);
+        let relays = relays(&[&dead, &live], Duration::from_secs(60));
+
+        let delivery = relays.send(message("First")).await.unwrap();
+        assert_eq!(delivery.relay.as_deref(), Some(live.as_str()));
+        assert_eq!(relays.unavailable(), [dead.as_str()]);
+
+        // The unreachable relay is skipped until its cooldown is over
+        let delivery = relays.send(message("Second")).await.unwrap();
+        assert_eq!(delivery.relay.as_deref(), Some(live.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_relay_cooldown() {
+        let dead = dead_relay().await;
+        let without_cooldown = relays(&[&dead], Duration::ZERO);
+
+        assert!(matches!(
+            without_cooldown.send(message("First")).await,
+            Err(MailerError::SmtpTransientError(_))
+        ));
+        // Without a cooldown, the relay is tried again right away
+        assert!(without_cooldown.unavailable().is_empty());
+
+        let with_cooldown = relays(&[&dead], Duration::from_secs(60));
+        with_cooldown.send(message("First")).await.unwrap_err();
+        let error = with_cooldown.send(message("Second")).await.unwrap_err();
+        assert!(matches!(error, MailerError::SmtpTransientError(description) if description == "No SMTP relay is available"));
+    }
+
+    #[tokio::test]
+    async fn test_rejection_does_not_fail_over() {
+        let (rejecting, live) = (stub_relay("550 No such user").await, stub_relay("250 OK").await);
+        let relays = relays(&[&rejecting, &live], Duration::from_secs(60));
+
+        assert!(matches!(relays.send(message("First")).await, Err(MailerError::SmtpPermanentError(_))));
+        assert!(relays.unavailable().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_file_transport() {
+        let directory = env::temp_dir().join(format!("notification_service_mails_{}", std::process::id()));
+        let transport = Transport::new("file", directory.to_str()).unwrap().unwrap();
+        transport.send(message("Your order")).await.unwrap();
+
+        let files: Vec<_> = fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        assert!(fs::read_to_string(&files[0]).unwrap().contains("Subject: Your order"));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,500 @@
+// Elements without a closing tag, they never contain the elements after them
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source", "track", "wbr",
+];
+
+// Elements that aren't shown, a rule such as `* { margin: 0 }` has no business with them
+const HEAD_ELEMENTS: [&str; 2] = ["html", "head"];
+
+#[derive(Debug, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+// A selector such as `div.footer`, matched against a single element
+#[derive(Debug, Default, PartialEq)]
+struct Compound {
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+}
+
+impl Compound {
+    fn parse(text: &str) -> Option<Self> {
+        let mut compound = Self::default();
+        let mut rest = text;
+
+        let tag_end = rest.find(['.', '#']).unwrap_or(rest.len());
+        match &rest[..tag_end] {
+            "" | "*" => {}
+            tag if is_name(tag) => compound.tag = Some(tag.to_ascii_lowercase()),
+            _ => return None,
+        }
+        rest = &rest[tag_end..];
+
+        while let Some(kind) = rest.chars().next() {
+            let end = rest[1..].find(['.', '#']).map_or(rest.len(), |end| end + 1);
+            let name = &rest[1..end];
+            if !is_name(name) {
+                return None;
+            }
+            if kind == '#' {
+                compound.id = Some(name.to_string());
+            } else {
+                compound.classes.push(name.to_string());
+            }
+            rest = &rest[end..];
+        }
+
+        Some(compound)
+    }
+
+    fn matches(&self, element: &Element) -> bool {
+        self.tag.as_ref().is_none_or(|tag| *tag == element.name)
+            && self.id.as_ref().is_none_or(|id| element.id.as_ref() == Some(id))
+            && self.classes.iter().all(|class| element.classes.contains(class))
+    }
+}
+
+// Selectors with pseudo-classes, attributes or sibling combinators only work in a style block, so they are left there
+#[derive(Debug, PartialEq)]
+struct Selector {
+    // The compound on the right matches the element, the others its ancestors
+    compounds: Vec<(Compound, Combinator)>,
+}
+
+impl Selector {
+    fn parse(text: &str) -> Option<Self> {
+        let mut compounds = Vec::new();
+        let mut combinator = Combinator::Descendant;
+
+        for token in text.replace('>', " > ").split_whitespace() {
+            if token == ">" {
+                if compounds.is_empty() || combinator == Combinator::Child {
+                    return None;
+                }
+                combinator = Combinator::Child;
+                continue;
+            }
+            compounds.push((Compound::parse(token)?, combinator));
+            combinator = Combinator::Descendant;
+        }
+
+        (!compounds.is_empty() && combinator == Combinator::Descendant).then_some(Self { compounds })
+    }
+
+    // Ids, classes and tags, in the order they weigh
+    fn specificity(&self) -> (usize, usize, usize) {
+        self.compounds.iter().fold((0, 0, 0), |(ids, classes, tags), (compound, _)| {
+            (
+                ids + usize::from(compound.id.is_some()),
+                classes + compound.classes.len(),
+                tags + usize::from(compound.tag.is_some()),
+            )
+        })
+    }
+
+    // `ancestors` are the open elements, the parent last
+    fn matches(&self, element: &Element, ancestors: &[Element]) -> bool {
+        let Some(((compound, combinator), rest)) = self.compounds.split_last() else {
+            return false;
+        };
+        compound.matches(element) && Self::matches_ancestors(rest, combinator, ancestors)
+    }
+
+    fn matches_ancestors(compounds: &[(Compound, Combinator)], combinator: &Combinator, ancestors: &[Element]) -> bool {
+        let Some(((compound, next), rest)) = compounds.split_last() else {
+            return true;
+        };
+
+        match combinator {
+            Combinator::Child => ancestors
+                .split_last()
+                .is_some_and(|(parent, above)| compound.matches(parent) && Self::matches_ancestors(rest, next, above)),
+            Combinator::Descendant => (0..ancestors.len())
+                .rev()
+                .any(|index| compound.matches(&ancestors[index]) && Self::matches_ancestors(rest, next, &ancestors[..index])),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Declaration {
+    property: String,
+    value: String,
+    important: bool,
+}
+
+struct Rule {
+    selector: Selector,
+    declarations: Vec<Declaration>,
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    id: Option<String>,
+    classes: Vec<String>,
+}
+
+/// Moves the rules of the `<style>` blocks of an HTML email into the `style` attributes of the elements they apply to.
+///
+/// Gmail and several other clients drop style blocks, inline styles are the only ones every client shows.
+/// Declarations already in a `style` attribute win over the rules, as they would in a browser. Media queries and rules
+/// with selectors that can't be inlined, such as `a:hover`, stay in the style block for the clients that support it.
+#[must_use]
+pub fn inline(html: &str) -> String {
+    let lowercase = html.to_ascii_lowercase();
+
+    // Where each style block starts and ends, with the CSS left in it once its rules are inlined
+    let mut blocks = Vec::new();
+    let mut rules = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = lowercase[offset..].find("<style").map(|start| offset + start) {
+        // A style block in a comment isn't applied by browsers either
+        if let Some(comment) = lowercase[offset..start].find("<!--").map(|comment| offset + comment) {
+            offset = lowercase[comment..].find("-->").map_or(html.len(), |end| comment + end + 3);
+            continue;
+        }
+        let Some(content_start) = lowercase[start..].find('>').map(|end| start + end + 1) else {
+            break;
+        };
+        let content_end = lowercase[content_start..].find("</style").map_or(html.len(), |end| content_start + end);
+        let end = lowercase[content_end..].find('>').map_or(html.len(), |end| content_end + end + 1);
+
+        let remaining = parse_stylesheet(&html[content_start..content_end], &mut rules);
+        blocks.push((
+            start,
+            end,
+            remaining.map(|css| format!("{}{css}{}", &html[start..content_start], &html[content_end..end])),
+        ));
+        offset = end;
+    }
+
+    if rules.is_empty() {
+        return html.to_string();
+    }
+
+    let mut inlined = String::with_capacity(html.len() + html.len() / 2);
+    let mut ancestors: Vec<Element> = Vec::new();
+    let mut blocks = blocks.into_iter().peekable();
+    let mut position = 0;
+
+    while let Some(start) = html[position..].find('<').map(|start| position + start) {
+        inlined.push_str(&html[position..start]);
+
+        if let Some((_, end, remaining)) = blocks.next_if(|(block_start, ..)| *block_start == start) {
+            inlined.push_str(remaining.as_deref().unwrap_or_default());
+            position = end;
+            continue;
+        }
+
+        let end = if html[start..].starts_with("<!--") {
+            html[start..].find("-->").map_or(html.len(), |end| start + end + 3)
+        } else {
+            html[start..].find('>').map_or(html.len(), |end| start + end + 1)
+        };
+        let tag = &html[start..end];
+        position = end;
+
+        let name = tag_name(tag);
+        if tag.starts_with("</") {
+            // Closes the element and any left open inside it, such as a <p> without its </p>
+            if let Some(index) = ancestors.iter().rposition(|element| element.name == name) {
+                ancestors.truncate(index);
+            }
+            inlined.push_str(tag);
+            continue;
+        }
+        if name.is_empty() || !tag.ends_with('>') {
+            inlined.push_str(tag);
+            continue;
+        }
+
+        let element = Element {
+            id: attribute(tag, "id").map(|(_, value)| value.to_string()),
+            classes: attribute(tag, "class")
+                .map(|(_, value)| value.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+            name,
+        };
+
+        let mut matched: Vec<(&Rule, (usize, usize, usize))> = rules
+            .iter()
+            .filter(|rule| rule.selector.matches(&element, &ancestors))
+            .map(|rule| (rule, rule.selector.specificity()))
+            .collect();
+        // Stable, so rules as specific as each other keep the order they were written in
+        matched.sort_by_key(|(_, specificity)| *specificity);
+
+        if matched.is_empty() || HEAD_ELEMENTS.contains(&element.name.as_str()) || ancestors.iter().any(|element| element.name == "head") {
+            inlined.push_str(tag);
+        } else {
+            let declarations = matched.iter().flat_map(|(rule, _)| rule.declarations.iter().cloned());
+            inlined.push_str(&with_style(tag, declarations));
+        }
+
+        if !VOID_ELEMENTS.contains(&element.name.as_str()) && !tag.ends_with("/>") {
+            ancestors.push(element);
+        }
+    }
+
+    inlined.push_str(&html[position..]);
+    inlined
+}
+
+// Adds the rules that can be inlined, returns the CSS that has to stay in the style block if there is any
+fn parse_stylesheet(css: &str, rules: &mut Vec<Rule>) -> Option<String> {
+    let css = strip_comments(css);
+    let mut remaining = String::new();
+    let mut rest = css.as_str();
+
+    while let Some(open) = rest.find(['{', ';']) {
+        let prelude = rest[..open].trim();
+
+        // At-rules such as @media or @font-face, kept as they are
+        if prelude.starts_with('@') {
+            let end = if rest[open..].starts_with(';') {
+                open + 1
+            } else {
+                block_end(rest, open)
+            };
+            remaining.push_str(rest[..end].trim());
+            remaining.push('\n');
+            rest = &rest[end..];
+            continue;
+        }
+        if rest[open..].starts_with(';') {
+            rest = &rest[open + 1..];
+            continue;
+        }
+
+        let close = rest[open..].find('}').map_or(rest.len(), |close| open + close);
+        let block = &rest[open + 1..close];
+        let declarations = parse_declarations(block);
+        rest = rest.get(close + 1..).unwrap_or_default();
+
+        let mut kept = Vec::new();
+        for selector in prelude.split(',').map(str::trim).filter(|selector| !selector.is_empty()) {
+            match Selector::parse(selector) {
+                Some(selector) => rules.push(Rule {
+                    selector,
+                    declarations: declarations.clone(),
+                }),
+                None => kept.push(selector),
+            }
+        }
+        if !kept.is_empty() {
+            remaining.push_str(&format!("{} {{{}}}\n", kept.join(", "), block.trim()));
+        }
+    }
+
+    Some(remaining.trim().to_string()).filter(|remaining| !remaining.is_empty())
+}
+
+// Where the block opened at `open` ends, counting the blocks nested in it
+fn block_end(css: &str, open: usize) -> usize {
+    let mut depth = 0;
+    for (index, character) in css[open..].char_indices() {
+        match character {
+            '{' => depth += 1,
+            '}' if depth == 1 => return open + index + 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    css.len()
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut stripped = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        stripped.push_str(&rest[..start]);
+        rest = rest[start..].find("*/").map_or("", |end| &rest[start + end + 2..]);
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+fn parse_declarations(block: &str) -> Vec<Declaration> {
+    split_declarations(block)
+        .into_iter()
+        .filter_map(|declaration| {
+            let (property, value) = declaration.split_once(':')?;
+            let property = property.trim().to_ascii_lowercase();
+            let value = value.trim();
+            let (value, important) = match value.to_ascii_lowercase().rfind("!important") {
+                Some(index) => (value[..index].trim_end(), true),
+                None => (value, false),
+            };
+
+            (!property.is_empty() && !value.is_empty()).then(|| Declaration {
+                property,
+                value: value.to_string(),
+                important,
+            })
+        })
+        .collect()
+}
+
+// Splits on the semicolons that end declarations, not the ones in quotes or in url(data:...;base64,...)
+fn split_declarations(block: &str) -> Vec<&str> {
+    let mut declarations = Vec::new();
+    let mut quote = None;
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (index, character) in block.char_indices() {
+        match (character, quote) {
+            ('"' | '\'', None) => quote = Some(character),
+            (character, Some(open)) if character == open => quote = None,
+            (_, Some(_)) => {}
+            ('(', None) => depth += 1,
+            (')', None) => depth -= 1,
+            (';', None) if depth == 0 => {
+                declarations.push(&block[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    declarations.push(&block[start..]);
+    declarations
+}
+
+// Rewrites the style attribute of a tag with the declarations of the rules before the ones it already had
+fn with_style(tag: &str, declarations: impl Iterator<Item = Declaration>) -> String {
+    let existing = attribute(tag, "style");
+    let inline = existing.map(|(_, value)| parse_declarations(value)).unwrap_or_default();
+
+    let mut merged: Vec<Declaration> = Vec::new();
+    // Coming last, an inline declaration beats any rule, unless the rule is important and it is not
+    for declaration in declarations.chain(inline) {
+        match merged.iter_mut().find(|merged| merged.property == declaration.property) {
+            Some(merged) if merged.important && !declaration.important => {}
+            Some(merged) => *merged = declaration,
+            None => merged.push(declaration),
+        }
+    }
+
+    let style = merged
+        .iter()
+        .map(|declaration| {
+            let important = if declaration.important { " !important" } else { "" };
+            format!("{}: {}{important}", declaration.property, declaration.value.replace('"', "'"))
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    match existing {
+        Some((span, _)) => format!("{}style=\"{style}\"{}", &tag[..span.0], &tag[span.1..]),
+        None => {
+            let end = if tag.ends_with("/>") { tag.len() - 2 } else { tag.len() - 1 };
+            let before = tag[..end].trim_end();
+            format!("{before} style=\"{style}\"{}", &tag[before.len()..])
+        }
+    }
+}
+
+fn tag_name(tag: &str) -> String {
+    tag.trim_start_matches('<')
+        .trim_start_matches('/')
+        .split(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .next()
+        .filter(|name| name.starts_with(|c: char| c.is_ascii_alphabetic()))
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+// The span of the whole attribute in the tag and its value
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<((usize, usize), &'a str)> {
+    let lowercase = tag.to_ascii_lowercase();
+    let mut offset = 0;
+
+    while let Some(found) = lowercase[offset..].find(name) {
+        let start = offset + found;
+        offset = start + name.len();
+
+        if !lowercase[..start].ends_with(|c: char| c.is_ascii_whitespace()) {
+            continue;
+        }
+        let after_name = tag[offset..].trim_start();
+        let Some(value) = after_name.strip_prefix('=').map(str::trim_start) else {
+            continue;
+        };
+        let value_start = tag.len() - value.len();
+
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let length = value[1..].find(quote).unwrap_or(value.len() - 1);
+                ((start, value_start + length + 2), &value[1..=length])
+            }
+            _ => {
+                let length = value.find(|c: char| c.is_ascii_whitespace() || c == '>').unwrap_or(value.len());
+                ((start, value_start + length), &value[..length])
+            }
+        });
+    }
+
+    None
+}
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline() {
+        let html = r##"<html><head><style>
+            /* brand */
+            p { color: #333; margin: 0 }
+            .footer p, #total { font-size: 12px; }
+            td > a.button { background: url(data:image/png;base64,AA==); font-family: "Helvetica Neue", Arial }
+            a:hover { color: red }
+            @media (max-width: 600px) { .footer { display: none } }
+        </style></head>
+        <body><p style="color: blue">Hi</p><div class="footer"><p>Bye<br></div>
+        <table><tr><td><a class="button" href="#">Shop</a></td></tr></table><span id="total"/></body></html>"##;
+
+        assert_eq!(
+            inline(html),
+            r##"<html><head><style>a:hover {color: red}
+@media (max-width: 600px) { .footer { display: none } }</style></head>
+        <body><p style="color: blue; margin: 0">Hi</p><div class="footer"><p style="color: #333; margin: 0; font-size: 12px">Bye<br></div>
+        <table><tr><td><a class="button" href="#" style="background: url(data:image/png;base64,AA==); font-family: 'Helvetica Neue', Arial">Shop</a></td></tr></table><span id="total" style="font-size: 12px"/></body></html>"##
+        );
+    }
+
+    #[test]
+    fn test_inline_specificity_and_important() {
+        let html = "<style>#main { color: red } p.lead { color: green !important } p { color: blue; margin: 0 }</style>\
+            <p id=\"main\" class=\"lead\" style=\"color: black; margin: 4px\">Hi</p>";
+
+        assert_eq!(
+            inline(html),
+            "<p id=\"main\" class=\"lead\" style=\"color: green !important; margin: 4px\">Hi</p>"
+        );
+    }
+
+    #[test]
+    fn test_inline_without_styles() {
+        let html = "<p class=\"note\">Hi</p><!-- <style>p { color: red }</style> -->";
+        assert_eq!(inline(html), html);
+    }
+
+    #[test]
+    fn test_selector_parse() {
+        assert!(Selector::parse("ul > li.item").is_some());
+        assert!(Selector::parse("a:hover").is_none());
+        assert!(Selector::parse("input[type=text]").is_none());
+        assert!(Selector::parse("h1 + p").is_none());
+        assert!(Selector::parse("> p").is_none());
+        assert_eq!(Selector::parse("div#main .item p").unwrap().specificity(), (1, 1, 2));
+    }
+}
@@ -1,6 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct Email {
     pub to: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<String>,
+    /// Not shown to the other recipients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bcc: Vec<String>,
+    /// Where replies go instead of the origin address, e.g. the shop's support
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Extra headers such as `List-Unsubscribe` or `X-Order-Id`, headers set by the mailer can't be replaced
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
     pub subject: String,
     pub html_body: String,
-    pub attachment: Option<Vec<u8>>,
+    /// Sent as the plain text alternative of the HTML, emails queued before it existed have none
+    #[serde(default)]
+    pub text_body: Option<String>,
+    /// Emails queued before there could be several attachments have the invoice PDF as `attachment`
+    #[serde(default, alias = "attachment", deserialize_with = "attachments::deserialize")]
+    pub attachments: Vec<EmailAttachment>,
+}
+
+/// A file sent with an email, shown inline where the HTML references its content id as `cid:`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmailAttachment {
+    pub filename: String,
+    /// MIME type, e.g. `application/pdf` or `text/csv`
+    pub content_type: String,
+    /// Set for images the HTML shows inline, they aren't listed as attachments by mail clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_id: Option<String>,
+    #[serde(with = "base64_bytes")]
+    pub content: Vec<u8>,
+}
+
+impl EmailAttachment {
+    #[must_use]
+    pub fn new(filename: impl Into<String>, content_type: impl Into<String>, content: Vec<u8>) -> Self {
+        Self {
+            filename: filename.into(),
+            content_type: content_type.into(),
+            content_id: None,
+            content,
+        }
+    }
+}
+
+// Emails are persisted as JSON in the outbox, attachments are stored as base64 instead of an array of numbers
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        STANDARD.decode(String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+mod attachments {
+    use super::EmailAttachment;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Attachments(Vec<EmailAttachment>),
+        // The base64 invoice of emails queued before attachments had names and types
+        Invoice(String),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<EmailAttachment>, D::Error> {
+        match Option::<Stored>::deserialize(deserializer)? {
+            None => Ok(Vec::new()),
+            Some(Stored::Attachments(attachments)) => Ok(attachments),
+            Some(Stored::Invoice(encoded)) => {
+                let content = STANDARD.decode(encoded).map_err(D::Error::custom)?;
+                Ok(vec![EmailAttachment::new("invoice.pdf", "application/pdf", content)])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_email_round_trip() {
+        let email = Email {
+            to: "recipient@test.com".to_string(),
+            cc: Vec::new(),
+            bcc: vec!["archive@test.com".to_string()],
+            reply_to: None,
+            headers: BTreeMap::from([("X-Order-Id".to_string(), "1001".to_string())]),
+            subject: "Test Subject".to_string(),
+            html_body: "<img src=\"cid:logo\">".to_string(),
+            text_body: None,
+            attachments: vec![
+                EmailAttachment::new("orders.csv", "text/csv", b"order,total\n1001,19.90\n".to_vec()),
+                EmailAttachment {
+                    content_id: Some("logo".to_string()),
+                    ..EmailAttachment::new("logo.png", "image/png", vec![137, 80, 78, 71])
+                },
+            ],
+        };
+
+        let stored = serde_json::to_value(&email).unwrap();
+        assert_eq!(stored["attachments"][1]["content"], "iVBORw==");
+        assert!(stored.get("cc").is_none() && stored.get("reply_to").is_none());
+        assert_eq!(serde_json::from_value::<Email>(stored).unwrap(), email);
+    }
+
+    #[test]
+    fn test_legacy_invoice_attachment() {
+        let stored = json!({ "to": "recipient@test.com", "subject": "Test", "html_body": "", "attachment": "AQIDBA==" });
+        let email: Email = serde_json::from_value(stored).unwrap();
+
+        assert_eq!(
+            email.attachments,
+            [EmailAttachment::new("invoice.pdf", "application/pdf", vec![1, 2, 3, 4])]
+        );
+
+        let stored = json!({ "to": "recipient@test.com", "subject": "Test", "html_body": "", "attachment": null });
+        assert!(serde_json::from_value::<Email>(stored).unwrap().attachments.is_empty());
+    }
 }
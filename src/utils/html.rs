@@ -0,0 +1,255 @@
+// Elements whose content is never shown as text
+const HIDDEN_ELEMENTS: [&str; 4] = ["head", "style", "script", "title"];
+
+// Elements that start on a line of their own
+const BLOCK_ELEMENTS: [&str; 20] = [
+    "address",
+    "article",
+    "blockquote",
+    "center",
+    "div",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "tr",
+    "ul",
+];
+
+/// Derives a plain text version of an HTML email, for clients that don't show HTML and for screen readers.
+///
+/// Blocks and line breaks become lines, list items are prefixed with `- ` and links are followed by their URL.
+/// Styles, scripts and the document head are left out.
+#[must_use]
+pub fn to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len() / 2);
+    // The URL of each open link and where its text starts
+    let mut links: Vec<(Option<String>, usize)> = Vec::new();
+    let mut hidden_until: Option<&str> = None;
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        if hidden_until.is_none() {
+            push_text(&mut text, &rest[..start]);
+        }
+
+        if rest[start..].starts_with("<!--") {
+            rest = rest[start..].find("-->").map_or("", |end| &rest[start + end + 3..]);
+            continue;
+        }
+
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        if let Some(hidden) = hidden_until {
+            if closing && name == hidden {
+                hidden_until = None;
+            }
+            continue;
+        }
+
+        match name.as_str() {
+            name if !closing && HIDDEN_ELEMENTS.contains(&name) => {
+                hidden_until = HIDDEN_ELEMENTS.iter().copied().find(|hidden| *hidden == name);
+            }
+            "br" => text.push('\n'),
+            "hr" => text.push_str("\n\n----\n\n"),
+            "li" if !closing => text.push_str("\n- "),
+            "td" | "th" if closing => text.push(' '),
+            "img" => {
+                if let Some(alt) = attribute(tag, "alt").filter(|alt| !alt.trim().is_empty()) {
+                    push_text(&mut text, &alt);
+                }
+            }
+            "a" if !closing => links.push((attribute(tag, "href"), text.len())),
+            "a" => {
+                if let Some((Some(href), start)) = links.pop() {
+                    push_link(&mut text, &href, start);
+                }
+            }
+            name if BLOCK_ELEMENTS.contains(&name) => text.push_str("\n\n"),
+            _ => {}
+        }
+    }
+
+    if hidden_until.is_none() {
+        push_text(&mut text, rest);
+    }
+
+    tidy(&text)
+}
+
+// Appends the URL after the link text, unless the text already is the URL
+fn push_link(text: &mut String, href: &str, start: usize) {
+    let href = decode_entities(href.trim());
+    let is_web = ["http://", "https://", "mailto:"].iter().any(|scheme| href.starts_with(scheme));
+    let label = text[start..].trim();
+
+    if is_web && label != href.trim_start_matches("mailto:") && label != href {
+        text.push_str(&format!(" ({href})"));
+    }
+}
+
+// Whitespace in HTML is a single space wherever it comes from, line breaks come from the elements
+fn push_text(text: &mut String, content: &str) {
+    for (index, word) in decode_entities(content).split_ascii_whitespace().enumerate() {
+        if index > 0 || content.starts_with(|c: char| c.is_ascii_whitespace()) {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    if content.ends_with(|c: char| c.is_ascii_whitespace()) && !content.trim().is_empty() {
+        text.push(' ');
+    }
+}
+
+// Trims the lines and keeps at most one empty line between paragraphs
+fn tidy(text: &str) -> String {
+    let mut tidied = String::with_capacity(text.len());
+    let mut empty_lines = 0;
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            empty_lines += 1;
+            continue;
+        }
+        if !tidied.is_empty() {
+            tidied.push_str(if empty_lines > 0 { "\n\n" } else { "\n" });
+        }
+        tidied.push_str(line);
+        empty_lines = 0;
+    }
+
+    tidied
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lowercase = tag.to_ascii_lowercase();
+    let mut offset = 0;
+
+    while let Some(found) = lowercase[offset..].find(name) {
+        let position = offset + found;
+        offset = position + name.len();
+
+        let preceded_by_space = lowercase[..position].ends_with(|c: char| c.is_ascii_whitespace());
+        let value = tag[offset..].trim_start();
+        let Some(value) = value.strip_prefix('=').map(str::trim_start).filter(|_| preceded_by_space) else {
+            continue;
+        };
+
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or_default().to_string(),
+            _ => value.split(|c: char| c.is_ascii_whitespace()).next().unwrap_or_default().to_string(),
+        });
+    }
+
+    None
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest[1..].find(';').filter(|end| *end <= 10).map(|end| &rest[1..=end]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map_or_else(
+                    || entity.strip_prefix('#').and_then(|code| code.parse().ok()),
+                    |code| u32::from_str_radix(code, 16).ok(),
+                )
+                .and_then(char::from_u32),
+        });
+
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_text() {
+        let html = r#"<!DOCTYPE html>
+            <html>
+            <head><title>Order</title><style>p { color: red; }</style></head>
+            <body style="font-family: Arial">
+                <!-- header -->
+                <h1>Thank You for Your Order!</h1>
+                <p>Dear Tom &amp; Jerry,<br>your order
+                   #1001 is on its way.</p>
+                <ul><li>1 &#215; Mug</li><li>2 &#x3D; Plates</li></ul>
+                <p><a href="https://shop.example.com/orders?id=1&amp;t=2">Track it</a> or mail
+                <a href="mailto:help@example.com">help@example.com</a></p>
+                <img src="logo.png" alt="Example Shop">
+            </body>
+            </html>"#;
+
+        assert_eq!(
+            to_text(html),
+            "Thank You for Your Order!\n\n\
+            Dear Tom & Jerry,\nyour order #1001 is on its way.\n\n\
+            - 1 × Mug\n- 2 = Plates\n\n\
+            Track it (https://shop.example.com/orders?id=1&t=2) or mail help@example.com\n\n\
+            Example Shop"
+        );
+    }
+
+    #[test]
+    fn test_to_text_table() {
+        assert_eq!(
+            to_text("<table><tr><td>Mug</td><td>$10.00</td></tr><tr><td>Plate</td><td>$5.00</td></tr></table>"),
+            "Mug $10.00\n\nPlate $5.00"
+        );
+    }
+
+    #[test]
+    fn test_to_text_without_markup() {
+        assert_eq!(to_text("  Hello\n  world <3  "), "Hello world <3");
+    }
+}
@@ -1,4 +1,6 @@
+pub mod css;
 pub mod email;
+pub mod html;
 pub mod shopify;
 
-pub use email::Email;
+pub use email::{Email, EmailAttachment};
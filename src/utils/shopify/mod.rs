@@ -1 +1,3 @@
+pub mod webhook_event;
+pub mod webhook_secrets;
 pub mod webhook_types;
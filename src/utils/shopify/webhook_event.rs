@@ -0,0 +1,14 @@
+use std::time::SystemTime;
+
+/// The webhook being processed, added to the request extensions once its origin is verified.
+#[derive(Clone, Debug)]
+pub struct WebhookEvent {
+    pub event_id: String,
+    pub webhook_secret_id: String,
+    pub topic: String,
+    pub webhook_id: String,
+    pub shop_domain: String,
+    pub api_version: String,
+    /// When the claim on the event expires, `None` until it is claimed
+    pub locked_until: Option<SystemTime>,
+}
@@ -0,0 +1,228 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+// Key id used when a single secret is configured without an id
+pub const DEFAULT_SECRET_ID: &str = "default";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum WebhookSecretsError {
+    #[error("No webhook secrets are configured")]
+    NoSecrets,
+
+    #[error("Webhook secret entry is empty")]
+    EmptyEntry,
+
+    #[error("Webhook secret {0} has an empty secret")]
+    EmptySecret(String),
+
+    #[error("Webhook secret {0} has an invalid not-after timestamp")]
+    InvalidNotAfter(String),
+
+    #[error("Webhook secret id {0} is used more than once")]
+    DuplicateId(String),
+
+    #[error("Webhook secret id {0} may only contain letters, digits, '-' and '_'")]
+    InvalidId(String),
+
+    #[error("Webhook secret contains ':' but does not start with an id, write it as id:secret")]
+    Ambiguous,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookSecret {
+    pub id: String,
+    pub secret: String,
+    pub not_after: Option<SystemTime>,
+}
+
+impl WebhookSecret {
+    /// Checks if the secret can still be used to verify webhooks.
+    #[must_use]
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.not_after.is_none_or(|not_after| now <= not_after)
+    }
+}
+
+/// The set of secrets webhooks may be signed with, in the order they are tried.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WebhookSecrets {
+    secrets: Vec<WebhookSecret>,
+}
+
+impl WebhookSecrets {
+    /// Parses a comma separated list of `id:secret[:not_after]` entries, where `not_after` is a unix timestamp.
+    ///
+    /// A single entry without an id is accepted and gets the id `default`. A single secret that contains ':' is only
+    /// read as an entry when the part before the first ':' is a valid id, otherwise it is rejected as ambiguous.
+    ///
+    /// # Errors
+    ///
+    /// Returns `WebhookSecretsError::NoSecrets` if the list is empty.
+    /// Returns `WebhookSecretsError::EmptyEntry` if an entry is empty.
+    /// Returns `WebhookSecretsError::EmptySecret` if an entry has no secret.
+    /// Returns `WebhookSecretsError::InvalidNotAfter` if the not-after timestamp cannot be parsed.
+    /// Returns `WebhookSecretsError::DuplicateId` if two entries share the same id.
+    /// Returns `WebhookSecretsError::InvalidId` if an id contains other characters than letters, digits, '-' and '_'.
+    /// Returns `WebhookSecretsError::Ambiguous` if a single secret contains ':' without starting with a valid id.
+    pub fn parse(value: &str) -> Result<Self, WebhookSecretsError> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(WebhookSecretsError::NoSecrets);
+        }
+
+        if !value.contains(':') && !value.contains(',') {
+            return Ok(Self::single(value));
+        }
+
+        if !value.contains(',') && value.split(':').next().is_some_and(|id| !is_valid_id(id.trim())) {
+            return Err(WebhookSecretsError::Ambiguous);
+        }
+
+        let mut secrets: Vec<WebhookSecret> = Vec::new();
+        for entry in value.split(',') {
+            let mut fields = entry.trim().splitn(3, ':');
+            let id = fields.next().unwrap_or_default().trim();
+            if id.is_empty() {
+                return Err(WebhookSecretsError::EmptyEntry);
+            }
+            if !is_valid_id(id) {
+                return Err(WebhookSecretsError::InvalidId(id.to_string()));
+            }
+
+            let secret = fields.next().unwrap_or_default().trim();
+            if secret.is_empty() {
+                return Err(WebhookSecretsError::EmptySecret(id.to_string()));
+            }
+
+            let not_after = match fields.next() {
+                Some(timestamp) => Some(
+                    timestamp
+                        .trim()
+                        .parse::<u64>()
+                        .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+                        .map_err(|_| WebhookSecretsError::InvalidNotAfter(id.to_string()))?,
+                ),
+                None => None,
+            };
+
+            if secrets.iter().any(|existing| existing.id == id) {
+                return Err(WebhookSecretsError::DuplicateId(id.to_string()));
+            }
+
+            secrets.push(WebhookSecret {
+                id: id.to_string(),
+                secret: secret.to_string(),
+                not_after,
+            });
+        }
+
+        Ok(Self { secrets })
+    }
+
+    /// Creates a set holding a single secret that never expires.
+    #[must_use]
+    pub fn single(secret: &str) -> Self {
+        Self {
+            secrets: vec![WebhookSecret {
+                id: DEFAULT_SECRET_ID.to_string(),
+                secret: secret.to_string(),
+                not_after: None,
+            }],
+        }
+    }
+
+    /// Returns the secrets that have not passed their not-after timestamp.
+    pub fn active(&self, now: SystemTime) -> impl Iterator<Item = &WebhookSecret> {
+        self.secrets.iter().filter(move |secret| secret.is_active(now))
+    }
+}
+
+// Checks if an id only uses letters, digits, '-' and '_', so it cannot be part of a secret by accident
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_single_secret() {
+        let secrets = WebhookSecrets::parse("shpss_secret").unwrap();
+        assert_eq!(secrets, WebhookSecrets::single("shpss_secret"));
+    }
+
+    #[test]
+    fn test_parse_multiple_secrets() {
+        let secrets = WebhookSecrets::parse("new:shpss_new, old:shpss_old:1735689600").unwrap();
+        let active: Vec<&WebhookSecret> = secrets.active(UNIX_EPOCH).collect();
+
+        assert_eq!(active.len(), 2);
+        assert_eq!(active[0].id, "new");
+        assert_eq!(active[0].not_after, None);
+        assert_eq!(active[1].id, "old");
+        assert_eq!(active[1].secret, "shpss_old");
+        assert_eq!(active[1].not_after, Some(UNIX_EPOCH + Duration::from_secs(1_735_689_600)));
+    }
+
+    #[test]
+    fn test_active_skips_expired_secrets() {
+        let secrets = WebhookSecrets::parse("new:shpss_new,old:shpss_old:1735689600").unwrap();
+        let after_expiry = UNIX_EPOCH + Duration::from_secs(1_735_689_601);
+
+        let active: Vec<&str> = secrets.active(after_expiry).map(|secret| secret.id.as_str()).collect();
+        assert_eq!(active, vec!["new"]);
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(WebhookSecrets::parse(" ").unwrap_err(), WebhookSecretsError::NoSecrets);
+    }
+
+    #[test]
+    fn test_parse_empty_entry() {
+        assert_eq!(WebhookSecrets::parse("new:shpss_new,").unwrap_err(), WebhookSecretsError::EmptyEntry);
+    }
+
+    #[test]
+    fn test_parse_empty_secret() {
+        assert_eq!(
+            WebhookSecrets::parse("new:,old:shpss_old").unwrap_err(),
+            WebhookSecretsError::EmptySecret("new".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_not_after() {
+        assert_eq!(
+            WebhookSecrets::parse("old:shpss_old:2025-01-01").unwrap_err(),
+            WebhookSecretsError::InvalidNotAfter("old".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_duplicate_id() {
+        assert_eq!(
+            WebhookSecrets::parse("new:shpss_new,new:shpss_old").unwrap_err(),
+            WebhookSecretsError::DuplicateId("new".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_ambiguous_secret() {
+        assert_eq!(WebhookSecrets::parse("shp/ss:secret").unwrap_err(), WebhookSecretsError::Ambiguous);
+        assert_eq!(WebhookSecrets::parse(":shpss_secret").unwrap_err(), WebhookSecretsError::Ambiguous);
+        assert_eq!(WebhookSecrets::parse("shp+ss=:abc").unwrap_err(), WebhookSecretsError::Ambiguous);
+
+        let error = WebhookSecrets::parse("primary:shp/ss:secret").unwrap_err();
+        assert_eq!(error, WebhookSecretsError::InvalidNotAfter("primary".to_string()));
+    }
+
+    #[test]
+    fn test_parse_invalid_id() {
+        assert_eq!(
+            WebhookSecrets::parse("new:shpss_new,o/ld:shpss_old").unwrap_err(),
+            WebhookSecretsError::InvalidId("o/ld".to_string())
+        );
+    }
+}
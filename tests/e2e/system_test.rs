@@ -1,7 +1,10 @@
 use axum::http::HeaderMap;
-use notification_service::app::app;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use notification_service::{app::app, config::Config};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::env;
 use testcontainers_modules::{
     postgres,
@@ -41,16 +44,20 @@ async fn test_e2e() {
     env::set_var("shopify_shop_url", shopify_shop_url);
     env::set_var("shopify_webhook_secret", shopify_webhook_secret);
     env::set_var("shopify_api_version", shopify_api_version);
-    env::set_var("smtp_username", "user");
-    env::set_var("smtp_password", "password");
+    // Mailpit offers neither TLS nor authentication
+    env::set_var("smtp_username", "");
+    env::set_var("smtp_password", "");
     env::set_var("smtp_host", "localhost");
     env::set_var("smtp_port", smtp_host_port.to_string());
+    env::set_var("smtp_tls", "none");
     env::set_var("origin_email", "Notifcation Service <noreply@test.com>");
 
     tokio::spawn(async move {
-        let app = app().await;
+        let config = Config::load().unwrap();
+        let bind_address = config.bind_address;
+        let app = app(config).await;
 
-        let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+        let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
         eprintln!("Server is listening on port 3000");
         axum::serve(listener, app).await.unwrap();
     });
@@ -65,11 +72,10 @@ async fn test_e2e() {
     headers.insert("X-Shopify-Webhook-Id", "1234567890".parse().unwrap());
     headers.insert("X-Shopify-Event-Id", "1234567890".parse().unwrap());
     headers.insert("X-Shopify-Shop-Domain", shopify_shop_url.parse().unwrap());
-    headers.insert("X-Shopify-Hmac-Sha256", shopify_webhook_secret.parse().unwrap());
     headers.insert("X-Shopify-Api-Version", shopify_api_version.parse().unwrap());
     headers.insert("Content-Type", "application/json".parse().unwrap());
 
-    create_order_route_test(client, &app_address, headers.clone(), smtp_web_port).await;
+    create_order_route_test(client, app_address, headers.clone(), shopify_webhook_secret, smtp_web_port).await;
 }
 
 async fn wait_for_server_start(address: &str) {
@@ -90,9 +96,17 @@ async fn wait_for_server_start(address: &str) {
     }
 }
 
+// Signs the body the same way Shopify does
+fn sign_body(body: &str, webhook_secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(webhook_secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
 // Tests
-async fn create_order_route_test(client: Client, address: &str, headers: HeaderMap, smtp_host_port: u16) {
+async fn create_order_route_test(client: Client, address: &str, mut headers: HeaderMap, webhook_secret: &str, smtp_host_port: u16) {
     let body = r#"{"order_number": "1", "customer": {"email": "test@test.com", "first_name": "John", "last_name": "Doe"}}"#;
+    headers.insert("X-Shopify-Hmac-Sha256", sign_body(body, webhook_secret).parse().unwrap());
 
     let response = client
         .post(format!("http://{address}/api/order/create"))
@@ -121,15 +135,21 @@ struct EmailSearchResponse {
 
 // Function for checking email sent
 async fn check_email_sent(client: Client, subject: &str, smtp_port: u16) {
-    let response = client
-        .get(format!("http://localhost:{smtp_port}/api/v1/search?query=subject:\"{subject}\""))
-        .send()
-        .await
-        .unwrap();
+    // Emails are sent by the outbox workers after the webhook has been answered, so give them a moment
+    for _ in 0..20 {
+        let response = client
+            .get(format!("http://localhost:{smtp_port}/api/v1/search?query=subject:\"{subject}\""))
+            .send()
+            .await
+            .unwrap();
+
+        assert!(response.status().is_success(), "Response status is not success {response:?}");
+        if !response.json::<EmailSearchResponse>().await.unwrap().messages.is_empty() {
+            return;
+        }
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    }
 
-    assert!(response.status().is_success(), "Response status is not success {response:?}");
-    assert!(
-        !response.json::<EmailSearchResponse>().await.unwrap().messages.is_empty(),
-        "No emails found"
-    );
+    panic!("No emails found");
 }
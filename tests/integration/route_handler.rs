@@ -1,23 +1,47 @@
 use axum::{body::Body, extract::Request, http::StatusCode, middleware, routing::post, Extension, Router};
-use handlebars::Handlebars;
 use lettre::Message;
-use notification_service::middlewares::verify_shopify_origin;
+use notification_service::config::Config;
+use notification_service::middlewares::{verify_shopify_origin, AdminToken, VerifyShopifyOriginState};
+use notification_service::routes::admin;
 use notification_service::routes::webhooks::handlers::{order_cancelled, order_created, order_fulfilled};
 use notification_service::services::database::Pool;
-use notification_service::services::email::{MailerError, MailerTrait};
-use notification_service::services::queries::{partial, template};
-use notification_service::services::template::Manager;
+use notification_service::services::email::{Delivery, DkimSigner, Mailer, MailerError, MailerTrait};
+use notification_service::services::outbox::{self, Outbox, RetryPolicy};
+use notification_service::services::queries::{event, outbox as outbox_queries, template as template_queries};
+use notification_service::services::retention::{self, RetentionMetrics, RetentionPolicy};
+use notification_service::services::shop::{ShopError, Shops};
+use notification_service::services::template::{self, Manager};
+use notification_service::services::transport::{MemoryTransport, SmtpOptions, Transport};
+use notification_service::utils::shopify::webhook_event::WebhookEvent;
 use notification_service::utils::Email;
-use tower::{ServiceBuilder, ServiceExt};
+use tower::ServiceExt;
 
+// Mailer the SMTP server rejects every email for
 #[derive(Clone)]
-pub struct MockMailer {}
+pub struct RejectingMailer {}
 
 #[async_trait::async_trait]
-impl MailerTrait for MockMailer {
+impl MailerTrait for RejectingMailer {
     #[allow(unused_variables)]
-    fn new(smtp_username: String, smtp_password: String, smtp_host: &str, origin_email: String, smtp_port: u16) -> Self {
-        Self {}
+    fn new(
+        smtp_username: String,
+        smtp_password: String,
+        smtp_host: &str,
+        origin_email: String,
+        smtp_port: u16,
+        smtp_options: &SmtpOptions,
+    ) -> Result<Self, MailerError> {
+        Ok(Self {})
+    }
+
+    #[allow(unused_variables)]
+    fn with_dkim(self, signer: DkimSigner) -> Self {
+        self
+    }
+
+    #[allow(unused_variables)]
+    fn with_transport(self, transport: Transport) -> Self {
+        self
     }
 
     #[allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
@@ -31,11 +55,36 @@ impl MailerTrait for MockMailer {
     }
 
     #[allow(clippy::unused_async, clippy::missing_errors_doc)]
-    async fn send_mail(&self, _email: Message) -> Result<(), MailerError> {
-        Ok(())
+    async fn send_mail(&self, _email: Message) -> Result<Delivery, MailerError> {
+        Err(MailerError::SmtpPermanentError("550 5.1.1 Mailbox does not exist".to_string()))
     }
 }
 
+pub const ADMIN_API_TOKEN: &str = "test_admin_api_token";
+
+/// Loads the configuration from `.env.test`, with mailers capturing their emails in `MemoryTransport::shared()`
+///
+/// # Panics
+///
+/// This function will panic if the configuration is invalid
+#[must_use]
+pub fn test_config() -> Config {
+    dotenv::from_filename(".env.test").ok();
+
+    let mut config = Config::load().unwrap();
+    config.email_transport = Some(Transport::Memory(MemoryTransport::shared()));
+    config
+}
+
+/// Loads the shops with mailers capturing their emails in `MemoryTransport::shared()`
+///
+/// # Errors
+///
+/// This function will return an error if the shops cannot be loaded
+pub async fn load_shops(db_client: &Pool) -> Result<Shops<Mailer>, ShopError> {
+    Shops::<Mailer>::load(db_client, &test_config()).await
+}
+
 /// Setup the app for testing
 ///
 /// # Returns
@@ -51,8 +100,6 @@ impl MailerTrait for MockMailer {
 /// This function will panic if the template registration fails
 pub async fn setup_app() -> Result<Router, Box<dyn std::error::Error>> {
     dotenv::from_filename(".env.test").ok();
-    #[allow(unused_variables)]
-    let mailer = MockMailer::new(String::new(), String::new(), "", String::new(), 1025);
 
     let db_client = Pool::new(
         std::env::var("postgres_db").unwrap(),
@@ -61,55 +108,69 @@ pub async fn setup_app() -> Result<Router, Box<dyn std::error::Error>> {
         std::env::var("postgres_password").unwrap(),
     );
 
-    let mut templates = Handlebars::new();
-    let templates_from_db = template::get_all(&db_client.get_client().await.unwrap()).await.unwrap();
-    for template in templates_from_db {
-        let name: &str = template.get("name");
-        let content: &str = template.get("content");
-
-        if templates.register_template_string(name, content).is_ok() {
-            println!("Template registered and ready: {name}");
-        } else {
-            println!("Error registering template: {name}");
-        }
-    }
-
-    // Get partials from database and persist in memory with the template client
-    let partials_templates_from_db = partial::get_all(&db_client.get_client().await.unwrap()).await.unwrap();
-    for partial in partials_templates_from_db {
-        let name: &str = partial.get("name");
-        let content: &str = partial.get("content");
-
-        if templates.register_partial(name, content).is_ok() {
-            println!("Partial template registered and ready: {name}");
-        } else {
-            println!("Error registering partial template: {name}");
-        }
-    }
-
-    // Create a template client
-    let template_manager = Manager::new(templates);
+    // Every shop gets a mailer that never reaches an SMTP server, and its own templates
+    let shops = load_shops(&db_client).await?;
+    let outbox = Outbox::new(db_client.clone());
+    let admin_router = admin::router(
+        db_client.clone(),
+        AdminToken::new(ADMIN_API_TOKEN),
+        RetentionMetrics::default(),
+        shops.template_managers(),
+    );
+    let verify_state = VerifyShopifyOriginState { db_client, shops };
 
     Ok(Router::new()
-        .route("/api/order/create", post(order_created::<MockMailer>))
-        .route("/api/order/cancel", post(order_cancelled::<MockMailer>))
-        .route("/api/order/fulfilled", post(order_fulfilled::<MockMailer>))
-        .layer(ServiceBuilder::new().layer(Extension(mailer)).layer(Extension(template_manager)))
-        .route_layer(middleware::from_fn_with_state(db_client, verify_shopify_origin)))
+        .route("/api/order/create", post(order_created::<Outbox>))
+        .route("/api/order/cancel", post(order_cancelled::<Outbox>))
+        .route("/api/order/fulfilled", post(order_fulfilled::<Outbox>))
+        .layer(Extension(outbox))
+        .route_layer(middleware::from_fn_with_state(verify_state, verify_shopify_origin::<Mailer>))
+        .merge(admin_router))
 }
 
 mod tests {
     use super::*;
     use axum::http::request::Builder;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
     static mut SHOPIFY_EVENT_ID: u32 = 0;
 
+    // Tests draining the outbox would otherwise send each other's notifications with the wrong mailer
+    static OUTBOX_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    // Reloads fail while another test has a template that doesn't compile in the database
+    static TEMPLATE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
     lazy_static::lazy_static! {
         static ref SHOPIFY_SHOP_URL: String = std::env::var("shopify_shop_url").unwrap();
         static ref SHOPIFY_WEBHOOK_SECRET: String = std::env::var("shopify_webhook_secret").unwrap();
         static ref SHOPIFY_API_VERSION: String = std::env::var("shopify_api_version").unwrap();
     }
 
-    fn create_request_builder() -> Builder {
+    const SECOND_SHOP_URL: &str = "second-shop.myshopify.com";
+    const SECOND_SHOP_WEBHOOK_SECRET: &str = "second_shop_webhook_secret";
+
+    fn create_pool() -> Pool {
+        Pool::new(
+            std::env::var("postgres_db").unwrap(),
+            std::env::var("postgres_url").unwrap(),
+            std::env::var("postgres_user").unwrap(),
+            std::env::var("postgres_password").unwrap(),
+        )
+    }
+
+    // Signs the body the same way Shopify does
+    fn sign_body_with_secret(body: &[u8], webhook_secret: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(webhook_secret.as_bytes()).unwrap();
+        mac.update(body);
+        STANDARD.encode(mac.finalize().into_bytes())
+    }
+
+    fn sign_body(body: &[u8]) -> String {
+        sign_body_with_secret(body, &SHOPIFY_WEBHOOK_SECRET)
+    }
+
+    fn create_shop_request_builder(body: &[u8], shop_url: &str, webhook_secret: &str) -> Builder {
         // Unsafe because possible overflow (Will prob never happen, in this case)
         unsafe {
             SHOPIFY_EVENT_ID += 1;
@@ -120,12 +181,44 @@ mod tests {
             .header("X-Shopify-Topic", "orders/create")
             .header("X-Shopify-Webhook-Id", "1234567890")
             .header("X-Shopify-Event-Id", unsafe { SHOPIFY_EVENT_ID })
-            .header("X-Shopify-Shop-Domain", SHOPIFY_SHOP_URL.to_string())
-            .header("X-Shopify-Hmac-Sha256", SHOPIFY_WEBHOOK_SECRET.to_string())
+            .header("X-Shopify-Shop-Domain", shop_url)
+            .header("X-Shopify-Hmac-Sha256", sign_body_with_secret(body, webhook_secret))
             .header("X-Shopify-Api-Version", SHOPIFY_API_VERSION.to_string())
             .header("Content-Type", "application/json")
     }
 
+    fn create_request_builder(body: &[u8]) -> Builder {
+        create_shop_request_builder(body, &SHOPIFY_SHOP_URL, &SHOPIFY_WEBHOOK_SECRET)
+    }
+
+    // Adds a second shop with its own secret and signature partial
+    async fn setup_second_shop() -> i32 {
+        let client = create_pool().get_client().await.unwrap();
+        let row = client
+            .query_one(
+                "INSERT INTO shops (domain, webhook_secret, api_version, origin_email, smtp_host, smtp_port, smtp_username, smtp_password)
+                VALUES ($1, $2, $3, 'Second Shop <noreply@second-shop.com>', 'localhost', 1025, 'user', 'password')
+                ON CONFLICT (domain) DO UPDATE SET webhook_secret = EXCLUDED.webhook_secret
+                RETURNING id",
+                &[&SECOND_SHOP_URL, &SECOND_SHOP_WEBHOOK_SECRET, &SHOPIFY_API_VERSION.as_str()],
+            )
+            .await
+            .unwrap();
+        let shop_id: i32 = row.get("id");
+
+        client
+            .execute(
+                "INSERT INTO template_partials (shop_id, name, content)
+                SELECT $1, 'signature', '<p>Sincerely Second Shop</p>'
+                WHERE NOT EXISTS (SELECT 1 FROM template_partials WHERE shop_id = $1 AND name = 'signature')",
+                &[&shop_id],
+            )
+            .await
+            .unwrap();
+
+        shop_id
+    }
+
     #[tokio::test]
     async fn test_routes_no_headers() {
         let app = setup_app().await.unwrap();
@@ -145,6 +238,44 @@ mod tests {
         assert_eq!(cancelled_order_response.status(), StatusCode::BAD_REQUEST);
     }
 
+    #[tokio::test]
+    async fn test_routes_tampered_body() {
+        let app = setup_app().await.unwrap();
+        let signed_body = serde_json::json!({
+            "order_number": "1234567890",
+            "customer": {
+                "email": "test@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        })
+        .to_string();
+        let tampered_body = signed_body.replace("test@test.com", "attacker@test.com");
+
+        let request = create_request_builder(signed_body.as_bytes())
+            .uri("/api/order/create")
+            .body(Body::from(tampered_body))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_routes_secret_as_hmac_sha256() {
+        // The shared secret itself must never be accepted in place of a signature
+        let app = setup_app().await.unwrap();
+        let mut request = create_request_builder(b"").uri("/api/order/create").body(Body::empty()).unwrap();
+        request
+            .headers_mut()
+            .insert("X-Shopify-Hmac-Sha256", SHOPIFY_WEBHOOK_SECRET.parse().unwrap());
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
     #[tokio::test]
     async fn test_routes_invalid_hmac_sha256() {
         // TODO: Add order cancelled test
@@ -178,12 +309,12 @@ mod tests {
 
         let create_order_response = app
             .clone()
-            .oneshot(create_request_builder().uri("/api/order/create").body(Body::empty()).unwrap())
+            .oneshot(create_request_builder(b"").uri("/api/order/create").body(Body::empty()).unwrap())
             .await
             .unwrap();
 
         let cancelled_order_response = app
-            .oneshot(create_request_builder().uri("/api/order/cancel").body(Body::empty()).unwrap())
+            .oneshot(create_request_builder(b"").uri("/api/order/cancel").body(Body::empty()).unwrap())
             .await
             .unwrap();
 
@@ -203,7 +334,7 @@ mod tests {
             }
         });
 
-        let request = create_request_builder()
+        let request = create_request_builder(json_body.to_string().as_bytes())
             .uri("/api/order/create")
             .body(Body::from(json_body.to_string()))
             .unwrap();
@@ -225,7 +356,7 @@ mod tests {
             }
         });
 
-        let request = create_request_builder()
+        let request = create_request_builder(json_body.to_string().as_bytes())
             .uri("/api/order/cancel")
             .body(Body::from(json_body.to_string()))
             .unwrap();
@@ -247,7 +378,7 @@ mod tests {
             }
         });
 
-        let request = create_request_builder()
+        let request = create_request_builder(json_body.to_string().as_bytes())
             .uri("/api/order/fulfilled")
             .body(Body::from(json_body.to_string()))
             .unwrap();
@@ -257,6 +388,1280 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn test_event_records_webhook_secret_id() {
+        let app = setup_app().await.unwrap();
+        let json_body = serde_json::json!({
+            "order_number": "1234567890",
+            "customer": {
+                "email": "test@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        });
+
+        let request = create_request_builder(json_body.to_string().as_bytes())
+            .uri("/api/order/create")
+            .body(Body::from(json_body.to_string()))
+            .unwrap();
+        let event_id = request.headers().get("X-Shopify-Event-Id").unwrap().to_str().unwrap().to_string();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let row = event::get(&create_pool().get_client().await.unwrap(), &event_id).await.unwrap();
+        let webhook_secret_id: Option<&str> = row.get("webhook_secret_id");
+
+        assert_eq!(webhook_secret_id, Some("default"));
+    }
+
+    #[tokio::test]
+    async fn test_outbox_sends_queued_email() {
+        let _outbox_lock = OUTBOX_LOCK.lock().await;
+        let app = setup_app().await.unwrap();
+        let json_body = serde_json::json!({
+            "order_number": "1234567890",
+            "customer": {
+                "email": "test@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        });
+
+        let request = create_request_builder(json_body.to_string().as_bytes())
+            .uri("/api/order/create")
+            .body(Body::from(json_body.to_string()))
+            .unwrap();
+        let event_id = request.headers().get("X-Shopify-Event-Id").unwrap().to_str().unwrap().to_string();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The webhook only queues the email, sending is left to the workers
+        let db_client = create_pool();
+        let client = db_client.get_client().await.unwrap();
+        let row = client
+            .query_one(
+                "SELECT sent_at IS NOT NULL AS sent, email->>'subject' AS subject, email->>'text_body' AS text_body
+                FROM outbox WHERE event_id = $1",
+                &[&event_id],
+            )
+            .await
+            .unwrap();
+        assert!(!row.get::<_, bool>("sent"));
+        assert_eq!(row.get::<_, &str>("subject"), "#1234567890: We have received your order");
+        assert!(row.get::<_, &str>("text_body").starts_with("Thank You for Your Order!\n\nDear John Doe,"));
+
+        let shops = load_shops(&db_client).await.unwrap();
+        while outbox::process_next(&db_client, &shops, &RetryPolicy::default()).await.unwrap() {}
+
+        let row = client
+            .query_one("SELECT sent_at IS NOT NULL AS sent FROM outbox WHERE event_id = $1", &[&event_id])
+            .await
+            .unwrap();
+        assert!(row.get::<_, bool>("sent"));
+
+        // The mailer built the email for real and handed it to the memory transport
+        let sent = MemoryTransport::shared().messages().iter().any(|message| {
+            let formatted = String::from_utf8(message.formatted()).unwrap();
+            message.envelope().to().iter().any(|to| to.to_string() == "test@test.com")
+                && formatted.contains("Subject: #1234567890: We have received your order")
+                && formatted.contains("Content-Type: text/plain")
+        });
+        assert!(sent);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_email_is_dead_lettered_and_requeued() {
+        let _outbox_lock = OUTBOX_LOCK.lock().await;
+        let app = setup_app().await.unwrap();
+        let json_body = serde_json::json!({
+            "order_number": "1234567890",
+            "customer": {
+                "email": "unknown@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        });
+
+        let request = create_request_builder(json_body.to_string().as_bytes())
+            .uri("/api/order/create")
+            .body(Body::from(json_body.to_string()))
+            .unwrap();
+        let event_id = request.headers().get("X-Shopify-Event-Id").unwrap().to_str().unwrap().to_string();
+
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A permanent rejection is not retried
+        let db_client = create_pool();
+        let shops = Shops::<RejectingMailer>::load(&db_client, &test_config()).await.unwrap();
+        while outbox::process_next(&db_client, &shops, &RetryPolicy::default()).await.unwrap() {}
+
+        let client = db_client.get_client().await.unwrap();
+        let row = client
+            .query_one("SELECT outbox_id, attempts, error FROM dead_letters WHERE event_id = $1", &[&event_id])
+            .await
+            .unwrap();
+        let outbox_id: i64 = row.get("outbox_id");
+        assert_eq!(row.get::<_, i32>("attempts"), 1);
+        assert!(row.get::<_, &str>("error").contains("Mailbox does not exist"));
+
+        let attempts = client
+            .query_one("SELECT count(*) FROM delivery_attempts WHERE outbox_id = $1", &[&outbox_id])
+            .await
+            .unwrap();
+        assert_eq!(attempts.get::<_, i64>(0), 1);
+
+        // The admin API lists and requeues the dead letter
+        let request = Request::builder()
+            .uri("/admin/dead-letters?limit=1000")
+            .header("Authorization", format!("Bearer {ADMIN_API_TOKEN}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let dead_letters: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(dead_letters
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|dead_letter| dead_letter["outbox_id"] == outbox_id && dead_letter["recipient"] == "John Doe <unknown@test.com>"));
+
+        let requeue = || {
+            Request::builder()
+                .method("POST")
+                .uri(format!("/admin/dead-letters/{outbox_id}/requeue"))
+                .header("Authorization", format!("Bearer {ADMIN_API_TOKEN}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(requeue()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let row = client
+            .query_one("SELECT attempts, sent_at IS NULL AS pending FROM outbox WHERE id = $1", &[&outbox_id])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, i32>("attempts"), 0);
+        assert!(row.get::<_, bool>("pending"));
+
+        let response = app.oneshot(requeue()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes_require_token() {
+        let app = setup_app().await.unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/admin/dead-letters").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/admin/dead-letters")
+                    .header("Authorization", "Bearer wrong_token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_event_log_lookup() {
+        let app = setup_app().await.unwrap();
+        let json_body = serde_json::json!({
+            "order_number": "EVENT-LOG-1001",
+            "customer": {
+                "email": "Event.Log@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        });
+
+        let request = create_request_builder(json_body.to_string().as_bytes())
+            .uri("/api/order/create")
+            .body(Body::from(json_body.to_string()))
+            .unwrap();
+        let event_id = request.headers().get("X-Shopify-Event-Id").unwrap().to_str().unwrap().to_string();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let client = create_pool().get_client().await.unwrap();
+        let events = event::get_by_order_number(&client, "EVENT-LOG-1001").await.unwrap();
+        assert_eq!(events.len(), 1);
+
+        let row = &events[0];
+        assert_eq!(row.get::<_, &str>("event_id"), event_id);
+        assert_eq!(row.get::<_, &str>("topic"), "orders/create");
+        assert_eq!(row.get::<_, &str>("shop_domain"), SHOPIFY_SHOP_URL.as_str());
+        assert_eq!(row.get::<_, &str>("api_version"), SHOPIFY_API_VERSION.as_str());
+        assert_eq!(row.get::<_, &str>("status"), "done");
+        assert_eq!(row.get::<_, serde_json::Value>("payload"), json_body);
+        assert!(row.get::<_, Option<i64>>("processed_at").is_some());
+        assert!(row.get::<_, Option<&str>>("delivery_status").is_some());
+
+        let events = event::get_by_customer_email(&client, "event.log@test.com").await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].get::<_, &str>("event_id"), event_id);
+    }
+
+    #[tokio::test]
+    async fn test_retention_purges_and_redacts_old_events() {
+        let client = create_pool().get_client().await.unwrap();
+        client
+            .execute(
+                "INSERT INTO events (event_id, status, payload, processed_at) VALUES
+                ('retention-done-old', 'done', '{\"order_number\": \"R1\", \"customer\": {\"email\": \"a@test.com\"}}', now() - interval '40 days'),
+                ('retention-failed-old', 'failed', '{\"order_number\": \"R2\"}', now() - interval '40 days'),
+                ('retention-failed-older', 'failed', '{\"order_number\": \"R3\"}', now() - interval '100 days'),
+                ('retention-done-recent', 'done', '{\"order_number\": \"R4\", \"customer\": {\"email\": \"b@test.com\"}}', now() - interval '10 days'),
+                ('retention-processing', 'processing', '{\"order_number\": \"R5\"}', NULL)",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let policy = RetentionPolicy {
+            redact_after: Some(std::time::Duration::from_secs(7 * 24 * 60 * 60)),
+            ..RetentionPolicy::default()
+        };
+        let counts = retention::run(&create_pool(), &policy).await.unwrap();
+        assert!(counts.events >= 1);
+        assert!(counts.failed_events >= 1);
+        assert!(counts.redacted_payloads >= 1);
+
+        // Failed events are kept longer than processed ones
+        assert!(event::get(&client, "retention-done-old").await.is_err());
+        assert!(event::get(&client, "retention-failed-old").await.is_ok());
+        assert!(event::get(&client, "retention-failed-older").await.is_err());
+        assert!(event::get(&client, "retention-processing").await.is_ok());
+
+        let recent = event::get(&client, "retention-done-recent").await.unwrap();
+        assert_eq!(recent.get::<_, serde_json::Value>("payload"), serde_json::json!({ "order_number": "R4" }));
+    }
+
+    fn admin_request(method: &str, uri: &str, body: Option<serde_json::Value>) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", format!("Bearer {ADMIN_API_TOKEN}"))
+            .header("Content-Type", "application/json")
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .unwrap()
+    }
+
+    async fn response_json(response: axum::response::Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    // Adds a shop the admin tests can change the templates of without affecting the other tests
+    async fn setup_admin_shop() -> i32 {
+        let client = create_pool().get_client().await.unwrap();
+        let row = client
+            .query_one(
+                "INSERT INTO shops (domain, webhook_secret, api_version, origin_email, smtp_host, smtp_port, smtp_username, smtp_password)
+                VALUES ('admin-shop.myshopify.com', 'admin_shop_webhook_secret', $1, 'noreply@admin-shop.com', 'localhost', 1025, 'user', 'password')
+                ON CONFLICT (domain) DO UPDATE SET webhook_secret = EXCLUDED.webhook_secret
+                RETURNING id",
+                &[&SHOPIFY_API_VERSION.as_str()],
+            )
+            .await
+            .unwrap();
+
+        row.get("id")
+    }
+
+    #[tokio::test]
+    async fn test_admin_template_crud() {
+        let _template_lock = TEMPLATE_LOCK.lock().await;
+        let app = setup_app().await.unwrap();
+        let shop_id = setup_admin_shop().await;
+
+        // Syntax errors are rejected with their position
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates",
+                Some(serde_json::json!({ "shop_id": shop_id, "name": "broken", "content": "<p>Hi</p>\n{{#if customer}}", "author": "jane@admin-shop.com" })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let error = response_json(response).await;
+        assert!(error["line"].is_number());
+        assert!(error["column"].is_number());
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates",
+                Some(serde_json::json!({
+                    "shop_id": shop_id,
+                    "name": "admin_order_created",
+                    "content": "<p>Admin {{customer.first_name}}</p>",
+                    "author": "jane@admin-shop.com",
+                })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let first_version = response_json(response).await;
+        assert_eq!(first_version["version"], 1);
+        let first_id = first_version["id"].as_i64().unwrap();
+
+        // Saving under the same name adds a version instead of changing the first one
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates",
+                Some(serde_json::json!({
+                    "shop_id": shop_id,
+                    "name": "admin_order_created",
+                    "subject": "Thanks {{customer.first_name}}",
+                    "preheader": "Version 2",
+                    "content": "<p>Admin v2 {{customer.first_name}}</p>",
+                    "author": "john@admin-shop.com",
+                    "note": "Mention the version",
+                })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let second_version = response_json(response).await;
+        assert_eq!(second_version["version"], 2);
+        assert_eq!(second_version["author"], "john@admin-shop.com");
+        assert_eq!(second_version["note"], "Mention the version");
+        assert_eq!(second_version["subject"], "Thanks {{customer.first_name}}");
+        assert!(second_version["created_at"].is_number());
+        let template_id = second_version["id"].as_i64().unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                &format!("/admin/templates/{template_id}/activate"),
+                Some(serde_json::json!({ "template_type": "unknown_type", "shop_id": shop_id })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                &format!("/admin/templates/{template_id}/activate"),
+                Some(serde_json::json!({ "template_type": "order_created", "shop_id": shop_id })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let client = create_pool().get_client().await.unwrap();
+        let manager = Manager::load(&client, Some(shop_id), None).await.unwrap();
+        let payload = serde_json::json!({ "customer": { "first_name": "John" } });
+        assert_eq!(manager.get_template_filled("order_created", &payload).unwrap(), "<p>Admin v2 John</p>");
+        let email = manager.get_email_filled("order_created", &payload).unwrap();
+        assert_eq!(email.subject, "Thanks John");
+        assert!(email.html_body.contains(">Version 2</div><p>Admin v2 John</p>"));
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("GET", &format!("/admin/templates/{first_id}/versions"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let versions = response_json(response).await;
+        let versions: Vec<_> = versions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|version| version["version"].as_i64().unwrap())
+            .collect();
+        assert_eq!(versions, vec![2, 1]);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("GET", &format!("/admin/templates/{first_id}/diff/{template_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let diff = response_json(response).await;
+        let content = diff["diff"].as_str().unwrap();
+        assert!(content.contains("--- admin_order_created v1"));
+        assert!(content.contains("-<p>Admin {{customer.first_name}}</p>"));
+        assert!(content.contains("+<p>Admin v2 {{customer.first_name}}</p>"));
+        let subject = diff["subject"].as_str().unwrap();
+        assert!(subject.contains("+++ admin_order_created v2 subject"));
+        assert!(subject.contains("+Thanks {{customer.first_name}}"));
+        assert!(diff["preheader"].as_str().unwrap().contains("+Version 2"));
+        assert_eq!(diff["text_content"], "");
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("GET", &format!("/admin/templates?shop_id={shop_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let templates = response_json(response).await;
+        let template = templates
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|template| template["id"] == template_id)
+            .unwrap();
+        assert_eq!(
+            template["active_for"],
+            serde_json::json!([{ "template_type": "order_created", "shop_id": shop_id, "locale": null }])
+        );
+
+        // Active templates can't be deleted
+        let response = app
+            .clone()
+            .oneshot(admin_request("DELETE", &format!("/admin/templates/{template_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // Rolling back goes to the previous version, until there is none left
+        let rollback = serde_json::json!({ "template_type": "order_created", "shop_id": shop_id });
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", "/admin/templates/rollback", Some(rollback.clone())))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response_json(response).await["id"], first_id);
+
+        let manager = Manager::load(&client, Some(shop_id), None).await.unwrap();
+        assert_eq!(manager.get_template_filled("order_created", &payload).unwrap(), "<p>Admin John</p>");
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", "/admin/templates/rollback", Some(rollback)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates/rollback",
+                Some(serde_json::json!({ "template_type": "order_created", "shop_id": shop_id, "version": 2 })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response_json(response).await["id"], template_id);
+
+        // A version that doesn't compile is not activated
+        client
+            .execute(
+                "INSERT INTO templates (shop_id, name, version, content) VALUES ($1, 'admin_order_created', 3, '{{#if customer}}')",
+                &[&shop_id],
+            )
+            .await
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates/rollback",
+                Some(serde_json::json!({ "template_type": "order_created", "shop_id": shop_id, "version": 3 })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let manager = Manager::load(&client, Some(shop_id), None).await.unwrap();
+        assert_eq!(manager.get_template_filled("order_created", &payload).unwrap(), "<p>Admin v2 John</p>");
+
+        let response = app.oneshot(admin_request("GET", "/admin/templates/999999", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_partial_crud() {
+        let app = setup_app().await.unwrap();
+        let shop_id = setup_admin_shop().await;
+        let partial = serde_json::json!({ "shop_id": shop_id, "name": "admin_footer", "content": "<p>Footer</p>" });
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", "/admin/partials", Some(partial.clone())))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let partial_id = response_json(response).await["id"].as_i64().unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", "/admin/partials", Some(partial)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "PUT",
+                &format!("/admin/partials/{partial_id}"),
+                Some(serde_json::json!({ "content": "{{/each}}" })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("DELETE", &format!("/admin/partials/{partial_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .oneshot(admin_request("GET", &format!("/admin/partials/{partial_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_image_crud() {
+        let app = setup_app().await.unwrap();
+        let shop_id = setup_admin_shop().await;
+        let image = serde_json::json!({ "shop_id": shop_id, "name": "admin_logo", "content_type": "image/png", "content": "iVBORw==" });
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", "/admin/images", Some(image.clone())))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = response_json(response).await;
+        assert_eq!(body["size"], 4);
+        let image_id = body["id"].as_i64().unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", "/admin/images", Some(image.clone())))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let mut invalid = image;
+        invalid["name"] = serde_json::json!("admin logo");
+        let response = app.clone().oneshot(admin_request("POST", "/admin/images", Some(invalid))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("GET", &format!("/admin/images/{image_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "image/png");
+        let content = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&content[..], [137, 80, 78, 71]);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("DELETE", &format!("/admin/images/{image_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .oneshot(admin_request("GET", &format!("/admin/images/{image_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_email_settings() {
+        let app = setup_app().await.unwrap();
+        let shop_id = setup_admin_shop().await;
+        let settings = serde_json::json!({
+            "shop_id": shop_id,
+            "template_type": "order_fulfilled",
+            "bcc": ["archive@example.com"],
+            "reply_to": "support@example.com",
+            "headers": { "X-Order-Id": "{{order_number}}" }
+        });
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("PUT", "/admin/email-settings", Some(settings.clone())))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response_json(response).await;
+        assert_eq!(body["bcc"], serde_json::json!(["archive@example.com"]));
+        assert!(body["cc"].is_null());
+        let settings_id = body["id"].as_i64().unwrap();
+
+        // Storing them again replaces them
+        let response = app
+            .clone()
+            .oneshot(admin_request("PUT", "/admin/email-settings", Some(settings.clone())))
+            .await
+            .unwrap();
+        assert_eq!(response_json(response).await["id"].as_i64(), Some(settings_id));
+
+        for (field, value, status) in [
+            ("headers", serde_json::json!({ "Subject": "Replaced" }), StatusCode::BAD_REQUEST),
+            (
+                "headers",
+                serde_json::json!({ "X-Order-Id": "{{#if}}" }),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            ("reply_to", serde_json::json!("support"), StatusCode::BAD_REQUEST),
+            ("template_type", serde_json::json!("unknown"), StatusCode::NOT_FOUND),
+        ] {
+            let mut invalid = settings.clone();
+            invalid[field] = value;
+            let response = app
+                .clone()
+                .oneshot(admin_request("PUT", "/admin/email-settings", Some(invalid)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{field}");
+        }
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("GET", &format!("/admin/email-settings?shop_id={shop_id}"), None))
+            .await
+            .unwrap();
+        let listed = response_json(response).await;
+        assert!(listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|settings| settings["id"].as_i64() == Some(settings_id) && settings["headers"]["X-Order-Id"] == "{{order_number}}"));
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("DELETE", &format!("/admin/email-settings/{settings_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .oneshot(admin_request("DELETE", &format!("/admin/email-settings/{settings_id}"), None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_template_preview() {
+        let client = create_pool().get_client().await.unwrap();
+        client
+            .execute(
+                "INSERT INTO template_images (name, content_type, content) VALUES ('preview_logo', 'image/png', '\\x89504e47')
+                ON CONFLICT DO NOTHING",
+                &[],
+            )
+            .await
+            .unwrap();
+        let app = setup_app().await.unwrap();
+
+        // Without a payload the default sample is used
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", "/admin/templates/order_created/preview", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("Dear Jane Doe"));
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "PUT",
+                "/admin/templates/order_created/samples/returning_customer",
+                Some(serde_json::json!({ "order_number": "1002", "customer": { "first_name": "Ann", "last_name": "Lee", "email": "ann@example.com" } })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("GET", "/admin/templates/order_created/samples", None))
+            .await
+            .unwrap();
+        let samples = response_json(response).await;
+        assert!(samples.as_array().unwrap().iter().any(|sample| sample["name"] == "returning_customer"));
+
+        // Drafts are rendered with the stored sample, without being saved
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates/order_created/preview",
+                Some(serde_json::json!({
+                    "sample": "returning_customer",
+                    "content": "<p>{{customer.first_name}}</p>{{> footer}}",
+                    "partials": { "footer": "<p>Draft footer</p>" },
+                })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"<p>Ann</p><p>Draft footer</p>");
+
+        // Emails are previewed as sent, with their styles inlined and the images they show embedded
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates/order_created/preview",
+                Some(serde_json::json!({
+                    "sample": "returning_customer",
+                    "content": "<style>p { color: #333 }</style><img src=\"cid:preview_logo\"><p>{{customer.first_name}}</p>",
+                })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"<img src=\"data:image/png;base64,iVBORw==\"><p style=\"color: #333\">Ann</p>");
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates/order_created/preview",
+                Some(serde_json::json!({ "content": "{{#if customer}}" })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates/invoice/preview?format=pdf",
+                Some(serde_json::json!({ "payload": { "order_number": "1003" } })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/pdf");
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                "/admin/templates/order_created/preview",
+                Some(serde_json::json!({ "sample": "unknown" })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .oneshot(admin_request("POST", "/admin/templates/unknown_type/preview", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_template_versions_conflict() {
+        let db_client = create_pool();
+        let mut client = db_client.get_client().await.unwrap();
+        let new_template = |content| template_queries::NewTemplate {
+            shop_id: None,
+            name: "concurrent_order_created",
+            subject: None,
+            preheader: None,
+            content,
+            text_content: None,
+            author: "jane@admin-shop.com",
+            note: None,
+        };
+        let version: Option<i32> = client
+            .query_one(
+                "SELECT max(version) FROM templates WHERE shop_id IS NULL AND name = 'concurrent_order_created'",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get(0);
+
+        // Both saves read the same latest version, the second one waits for the first to commit
+        let transaction = client.transaction().await.unwrap();
+        let first = template_queries::create(&transaction, &new_template("<p>First</p>"))
+            .await
+            .unwrap()
+            .unwrap();
+        let second = tokio::spawn(async move {
+            let client = db_client.get_client().await.unwrap();
+            template_queries::create(&client, &new_template("<p>Second</p>")).await.unwrap()
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        transaction.commit().await.unwrap();
+
+        assert_eq!(first.get::<_, i32>("version"), version.unwrap_or(0) + 1);
+        assert!(second.await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_template_reload() {
+        let _template_lock = TEMPLATE_LOCK.lock().await;
+        dotenv::from_filename(".env.test").ok();
+        let db_client = create_pool();
+        let client = db_client.get_client().await.unwrap();
+
+        let shop_id: i32 = client
+            .query_one(
+                "INSERT INTO shops (domain, webhook_secret, api_version, origin_email, smtp_host, smtp_port, smtp_username, smtp_password)
+                VALUES ('reload-shop.myshopify.com', 'reload_shop_webhook_secret', $1, 'noreply@reload-shop.com', 'localhost', 1025, 'user', 'password')
+                ON CONFLICT (domain) DO UPDATE SET webhook_secret = EXCLUDED.webhook_secret
+                RETURNING id",
+                &[&SHOPIFY_API_VERSION.as_str()],
+            )
+            .await
+            .unwrap()
+            .get("id");
+        client
+            .execute("DELETE FROM template_partials WHERE shop_id = $1", &[&shop_id])
+            .await
+            .unwrap();
+        // Templates can't be edited, every change is a new version made active for the shop
+        let publish = |content: &'static str| {
+            let client = &client;
+            async move {
+                client
+                    .execute(
+                        "WITH version AS (
+                            INSERT INTO templates (shop_id, name, version, content)
+                            SELECT $1, 'reload_order_created', coalesce(max(version), 0) + 1, $2
+                            FROM templates WHERE shop_id = $1 AND name = 'reload_order_created'
+                            RETURNING id
+                        )
+                        INSERT INTO active_templates (shop_id, template_type_id, template_id)
+                        SELECT $1, tt.id, version.id FROM version, template_types tt WHERE tt.name = 'order_created'
+                        ON CONFLICT (shop_id, template_type_id, locale) DO UPDATE SET template_id = EXCLUDED.template_id",
+                        &[&shop_id, &content],
+                    )
+                    .await
+                    .unwrap();
+            }
+        };
+        publish("<p>v1</p>").await;
+
+        let shops = load_shops(&db_client).await.unwrap();
+        let managers = shops.template_managers();
+        let app = admin::router(
+            db_client.clone(),
+            AdminToken::new(ADMIN_API_TOKEN),
+            RetentionMetrics::default(),
+            managers.clone(),
+        );
+        let manager = shops.get("reload-shop.myshopify.com").unwrap().template_manager.clone();
+        assert_eq!(manager.get_template_filled("order_created", serde_json::json!({})).unwrap(), "<p>v1</p>");
+
+        // Requests already holding the manager render the reloaded template
+        publish("<p>v2</p>").await;
+        let response = app.clone().oneshot(admin_request("POST", "/admin/templates/reload", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(manager.get_template_filled("order_created", serde_json::json!({})).unwrap(), "<p>v2</p>");
+
+        // Nothing is swapped in while a single partial doesn't compile
+        publish("<p>v3</p>").await;
+        client
+            .execute(
+                "INSERT INTO template_partials (shop_id, name, content) VALUES ($1, 'reload_broken', '{{#if customer}}')",
+                &[&shop_id],
+            )
+            .await
+            .unwrap();
+        let response = app.clone().oneshot(admin_request("POST", "/admin/templates/reload", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(response_json(response).await["error"].as_str().unwrap().contains("reload_broken"));
+        assert_eq!(manager.get_template_filled("order_created", serde_json::json!({})).unwrap(), "<p>v2</p>");
+
+        // A rollback reports the reload failing as well
+        let rollback = serde_json::json!({ "template_type": "order_created", "shop_id": shop_id });
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", "/admin/templates/rollback", Some(rollback)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(response_json(response).await["error"].as_str().unwrap().contains("reload_broken"));
+
+        client
+            .execute("DELETE FROM template_partials WHERE shop_id = $1", &[&shop_id])
+            .await
+            .unwrap();
+
+        // Changes are picked up from the database notification without calling the admin API
+        template::spawn_reloader(&db_client, managers);
+        publish("<p>v4</p>").await;
+
+        let mut rendered = String::new();
+        for _ in 0..20 {
+            rendered = manager.get_template_filled("order_created", serde_json::json!({})).unwrap();
+            if rendered == "<p>v4</p>" {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        }
+        assert_eq!(rendered, "<p>v4</p>");
+    }
+
+    #[tokio::test]
+    async fn test_second_shop_route() {
+        setup_second_shop().await;
+        let app = setup_app().await.unwrap();
+        let json_body = serde_json::json!({
+            "order_number": "1234567890",
+            "customer": {
+                "email": "test@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        });
+
+        let request = create_shop_request_builder(json_body.to_string().as_bytes(), SECOND_SHOP_URL, SECOND_SHOP_WEBHOOK_SECRET)
+            .uri("/api/order/create")
+            .body(Body::from(json_body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Each shop only accepts webhooks signed with its own secret
+        let request = create_shop_request_builder(json_body.to_string().as_bytes(), SECOND_SHOP_URL, &SHOPIFY_WEBHOOK_SECRET)
+            .uri("/api/order/create")
+            .body(Body::from(json_body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_shop_route() {
+        let app = setup_app().await.unwrap();
+        let request = create_shop_request_builder(b"{}", "unknown-shop.myshopify.com", &SHOPIFY_WEBHOOK_SECRET)
+            .uri("/api/order/create")
+            .body(Body::from("{}"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_second_shop_templates() {
+        let shop_id = setup_second_shop().await;
+        let client = create_pool().get_client().await.unwrap();
+        let payload = serde_json::json!({
+            "customer": {
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        });
+
+        let shared = Manager::load(&client, None, None).await.unwrap();
+        let second_shop = Manager::load(&client, Some(shop_id), None).await.unwrap();
+
+        // The shop's own signature partial overrides the shared one
+        assert!(!shared.get_template_filled("order_created", &payload).unwrap().contains("Second Shop"));
+        assert!(second_shop
+            .get_template_filled("order_created", &payload)
+            .unwrap()
+            .contains("Second Shop"));
+    }
+
+    #[tokio::test]
+    async fn test_localized_templates() {
+        let app = setup_app().await.unwrap();
+        let client = create_pool().get_client().await.unwrap();
+        let shop_id: i32 = client
+            .query_one(
+                "INSERT INTO shops (domain, webhook_secret, api_version, origin_email, smtp_host, smtp_port, smtp_username, smtp_password, default_locale)
+                VALUES ('locale-shop.myshopify.com', 'locale_shop_webhook_secret', $1, 'noreply@locale-shop.com', 'localhost', 1025, 'user', 'password', 'fr')
+                ON CONFLICT (domain) DO UPDATE SET default_locale = EXCLUDED.default_locale
+                RETURNING id",
+                &[&SHOPIFY_API_VERSION.as_str()],
+            )
+            .await
+            .unwrap()
+            .get("id");
+        client
+            .execute("DELETE FROM template_partials WHERE shop_id = $1", &[&shop_id])
+            .await
+            .unwrap();
+
+        for (locale, content) in [
+            ("de", "<p>Hallo {{> locale_greeting}}, {{format_currency total_price currency}}</p>"),
+            ("FR", "<p>Bonjour {{> locale_greeting}}</p>"),
+        ] {
+            let template =
+                serde_json::json!({ "shop_id": shop_id, "name": format!("locale_order_created_{locale}"), "content": content, "author": "test" });
+            let response = app
+                .clone()
+                .oneshot(admin_request("POST", "/admin/templates", Some(template)))
+                .await
+                .unwrap();
+            let template_id = response_json(response).await["id"].as_i64().unwrap();
+
+            let activation = serde_json::json!({ "template_type": "order_created", "shop_id": shop_id, "locale": locale });
+            let response = app
+                .clone()
+                .oneshot(admin_request(
+                    "POST",
+                    &format!("/admin/templates/{template_id}/activate"),
+                    Some(activation),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        for (locale, content) in [(None, "Customer"), (Some("de"), "Kunde")] {
+            let partial = serde_json::json!({ "shop_id": shop_id, "name": "locale_greeting", "locale": locale, "content": content });
+            let response = app
+                .clone()
+                .oneshot(admin_request("POST", "/admin/partials", Some(partial)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        let manager = Manager::load(&client, Some(shop_id), Some("fr".to_string())).await.unwrap();
+        let payload = serde_json::json!({ "total_price": "1234.50", "currency": "EUR" });
+
+        // de-AT falls back to the German template and partial, written with Austrian conventions
+        assert_eq!(
+            manager.with_locale(Some("de-AT")).get_template_filled("order_created", &payload).unwrap(),
+            "<p>Hallo Kunde, 1.234,50 €</p>"
+        );
+        // Locales without templates get the shop's default locale, whose partial falls back to the one without a locale
+        assert_eq!(
+            manager.with_locale(Some("es")).get_template_filled("order_created", &payload).unwrap(),
+            "<p>Bonjour Customer</p>"
+        );
+        assert_eq!(manager.get_template_filled("order_created", &payload).unwrap(), "<p>Bonjour Customer</p>");
+
+        // Without a default locale, the template without a locale is used
+        let manager = Manager::load(&client, Some(shop_id), None).await.unwrap();
+        assert!(manager
+            .with_locale(Some("es"))
+            .get_template_filled("order_created", &payload)
+            .unwrap()
+            .contains("Thank You for Your Order!"));
+    }
+
+    fn create_event_request(body: &str, event_id: &str) -> Request<Body> {
+        let mut request = create_request_builder(body.as_bytes())
+            .uri("/api/order/create")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        request.headers_mut().insert("X-Shopify-Event-Id", event_id.parse().unwrap());
+
+        request
+    }
+
+    async fn count_outbox_rows(event_id: &str) -> i64 {
+        let client = create_pool().get_client().await.unwrap();
+        let row = client
+            .query_one("SELECT count(*) FROM outbox WHERE event_id = $1", &[&event_id])
+            .await
+            .unwrap();
+
+        row.get(0)
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_duplicate_event_id() {
+        let app = setup_app().await.unwrap();
+        let json_body = serde_json::json!({
+            "order_number": "1234567890",
+            "customer": {
+                "email": "test@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        })
+        .to_string();
+
+        let (response_1, response_2) = tokio::join!(
+            app.clone().oneshot(create_event_request(&json_body, "777777777")),
+            app.clone().oneshot(create_event_request(&json_body, "777777777")),
+        );
+        let statuses = [response_1.unwrap().status(), response_2.unwrap().status()];
+
+        // One request processes the event, the other either sees it done or still in progress
+        assert!(statuses.contains(&StatusCode::OK));
+        assert!(statuses.iter().all(|status| *status == StatusCode::OK || *status == StatusCode::CONFLICT));
+        assert_eq!(count_outbox_rows("777777777").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_event_claim_is_retried() {
+        let app = setup_app().await.unwrap();
+        let json_body = serde_json::json!({
+            "order_number": "1234567890",
+            "customer": {
+                "email": "test@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        })
+        .to_string();
+
+        // Claims left behind by a request that crashed, one still locked and one that expired
+        let client = create_pool().get_client().await.unwrap();
+        client
+            .execute(
+                "INSERT INTO events (event_id, status, locked_until) VALUES
+                ('666666661', 'processing', now() + interval '1 hour'),
+                ('666666662', 'processing', now() - interval '1 second')",
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let response = app.clone().oneshot(create_event_request(&json_body, "666666661")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(count_outbox_rows("666666661").await, 0);
+
+        let response = app.oneshot(create_event_request(&json_body, "666666662")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(count_outbox_rows("666666662").await, 1);
+
+        let row = event::get(&client, "666666662").await.unwrap();
+        assert_eq!(row.get::<_, &str>("status"), "done");
+    }
+
+    #[tokio::test]
+    async fn test_expired_claim_cannot_complete_event() {
+        let client = create_pool().get_client().await.unwrap();
+        let event = WebhookEvent {
+            event_id: "666666663".to_string(),
+            webhook_secret_id: "default".to_string(),
+            topic: "orders/create".to_string(),
+            webhook_id: "666666663".to_string(),
+            shop_domain: SHOPIFY_SHOP_URL.to_string(),
+            api_version: SHOPIFY_API_VERSION.to_string(),
+            locked_until: None,
+        };
+
+        // The first request runs past its claim and Shopify's retry claims the event again
+        let first = event::claim(&client, &event, None, 0.1).await.unwrap();
+        assert!(first.is_some());
+        assert!(event::claim(&client, &event, None, 60.0).await.unwrap().is_none());
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let second = event::claim(&client, &event, None, 60.0).await.unwrap();
+        assert!(second.is_some());
+
+        // The first request can neither complete nor release the event anymore
+        assert!(event::complete(&client, &event.event_id, first).await.is_err());
+        event::fail(&client, &event.event_id, first, "timed out").await.unwrap();
+        let row = event::get(&client, &event.event_id).await.unwrap();
+        assert_eq!(row.get::<_, &str>("status"), "processing");
+
+        event::complete(&client, &event.event_id, second).await.unwrap();
+        let row = event::get(&client, &event.event_id).await.unwrap();
+        assert_eq!(row.get::<_, &str>("status"), "done");
+    }
+
+    #[tokio::test]
+    async fn test_expired_lease_cannot_record_attempt() {
+        let _outbox_lock = OUTBOX_LOCK.lock().await;
+        let client = create_pool().get_client().await.unwrap();
+        let id: i64 = client
+            .query_one(
+                "INSERT INTO outbox (shop_domain, email) VALUES ('lease.myshopify.com', '{}') RETURNING id",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get("id");
+
+        // Notifications queued by other tests may be due as well, they are claimed with short leases along the way
+        let claim = |lease_secs: f64| {
+            let client = &client;
+            async move {
+                while let Some(row) = outbox_queries::claim_next(client, lease_secs).await.unwrap() {
+                    if row.get::<_, i64>("id") == id {
+                        return Some(row.get::<_, std::time::SystemTime>("available_at"));
+                    }
+                }
+                None
+            }
+        };
+
+        // The first worker runs past its lease and another worker claims the notification again
+        let first = claim(0.1).await.unwrap();
+        assert!(claim(60.0).await.is_none());
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let second = claim(60.0).await.unwrap();
+
+        // Only the worker holding the lease records its attempt
+        assert!(outbox_queries::mark_sent(&client, id, first).await.is_err());
+        outbox_queries::mark_sent(&client, id, second).await.unwrap();
+        let row = client
+            .query_one("SELECT sent_at IS NOT NULL AS sent, attempts FROM outbox WHERE id = $1", &[&id])
+            .await
+            .unwrap();
+        assert!(row.get::<_, bool>("sent"));
+        assert_eq!(row.get::<_, i32>("attempts"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_event_is_released() {
+        let app = setup_app().await.unwrap();
+
+        // The payload is missing the customer, so the handler fails
+        let response = app.clone().oneshot(create_event_request("{}", "555555555")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let client = create_pool().get_client().await.unwrap();
+        let row = event::get(&client, "555555555").await.unwrap();
+        assert_eq!(row.get::<_, &str>("status"), "failed");
+        assert!(row.get::<_, &str>("error").starts_with("422"));
+
+        // Shopify's retry of the event is processed again
+        let json_body = serde_json::json!({
+            "order_number": "1234567890",
+            "customer": {
+                "email": "test@test.com",
+                "first_name": "John",
+                "last_name": "Doe"
+            }
+        });
+        let response = app.oneshot(create_event_request(&json_body.to_string(), "555555555")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(count_outbox_rows("555555555").await, 1);
+    }
+
     #[tokio::test]
     async fn test_duplicate_event_id() {
         let app = setup_app().await.unwrap();
@@ -275,7 +1680,7 @@ mod tests {
             .header("X-Shopify-Webhook-Id", "1234567890")
             .header("X-Shopify-Event-Id", "888888888")
             .header("X-Shopify-Shop-Domain", SHOPIFY_SHOP_URL.to_string())
-            .header("X-Shopify-Hmac-Sha256", SHOPIFY_WEBHOOK_SECRET.to_string())
+            .header("X-Shopify-Hmac-Sha256", sign_body(json_body.to_string().as_bytes()))
             .header("X-Shopify-Api-Version", SHOPIFY_API_VERSION.to_string())
             .header("Content-Type", "application/json")
             .uri("/api/order/create")
@@ -291,7 +1696,7 @@ mod tests {
             .header("X-Shopify-Webhook-Id", "1234567890")
             .header("X-Shopify-Event-Id", "888888888")
             .header("X-Shopify-Shop-Domain", SHOPIFY_SHOP_URL.to_string())
-            .header("X-Shopify-Hmac-Sha256", SHOPIFY_WEBHOOK_SECRET.to_string())
+            .header("X-Shopify-Hmac-Sha256", sign_body(json_body.to_string().as_bytes()))
             .header("X-Shopify-Api-Version", SHOPIFY_API_VERSION.to_string())
             .header("Content-Type", "application/json")
             .uri("/api/order/create")